@@ -0,0 +1,64 @@
+//! Prints the datagrams of a capture written by `UdpSocket::set_capture`.
+//!
+//! Usage: amethyst-protocol-dump [--compressed] [--dictionary FILE] CAPTURE
+
+extern crate amethyst_protocol;
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+
+use amethyst_protocol::{dump, Capture, Compression};
+
+const USAGE: &str = "usage: amethyst-protocol-dump [--compressed] [--dictionary FILE] CAPTURE
+
+Reads a capture in pcap or the native format and decodes every datagram in it,
+packets with messages are split into their messages and channels.
+  --compressed       the sockets used compression with the default settings
+  --dictionary FILE  the sockets used compression with this preset dictionary";
+
+fn main() {
+    let mut compression = None;
+    let mut path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--compressed" => compression = Some(compression.unwrap_or_else(Compression::new)),
+            "--dictionary" => {
+                let file = args.next().unwrap_or_else(|| exit_with_usage());
+                let dictionary = fs::read(&file).unwrap_or_else(|e| exit_with_error(&format!("could not read {}: {}", file, e)));
+                // The limit only matters when compressing, the flags of a datagram tell if it used the dictionary
+                compression = Some(Compression::new().with_dictionary(dictionary, usize::MAX));
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+
+    let path = path.unwrap_or_else(|| exit_with_usage());
+    let datagrams = Capture::read(&path).unwrap_or_else(|e| exit_with_error(&format!("could not read {}: {}", path, e)));
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for datagram in &datagrams {
+        // Stop quietly when the output is piped into something like `head` that exits early
+        if writeln!(stdout, "{}", dump::dump(datagram, compression.as_ref())).is_err() {
+            return;
+        }
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("amethyst-protocol-dump: {}", message);
+    process::exit(1);
+}
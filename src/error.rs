@@ -1,3 +1,5 @@
+#![allow(non_local_definitions)]
+
 use failure;
 use std::result;
 
@@ -16,5 +18,25 @@ pub enum NetworkError {
     #[fail(display = "TCP client connections hash was poisoned")]
     TcpClientConnectionsHashPoisoned,
     #[fail(display = "The lock for a specific TCP client was poisoned")]
-    TcpClientLockFailed
+    TcpClientLockFailed,
+    #[fail(display = "There is no TCP client connected with that address")]
+    TcpClientNotFound,
+    #[fail(display = "The TCP server is already running")]
+    TcpServerAlreadyRunning,
+    #[fail(display = "The message is too large to be sent over TCP")]
+    TcpMessageTooLarge,
+    #[fail(display = "There is no peer with that id")]
+    PeerNotFound,
+    #[fail(display = "The peer has not linked its UDP address yet")]
+    PeerNotLinked,
+    #[fail(display = "The packet could not be encrypted")]
+    EncryptionFailed,
+    #[fail(display = "The connect token is invalid")]
+    InvalidConnectToken,
+    #[fail(display = "The connect token has too many server addresses or too much user data")]
+    ConnectTokenTooLarge,
+    #[fail(display = "The connect token was already used for a session, a new token is needed to connect again")]
+    ConnectTokenUsed,
+    #[fail(display = "No client has connected from that address with a connect token")]
+    ClientNotConnected,
 }
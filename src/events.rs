@@ -1,36 +1,133 @@
-use std::sync::Arc;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
 
 use net::connection::Connection;
 use net::connection::Quality;
+use net::endpoint::{PeerId, Transport};
+use packet::{Packet, PacketHandle};
 
 /// Events that are generated in response to a change in state of the connected client
+#[derive(Debug)]
 pub enum ConnectionEvent {
-    /// A new client connects. Clients are uniquely identified by the ip:port combination at this layer.
-    Connected{ conn: Arc<Connection> },
+    /// The first packet from a client arrived. Clients are uniquely identified by the ip:port combination at this layer.
+    Connected{ conn: Arc<RwLock<Connection>> },
     /// A client disconnects. This can be generated from the server-side intentionally disconnecting a client,
     /// or it could be from the client disconnecting.
-    Disconnected{ conn: Arc<Connection> },
+    Disconnected{ conn: Arc<RwLock<Connection>> },
     /// This is generated if the server has not seen traffic from a client for a configurable amount of time.
-    TimedOut{ conn: Arc<Connection> },
+    TimedOut{ conn: Arc<RwLock<Connection>> },
     /// This is generated when there is a change in the connection quality of a client.
-    QualityChange{ conn: Arc<Connection>, from: Quality, to: Quality },
+    QualityChange{ conn: Arc<RwLock<Connection>>, from: Quality, to: Quality },
+}
+
+/// Events that are generated by a `UdpSocket` running on a worker thread
+#[derive(Debug)]
+pub enum SocketEvent {
+    /// A packet was received.
+    Packet(Packet),
+    /// A connection was created or timed out.
+    Connection(ConnectionEvent),
+    /// A packet sent with `send_tracked` was acked or lost.
+    Delivery(DeliveryEvent),
+}
+
+/// What happened to a packet sent with `UdpSocket::send_tracked`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryEvent {
+    /// The other side acknowledged the packet.
+    Acked(PacketHandle),
+    /// The other side acknowledged 32 newer packets but not this one, so it most likely never arrived.
+    Lost(PacketHandle),
+}
+
+/// Events that are generated by the TCP server for its connected clients
+#[derive(Debug)]
+pub enum TcpEvent {
+    /// A new client connected to the server.
+    Connected{ addr: SocketAddr },
+    /// A client sent us a message.
+    Message{ addr: SocketAddr, payload: Vec<u8> },
+    /// A client was removed from the server, together with the reason why.
+    Disconnected{ addr: SocketAddr, reason: DisconnectReason },
+}
+
+/// Events that are generated by an `Endpoint` for its peers, no matter which transport they came from
+#[derive(Debug)]
+pub enum EndpointEvent {
+    /// A peer connected over TCP.
+    Connected{ peer: PeerId },
+    /// The UDP address of the peer was linked to its TCP stream, so it can be reached over both transports now.
+    Linked{ peer: PeerId, udp_addr: SocketAddr },
+    /// A peer sent us a message over one of the transports.
+    Message{ peer: PeerId, transport: Transport, payload: Vec<u8> },
+    /// The TCP stream of the peer was closed, which ends the peer on both transports.
+    Disconnected{ peer: PeerId, reason: DisconnectReason },
+}
+
+/// Events that are generated by a `Reactor`, for both transports
+#[cfg(feature = "mio")]
+#[derive(Debug)]
+pub enum ReactorEvent {
+    /// A packet was received over UDP.
+    Packet(Packet),
+    /// A TCP stream connected, sent a message or was disconnected.
+    Tcp(TcpEvent),
+}
+
+/// Describes why a TCP client was disconnected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The remote endpoint closed the stream (we read EOF).
+    Closed,
+    /// The remote endpoint reset or aborted the connection.
+    Reset,
+    /// Reading from the stream failed with the given error.
+    ReadFailed(io::ErrorKind),
+    /// Writing to the stream failed with the given error.
+    WriteFailed(io::ErrorKind),
+    /// Nothing was received from the remote endpoint within the read timeout.
+    TimedOut,
+    /// The remote endpoint did not answer a ping within the heartbeat timeout.
+    HeartbeatTimeout,
+    /// The server was shut down.
+    Shutdown,
+}
+
+impl DisconnectReason {
+    /// Maps an error that occurred while reading from a stream to a disconnect reason.
+    pub fn from_read_error(error: &io::Error) -> DisconnectReason {
+        match error.kind() {
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => DisconnectReason::Reset,
+            kind => DisconnectReason::ReadFailed(kind),
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::ConnectionEvent;
+    use super::{ConnectionEvent, DisconnectReason};
     use net::connection::Connection;
-    use std::sync::Arc;
+    use std::io;
+    use std::sync::{Arc, RwLock};
     use std::net::ToSocketAddrs;
 
-    static TEST_HOST_IP: &'static str = "127.0.0.1";
-    static TEST_PORT: &'static str = "20000";
+    static TEST_HOST_IP: &str = "127.0.0.1";
+    static TEST_PORT: &str = "20000";
 
     #[test]
     fn test_create_event() {
         let addr = format!("{}:{}", TEST_HOST_IP, TEST_PORT).to_socket_addrs();
         let mut addr = addr.unwrap();
-        let test_conn = Arc::new(Connection::new(addr.next().unwrap()));
+        let test_conn = Arc::new(RwLock::new(Connection::new(addr.next().unwrap())));
         let _ = ConnectionEvent::Connected{conn: test_conn};
     }
+
+    #[test]
+    fn test_read_error_reason() {
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        let other = io::Error::new(io::ErrorKind::InvalidData, "garbage");
+        assert_eq!(DisconnectReason::from_read_error(&reset), DisconnectReason::Reset);
+        assert_eq!(DisconnectReason::from_read_error(&other), DisconnectReason::ReadFailed(io::ErrorKind::InvalidData));
+    }
 }
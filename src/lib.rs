@@ -1,8 +1,19 @@
 //! Amethysts networking protocol
 
 extern crate bincode;
+extern crate chacha20poly1305;
+extern crate crossbeam_channel;
 extern crate failure;
+extern crate hkdf;
+extern crate lz4_flex;
+#[cfg(feature = "mio")]
+extern crate mio;
+extern crate rand;
 extern crate serde;
+extern crate sha2;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+extern crate x25519_dalek;
 
 #[macro_use]
 extern crate serde_derive;
@@ -17,5 +28,14 @@ mod packet;
 pub mod error;
 pub mod events;
 
-pub use net::udp::UdpSocket;
-use packet::{Packet, RawPacket};
+pub use net::{Capture, CaptureFormat, CapturedDatagram, Channel, ClockSample, ClockSync, Compression, ConnectToken, ConnectedClient, Connection, Diff, Direction, Dropped, Endpoint, FloodProtection, LinkConditioner, Message, MethodId, PeerId, Priority, Quality, Replay, RequestId, Rpc, RpcError, RpcTransport, RttHistogram, SnapshotBuffer, TcpSocketState, TcpStats, Transport, UdpSocket, UdpStats, Worker};
+#[cfg(feature = "prometheus")]
+pub use net::{MetricsServer, Prometheus};
+#[cfg(feature = "tokio")]
+pub use net::{AsyncTcpListener, AsyncTcpStream, AsyncUdpSocket};
+#[cfg(feature = "mio")]
+pub use net::Reactor;
+pub use net::dump;
+pub use net::snapshot;
+pub use packet::{PacketHandle, PacketType};
+use packet::{Packet, RawPacket, RAW_PACKET_HEADER_SIZE};
@@ -0,0 +1,272 @@
+use std::future::{poll_fn, Future};
+use std::io;
+use std::net::{self, SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream};
+use tokio::time::{interval, sleep, Interval, MissedTickBehavior, Sleep};
+
+use super::frame::{Frame, FrameBuffer};
+
+// How much we try to read from the stream at once
+const READ_SIZE: usize = 4096;
+
+/// The tokio version of the TCP server, it accepts `AsyncTcpStream`s.
+///
+/// It has to be created inside a tokio runtime with IO enabled.
+pub struct AsyncTcpListener {
+    listener: TokioTcpListener,
+}
+
+impl AsyncTcpListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<AsyncTcpListener> {
+        let listener = net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(AsyncTcpListener {
+            listener: TokioTcpListener::from_std(listener)?,
+        })
+    }
+
+    /// Waits for the next client.
+    pub fn accept<'a>(&'a mut self) -> impl Future<Output = io::Result<AsyncTcpStream>> + 'a {
+        poll_fn(move |cx| match self.listener.poll_accept(cx) {
+            Poll::Ready(Ok((stream, _))) => Poll::Ready(Ok(AsyncTcpStream::new(stream))),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        })
+    }
+
+    /// Returns the address this listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+/// A TCP stream speaking the same framed protocol as `TcpSocketState`, for tokio.
+///
+/// Pings from the other side are answered while `recv` runs. With a heartbeat, `recv` also sends the pings and fails with `TimedOut` when the other side does not answer in time.
+pub struct AsyncTcpStream {
+    stream: TokioTcpStream,
+    read_buffer: FrameBuffer,
+    write_buffer: Vec<u8>,
+    heartbeat: Option<Heartbeat>,
+}
+
+// Send a ping every tick of `timer` and expect something back within `timeout` after it
+struct Heartbeat {
+    timer: Interval,
+    timeout: Duration,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl AsyncTcpStream {
+    fn new(stream: TokioTcpStream) -> AsyncTcpStream {
+        AsyncTcpStream {
+            stream,
+            read_buffer: FrameBuffer::new(),
+            write_buffer: Vec::new(),
+            heartbeat: None,
+        }
+    }
+
+    /// Connects to a TCP server.
+    pub fn connect(addr: SocketAddr) -> impl Future<Output = io::Result<AsyncTcpStream>> {
+        let mut connect = Box::pin(TokioTcpStream::connect(addr));
+        poll_fn(move |cx| match connect.as_mut().poll(cx) {
+            Poll::Ready(Ok(stream)) => Poll::Ready(Ok(AsyncTcpStream::new(stream))),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        })
+    }
+
+    /// Sends a ping every `ping_interval` while `recv` runs, and fails `recv` when nothing comes back within `timeout` after a ping.
+    pub fn with_heartbeat(mut self, ping_interval: Duration, timeout: Duration) -> AsyncTcpStream {
+        let mut timer = interval(ping_interval);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.heartbeat = Some(Heartbeat {
+            timer,
+            timeout,
+            deadline: None,
+        });
+        self
+    }
+
+    /// Sends a message.
+    pub fn send<'a>(&'a mut self, payload: Vec<u8>) -> impl Future<Output = io::Result<()>> + 'a {
+        let mut frame = Some(Frame::Data(payload));
+        poll_fn(move |cx| {
+            if let Some(frame) = frame.take() {
+                frame.write_to(&mut self.write_buffer)?;
+            }
+            self.poll_flush(cx)
+        })
+    }
+
+    /// Waits for the next message. Returns `None` once the other side closed the stream.
+    pub fn recv<'a>(&'a mut self) -> impl Future<Output = io::Result<Option<Vec<u8>>>> + 'a {
+        poll_fn(move |cx| loop {
+            // Pongs and pings go out while we wait, a full send buffer does not stop us from reading
+            if let Poll::Ready(Err(e)) = self.poll_flush(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            if let Some(frame) = self.read_buffer.decode()? {
+                if let Some(ref mut heartbeat) = self.heartbeat {
+                    heartbeat.deadline = None;
+                }
+                match frame {
+                    Frame::Data(payload) => return Poll::Ready(Ok(Some(payload))),
+                    Frame::Ping => Frame::Pong.write_to(&mut self.write_buffer)?,
+                    Frame::Pong => {}
+                }
+                continue;
+            }
+
+            if let Some(ref mut heartbeat) = self.heartbeat {
+                if heartbeat.timer.poll_tick(cx).is_ready() && heartbeat.deadline.is_none() {
+                    Frame::Ping.write_to(&mut self.write_buffer)?;
+                    heartbeat.deadline = Some(Box::pin(sleep(heartbeat.timeout)));
+                    continue;
+                }
+                if let Some(ref mut deadline) = heartbeat.deadline {
+                    if deadline.as_mut().poll(cx).is_ready() {
+                        return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "the other side did not answer the heartbeat")));
+                    }
+                }
+            }
+
+            match self.stream.poll_read_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            let mut buffer = [0; READ_SIZE];
+            match self.stream.try_read(&mut buffer) {
+                Ok(0) => return Poll::Ready(Ok(None)),
+                Ok(len) => self.read_buffer.extend(&buffer[..len]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        })
+    }
+
+    /// Returns the address of the other side.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Returns the local address of the stream.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.stream.set_nodelay(nodelay)
+    }
+
+    // Writes as much of the write buffer as the stream takes
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while !self.write_buffer.is_empty() {
+            match self.stream.poll_write_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            match self.stream.try_write(&self.write_buffer) {
+                Ok(len) => {
+                    self.write_buffer.drain(..len);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AsyncTcpListener, AsyncTcpStream};
+    use events::TcpEvent;
+    use net::TcpSocketState;
+    use std::io;
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::{Builder, Runtime};
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().enable_all().build().unwrap()
+    }
+
+    #[test]
+    fn async_streams_exchange_messages() {
+        let runtime = runtime();
+        let _guard = runtime.enter();
+        let addr: SocketAddr = "127.0.0.1:27011".parse().unwrap();
+        let mut listener = AsyncTcpListener::bind(addr).unwrap();
+
+        let mut client = runtime.block_on(AsyncTcpStream::connect(addr)).unwrap();
+        let mut server = runtime.block_on(listener.accept()).unwrap();
+        assert_eq!(server.peer_addr().unwrap(), client.local_addr().unwrap());
+
+        runtime.block_on(client.send(b"hello".to_vec())).unwrap();
+        assert_eq!(runtime.block_on(server.recv()).unwrap(), Some(b"hello".to_vec()));
+        runtime.block_on(server.send(b"world".to_vec())).unwrap();
+        assert_eq!(runtime.block_on(client.recv()).unwrap(), Some(b"world".to_vec()));
+
+        drop(client);
+        assert_eq!(runtime.block_on(server.recv()).unwrap(), None);
+    }
+
+    #[test]
+    fn talks_to_the_blocking_server() {
+        let addr: SocketAddr = "127.0.0.1:27012".parse().unwrap();
+        let mut state = TcpSocketState::new();
+        state.start(addr).unwrap();
+
+        let runtime = runtime();
+        let _guard = runtime.enter();
+        let mut client = runtime.block_on(AsyncTcpStream::connect(addr)).unwrap();
+        let client_addr = client.local_addr().unwrap();
+        runtime.block_on(client.send(b"hello".to_vec())).unwrap();
+
+        let started = Instant::now();
+        loop {
+            assert!(started.elapsed() < Duration::from_secs(5), "no message from the async client");
+            match state.events().next() {
+                Some(TcpEvent::Message { addr, payload }) => {
+                    assert_eq!(addr, client_addr);
+                    assert_eq!(payload, b"hello");
+                    break;
+                }
+                Some(_) => {}
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+
+        state.send(client_addr, b"world".to_vec()).unwrap();
+        assert_eq!(runtime.block_on(client.recv()).unwrap(), Some(b"world".to_vec()));
+        state.shutdown();
+    }
+
+    #[test]
+    fn heartbeat_times_out_silent_streams() {
+        let runtime = runtime();
+        let _guard = runtime.enter();
+        let addr: SocketAddr = "127.0.0.1:27013".parse().unwrap();
+        let mut listener = AsyncTcpListener::bind(addr).unwrap();
+
+        // A plain stream never answers the pings
+        let _silent = TcpStream::connect(addr).unwrap();
+        let server = runtime.block_on(listener.accept()).unwrap();
+        let mut server = server.with_heartbeat(Duration::from_millis(50), Duration::from_millis(100));
+
+        let error = runtime.block_on(server.recv()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+}
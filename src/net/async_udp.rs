@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::net::{self, IpAddr, SocketAddr, ToSocketAddrs};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket as TokioUdpSocket;
+use tokio::time::{interval, MissedTickBehavior};
+
+use super::compression::Compression;
+use super::connect_token::{ConnectToken, ConnectedClient};
+use super::encryption::Encryption;
+use super::flood_protection::FloodProtection;
+use super::protocol::{Datagram, Protocol};
+use super::socket_state::TIMEOUT_POLL_INTERVAL;
+use super::udp::BUFFER_SIZE;
+use super::{ClockSync, Packet, PacketHandle, SocketState, UdpStats};
+
+use error::Result;
+use events::DeliveryEvent;
+
+/// The tokio version of `UdpSocket`.
+///
+/// It runs the same protocol, so both can talk to each other. Instead of a thread, a tokio task removes timed out connections; it ends when the socket is dropped.
+/// The socket has to be created inside a tokio runtime with IO and timers enabled.
+///
+/// Replies to handshakes and MTU probes are queued and go out on the next `send` or `recv`.
+pub struct AsyncUdpSocket {
+    socket: TokioUdpSocket,
+    protocol: Protocol,
+    recv_buffer: [u8; BUFFER_SIZE],
+    outgoing: VecDeque<Datagram>,
+}
+
+impl AsyncUdpSocket {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let socket = net::UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let socket = TokioUdpSocket::from_std(socket)?;
+
+        let state = SocketState::without_timeout_thread();
+        let check = state.timeout_check();
+        let mut timer = interval(Duration::from_secs(TIMEOUT_POLL_INTERVAL));
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        tokio::spawn(poll_fn(move |cx| loop {
+            if timer.poll_tick(cx).is_pending() {
+                return Poll::Pending;
+            }
+            if !check.run() {
+                return Poll::Ready(());
+            }
+        }));
+
+        Ok(AsyncUdpSocket {
+            socket,
+            protocol: Protocol::new(state),
+            recv_buffer: [0; BUFFER_SIZE],
+            outgoing: VecDeque::new(),
+        })
+    }
+
+    /// Encrypts everything this socket sends and only accepts encrypted datagrams, see `UdpSocket::with_encryption`.
+    pub fn with_encryption(mut self) -> Self {
+        self.protocol.encryption = Some(Encryption::new());
+        self
+    }
+
+    /// Connects to the servers in `token` with it, see `UdpSocket::with_connect_token`.
+    pub fn with_connect_token(mut self, token: ConnectToken) -> Self {
+        self.protocol.encryption = Some(self.protocol.encryption.take().unwrap_or_default().with_connect_token(token));
+        self
+    }
+
+    /// Only accepts clients with a connect token signed with `key` that lists `public_addr`, see `UdpSocket::with_token_key`.
+    pub fn with_token_key(mut self, key: [u8; 32], public_addr: SocketAddr) -> Self {
+        self.protocol.encryption = Some(self.protocol.encryption.take().unwrap_or_default().with_token_key(key, public_addr));
+        self
+    }
+
+    /// Compresses the datagrams this socket sends, see `Compression`.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.protocol.compression = Some(compression);
+        self
+    }
+
+    /// Pings the other side of every connection now and then to estimate its clock, see `UdpSocket::with_clock_sync`.
+    pub fn with_clock_sync(mut self) -> Self {
+        self.protocol.clock_sync = true;
+        self
+    }
+
+    /// Checks every received datagram against the given flood protection before anything else is done with it.
+    pub fn with_flood_protection(mut self, flood_protection: FloodProtection) -> Self {
+        self.protocol.flood_protection = Some(flood_protection);
+        self
+    }
+
+    /// Returns the client that connected from `addr` with a connect token, if any.
+    pub fn connected_client(&self, addr: SocketAddr) -> Option<&ConnectedClient> {
+        self.protocol.connected_client(addr)
+    }
+
+    /// Returns the IP addresses the flood protection banned.
+    pub fn banned_addresses(&self) -> Vec<IpAddr> {
+        self.protocol.banned_addresses()
+    }
+
+    /// Waits for the next packet.
+    pub fn recv<'a>(&'a mut self) -> impl Future<Output = io::Result<Packet>> + 'a {
+        poll_fn(move |cx| loop {
+            if let Poll::Ready(Err(e)) = self.poll_flush(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            let mut buffer = ReadBuf::new(&mut self.recv_buffer);
+            let addr = match self.socket.poll_recv_from(cx, &mut buffer) {
+                Poll::Ready(Ok(addr)) => addr,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if buffer.filled().is_empty() {
+                continue;
+            }
+            let data = buffer.filled().to_vec();
+
+            // Handshake, rejected and duplicate datagrams are handled here, keep reading until there is a packet
+            let received = self.protocol.receive(addr, data)?;
+            for data in received.replies {
+                self.outgoing.push_back(Datagram { addr, data, probe: false });
+            }
+            if let Some(packet) = received.packet {
+                return Poll::Ready(Ok(packet));
+            }
+        })
+    }
+
+    /// Sends a packet, and a path MTU probe after it if one is due. Returns the number of bytes sent for the packet.
+    pub fn send<'a>(&'a mut self, packet: Packet) -> impl Future<Output = Result<io::Result<usize>>> + 'a {
+        let mut packet = Some(packet);
+        let mut sent = 0;
+
+        poll_fn(move |cx| {
+            if let Some(packet) = packet.take() {
+                let (addr, datagrams) = self.protocol.send(packet)?;
+                sent = self.queue(addr, datagrams)?;
+            }
+
+            match self.poll_flush(cx) {
+                Poll::Ready(Ok(())) => Poll::Ready(Ok(Ok(sent))),
+                Poll::Ready(Err(e)) => Poll::Ready(Ok(Err(e))),
+                Poll::Pending => Poll::Pending,
+            }
+        })
+    }
+
+    /// Like `send`, and returns the handle of the `DeliveryEvent`s about the packet, see `UdpSocket::send_tracked`.
+    pub fn send_tracked<'a>(&'a mut self, packet: Packet) -> impl Future<Output = Result<io::Result<PacketHandle>>> + 'a {
+        let mut packet = Some(packet);
+        let mut handle = None;
+
+        poll_fn(move |cx| {
+            if let Some(packet) = packet.take() {
+                let sent_handle = self.protocol.state.new_handle(packet.addr());
+                let (addr, datagrams) = self.protocol.send_tracked(packet, vec![sent_handle])?;
+                self.queue(addr, datagrams)?;
+                handle = Some(sent_handle);
+            }
+
+            match (self.poll_flush(cx), handle) {
+                (Poll::Ready(Ok(())), Some(handle)) => Poll::Ready(Ok(Ok(handle))),
+                (Poll::Ready(Err(e)), _) => Poll::Ready(Ok(Err(e))),
+                _ => Poll::Pending,
+            }
+        })
+    }
+
+    /// Returns the packets sent with `send_tracked` that were acked or lost since the last call.
+    pub fn delivery_events(&mut self) -> Vec<DeliveryEvent> {
+        self.protocol.state.take_delivery_events()
+    }
+
+    // Queues the datagrams of a packet, and a path MTU probe and clock sync ping after them if they are due. Returns the bytes queued for the packet.
+    fn queue(&mut self, addr: SocketAddr, datagrams: Vec<Vec<u8>>) -> Result<usize> {
+        let mut queued = 0;
+        for data in datagrams {
+            queued += data.len();
+            self.outgoing.push_back(Datagram { addr, data, probe: false });
+        }
+        for data in self.protocol.probe(addr)? {
+            self.outgoing.push_back(Datagram { addr, data, probe: true });
+        }
+        for data in self.protocol.time_ping(addr)? {
+            self.outgoing.push_back(Datagram { addr, data, probe: false });
+        }
+        Ok(queued)
+    }
+
+    /// Returns the biggest payload that fits in a single datagram to `addr`, see `UdpSocket::max_payload_size`.
+    pub fn max_payload_size(&self, addr: SocketAddr) -> usize {
+        self.protocol.max_payload_size(addr)
+    }
+
+    /// Returns the clock sync state of the connection with `addr`, with the estimated offset and drift of its clock.
+    pub fn clock(&self, addr: SocketAddr) -> Option<ClockSync> {
+        self.protocol.state.clock(addr)
+    }
+
+    /// Returns the time on our clock sync clock, in microseconds.
+    pub fn clock_time(&self) -> i64 {
+        self.protocol.state.clock_time()
+    }
+
+    /// Returns the time on the clock of `addr` right now, in microseconds, once clock sync has an estimate.
+    pub fn remote_time(&self, addr: SocketAddr) -> Option<i64> {
+        self.protocol.remote_time(addr)
+    }
+
+    /// Returns the packets sent to `addr` that the other side never acknowledged, so they can be resent.
+    pub fn dropped_packets(&mut self, addr: SocketAddr) -> Result<Vec<Packet>> {
+        self.protocol.state.dropped_packets(addr)
+    }
+
+    /// Returns the sequence numbers of the packets sent to `addr` that the other side acknowledged since the last call, oldest first.
+    pub fn acked_packets(&mut self, addr: SocketAddr) -> Result<Vec<u16>> {
+        self.protocol.state.acked_packets(addr)
+    }
+
+    /// Returns the sequence number the next packet sent to `addr` gets.
+    pub fn next_seq(&self, addr: SocketAddr) -> u16 {
+        self.protocol.state.next_seq(addr)
+    }
+
+    pub fn stats(&self) -> UdpStats {
+        self.protocol.stats()
+    }
+
+    /// Returns the address this socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // Sends the queued datagrams, a probe that can not be sent is reported to path MTU discovery instead of failing
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while let Some(datagram) = self.outgoing.front() {
+            let result = match self.socket.poll_send_to(cx, &datagram.data, datagram.addr) {
+                Poll::Ready(result) => result,
+                Poll::Pending => return Poll::Pending,
+            };
+            let datagram = self.outgoing.pop_front().expect("the queue has a datagram");
+
+            if let Err(e) = result {
+                if !datagram.probe {
+                    return Poll::Ready(Err(e));
+                }
+                debug!("Could not send MTU probe to {}: {}", datagram.addr, e);
+                self.protocol.probe_failed(datagram.addr).map_err(|e| io::Error::other(e.to_string()))?;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncUdpSocket;
+    use net::{Compression, UdpSocket};
+    use packet::Packet;
+    use std::net::SocketAddr;
+    use std::thread;
+    use tokio::runtime::{Builder, Runtime};
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().enable_all().build().unwrap()
+    }
+
+    #[test]
+    fn async_sockets_talk_to_each_other() {
+        let runtime = runtime();
+        let _guard = runtime.enter();
+        let server_addr: SocketAddr = "127.0.0.1:12437".parse().unwrap();
+        let client_addr: SocketAddr = "127.0.0.1:12438".parse().unwrap();
+        let mut server = AsyncUdpSocket::bind(server_addr).unwrap().with_compression(Compression::new());
+        let mut client = AsyncUdpSocket::bind(client_addr).unwrap().with_compression(Compression::new());
+
+        let sent = runtime.block_on(client.send(Packet::new(server_addr, vec![7; 100]))).unwrap().unwrap();
+        assert!(sent > 0 && sent < 100);
+
+        let packet = runtime.block_on(server.recv()).unwrap();
+        assert_eq!(packet.addr(), client_addr);
+        assert_eq!(packet.payload(), &[7; 100][..]);
+
+        runtime.block_on(server.send(Packet::new(client_addr, vec![1, 2, 3]))).unwrap().unwrap();
+        let packet = runtime.block_on(client.recv()).unwrap();
+        assert_eq!(packet.payload(), &[1, 2, 3]);
+        assert_eq!(client.stats().received_packets, 1);
+    }
+
+    #[test]
+    fn talks_to_the_blocking_socket() {
+        let server_addr: SocketAddr = "127.0.0.1:12447".parse().unwrap();
+        let client_addr: SocketAddr = "127.0.0.1:12448".parse().unwrap();
+        let mut server = UdpSocket::bind(server_addr).unwrap().with_encryption();
+
+        let server_thread = thread::spawn(move || {
+            let packet = server.recv().unwrap().unwrap();
+            server.send(Packet::new(packet.addr(), packet.payload().to_vec())).unwrap().unwrap();
+        });
+
+        let runtime = runtime();
+        let _guard = runtime.enter();
+        let mut client = AsyncUdpSocket::bind(client_addr).unwrap().with_encryption();
+
+        // The packet waits for the handshake, receiving the server's answer to it sends the packet
+        runtime.block_on(client.send(Packet::new(server_addr, b"echo".to_vec()))).unwrap().unwrap();
+        let packet = runtime.block_on(client.recv()).unwrap();
+        assert_eq!(packet.addr(), server_addr);
+        assert_eq!(packet.payload(), b"echo");
+
+        server_thread.join().unwrap();
+    }
+}
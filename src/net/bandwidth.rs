@@ -0,0 +1,191 @@
+use std::cmp::Reverse;
+use std::time::Instant;
+
+use super::{message, PacketHandle};
+
+// A deferred packet moves up a priority every this many flushes, so a steady stream of high priority packets can not hold back the others forever
+const AGING_FLUSHES: u32 = 4;
+
+/// How much a packet matters when there is not enough bandwidth to send everything, see `UdpSocket::with_bandwidth_budget`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Nice to have, like cosmetic effects. These are dropped instead of deferred while the connection quality is `Bad`.
+    Low,
+    #[default]
+    Normal,
+    /// Has to get there, like damage and spawns.
+    High,
+}
+
+/// Allows sending `rate` bytes per second, and bursts of up to a second's worth after being idle.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: usize,
+    // Goes negative when a packet bigger than what was left is sent
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: usize, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Adds the tokens earned since the last refill.
+    pub fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+    }
+
+    /// Returns the bytes that can be sent right now.
+    pub fn available(&self) -> usize {
+        self.tokens.max(0.0) as usize
+    }
+
+    /// Returns true if the bucket is full, so it is no different from a new one.
+    pub fn is_full(&self) -> bool {
+        self.tokens >= self.rate as f64
+    }
+
+    /// Takes the tokens for `bytes` that were sent.
+    pub fn take(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// A packet payload waiting for `UdpSocket::flush`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Queued {
+    pub priority: Priority,
+    pub payload: Vec<u8>,
+    // Flushes this payload was held back for
+    pub deferred: u32,
+    // Set for packets sent with `UdpSocket::send_tracked`
+    pub handle: Option<PacketHandle>,
+}
+
+impl Queued {
+    pub fn new(priority: Priority, payload: Vec<u8>) -> Queued {
+        Queued {
+            priority,
+            payload,
+            deferred: 0,
+            handle: None,
+        }
+    }
+
+    fn effective_priority(&self) -> u32 {
+        self.priority as u32 + self.deferred / AGING_FLUSHES
+    }
+}
+
+/// What a flush sends, and what it held back.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Schedule {
+    /// Payloads to send now, most important first.
+    pub send: Vec<Queued>,
+    /// Payloads that wait for the next flush.
+    pub deferred: Vec<Queued>,
+    /// How many low priority payloads were dropped.
+    pub dropped: usize,
+}
+
+/// Picks the payloads that fit in `budget` bytes, by priority and then in the order they were queued.
+///
+/// The payload that goes over the budget is still sent, so payloads bigger than the budget are not stuck. The rest are deferred.
+pub fn schedule(mut queue: Vec<Queued>, budget: usize) -> Schedule {
+    // Stable, so payloads of the same priority keep their order
+    queue.sort_by_key(|queued| Reverse(queued.effective_priority()));
+
+    let mut schedule = Schedule::default();
+    let mut left = budget;
+    for mut queued in queue {
+        if left > 0 {
+            left = left.saturating_sub(message::packed_size(queued.payload.len()));
+            schedule.send.push(queued);
+        } else {
+            queued.deferred += 1;
+            schedule.deferred.push(queued);
+        }
+    }
+    schedule
+}
+
+/// Like `schedule`, but drops the `Low` payloads that do not fit instead of deferring them.
+pub fn schedule_dropping_low(queue: Vec<Queued>, budget: usize) -> Schedule {
+    let mut schedule = schedule(queue, budget);
+    let before = schedule.deferred.len();
+    schedule.deferred.retain(|queued| queued.priority != Priority::Low);
+    schedule.dropped = before - schedule.deferred.len();
+    schedule
+}
+
+#[cfg(test)]
+mod test {
+    use super::{schedule, schedule_dropping_low, Priority, Queued, TokenBucket};
+    use std::time::{Duration, Instant};
+
+    fn queue(priorities: &[Priority]) -> Vec<Queued> {
+        priorities.iter().enumerate().map(|(i, &priority)| Queued::new(priority, vec![i as u8; 97])).collect()
+    }
+
+    fn payloads(queue: &[Queued]) -> Vec<Vec<u8>> {
+        queue.iter().map(|queued| queued.payload.clone()).collect()
+    }
+
+    #[test]
+    fn sends_by_priority_within_the_budget() {
+        let queue = queue(&[Priority::Low, Priority::Normal, Priority::High, Priority::Normal]);
+        // Each payload takes 100 bytes packed
+        let schedule = schedule(queue, 250);
+        assert_eq!(payloads(&schedule.send), vec![vec![2; 97], vec![1; 97], vec![3; 97]]);
+        assert_eq!(schedule.deferred.len(), 1);
+        assert_eq!(schedule.deferred[0].priority, Priority::Low);
+        assert_eq!(schedule.deferred[0].deferred, 1);
+    }
+
+    #[test]
+    fn deferred_payloads_are_not_starved() {
+        let mut waiting = queue(&[Priority::Low]);
+        let mut flushes = 0;
+        loop {
+            flushes += 1;
+            // A new high priority payload every flush, and only room for one
+            waiting.push(Queued::new(Priority::High, vec![9; 97]));
+            let schedule = schedule(waiting, 1);
+            if schedule.send[0].payload == vec![0; 97] {
+                break;
+            }
+            waiting = schedule.deferred;
+            assert!(flushes < 20, "the low priority payload starved");
+        }
+        assert_eq!(flushes, 9);
+    }
+
+    #[test]
+    fn drops_low_priority_payloads() {
+        let schedule = schedule_dropping_low(queue(&[Priority::High, Priority::Low, Priority::Normal]), 1);
+        assert_eq!(payloads(&schedule.send), vec![vec![0; 97]]);
+        assert_eq!(schedule.deferred.len(), 1);
+        assert_eq!(schedule.dropped, 1);
+    }
+
+    #[test]
+    fn token_bucket_refills_up_to_a_second() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        assert!(bucket.is_full());
+        bucket.take(1500);
+        assert_eq!(bucket.available(), 0);
+
+        bucket.refill(start + Duration::from_millis(1000));
+        assert_eq!(bucket.available(), 500);
+        bucket.refill(start + Duration::from_secs(10));
+        assert_eq!(bucket.available(), 1000);
+    }
+}
@@ -0,0 +1,502 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bincode::{deserialize, serialize};
+
+use super::compression::Compression;
+use super::link_conditioner::Direction;
+use super::protocol::read_raw_packet;
+use super::{Connection, Packet, SocketState, UdpStats};
+
+// Starts a capture in our own format
+const NATIVE_MAGIC: &[u8; 8] = b"AMCAP001";
+// Starts a pcap file with microsecond timestamps, written little endian
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+// Linux cooked capture, the only common link type with a direction in every packet
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const SLL_HEADER_SIZE: usize = 16;
+const SLL_INCOMING: u16 = 0;
+const SLL_OUTGOING: u16 = 4;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+const IP_PROTOCOL_UDP: u8 = 17;
+// Frames are never longer than the snapshot length in the pcap header
+const SNAPLEN: u32 = 65_535;
+// A native record is a datagram plus its time and addresses, which take well under a kilobyte
+const MAX_NATIVE_RECORD_SIZE: u32 = SNAPLEN + 1024;
+
+/// The file format of a `Capture`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureFormat {
+    /// A pcap file Wireshark can open, every datagram is wrapped in made up IP and UDP headers.
+    Pcap,
+    /// Our own format, it stores the datagrams as they are.
+    Native,
+}
+
+/// A datagram a `UdpSocket` sent or received, as it was on the wire.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedDatagram {
+    /// When the datagram was sent or received, since the UNIX epoch.
+    pub time: Duration,
+    pub outgoing: bool,
+    /// The address of the socket that captured the datagram.
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+impl CapturedDatagram {
+    pub fn direction(&self) -> Direction {
+        if self.outgoing {
+            Direction::Outgoing
+        } else {
+            Direction::Incoming
+        }
+    }
+}
+
+/// Writes every datagram a `UdpSocket` sends and receives to a file, see `UdpSocket::set_capture`.
+///
+/// Datagrams are captured as they are on the wire, compressed and encrypted. The link conditioner is left out: outgoing datagrams are captured before it, incoming ones after it.
+/// The file is flushed when the capture is dropped.
+pub struct Capture {
+    writer: BufWriter<File>,
+    format: CaptureFormat,
+    local: Option<SocketAddr>,
+}
+
+impl Capture {
+    /// Creates the file at `path`, replacing any file that is there.
+    pub fn create<P: AsRef<Path>>(path: P, format: CaptureFormat) -> io::Result<Capture> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        match format {
+            CaptureFormat::Pcap => {
+                writer.write_all(&PCAP_MAGIC.to_le_bytes())?;
+                // Version 2.4, UTC, no snapshot length to speak of
+                writer.write_all(&2_u16.to_le_bytes())?;
+                writer.write_all(&4_u16.to_le_bytes())?;
+                writer.write_all(&0_i32.to_le_bytes())?;
+                writer.write_all(&0_u32.to_le_bytes())?;
+                writer.write_all(&SNAPLEN.to_le_bytes())?;
+                writer.write_all(&LINKTYPE_LINUX_SLL.to_le_bytes())?;
+            }
+            CaptureFormat::Native => writer.write_all(NATIVE_MAGIC)?,
+        }
+
+        Ok(Capture {
+            writer,
+            format,
+            local: None,
+        })
+    }
+
+    /// Reads the datagrams of a capture in either format.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<CapturedDatagram>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+
+        if &magic == NATIVE_MAGIC {
+            read_native(reader)
+        } else if magic[..4] == PCAP_MAGIC.to_le_bytes() {
+            // The rest of the header only matters to tools that write other link types
+            let mut rest = [0; 16];
+            reader.read_exact(&mut rest)?;
+            if rest[12..] != LINKTYPE_LINUX_SLL.to_le_bytes() {
+                return Err(invalid_data("the pcap file was not written by a capture"));
+            }
+            read_pcap(reader)
+        } else {
+            Err(invalid_data("not a capture file"))
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub(super) fn set_local_addr(&mut self, local: SocketAddr) {
+        self.local = Some(local);
+    }
+
+    // Writes a datagram, a failing capture must not take the socket down with it
+    pub(super) fn record(&mut self, direction: Direction, remote: SocketAddr, data: &[u8]) {
+        let datagram = CapturedDatagram {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            outgoing: direction == Direction::Outgoing,
+            local: self.local.unwrap_or_else(|| SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)),
+            remote,
+            data: data.to_vec(),
+        };
+        if let Err(e) = self.write(&datagram) {
+            error!("Could not capture datagram: {}", e);
+        }
+    }
+
+    /// Writes a datagram to the capture.
+    pub fn write(&mut self, datagram: &CapturedDatagram) -> io::Result<()> {
+        match self.format {
+            CaptureFormat::Pcap => write_pcap(&mut self.writer, datagram),
+            CaptureFormat::Native => {
+                let record = serialize(datagram).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                self.writer.write_all(&(record.len() as u32).to_le_bytes())?;
+                self.writer.write_all(&record)
+            }
+        }
+    }
+}
+
+fn read_native<R: Read>(mut reader: R) -> io::Result<Vec<CapturedDatagram>> {
+    let mut datagrams = Vec::new();
+    loop {
+        let mut len = [0; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(datagrams),
+            Err(e) => return Err(e),
+        }
+        let len = u32::from_le_bytes(len);
+        if len > MAX_NATIVE_RECORD_SIZE {
+            return Err(invalid_data("record in the capture is too long"));
+        }
+        let mut record = vec![0; len as usize];
+        reader.read_exact(&mut record)?;
+        datagrams.push(deserialize(&record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+    }
+}
+
+fn write_pcap<W: Write>(writer: &mut W, datagram: &CapturedDatagram) -> io::Result<()> {
+    let (source, destination) = if datagram.outgoing {
+        (datagram.local, datagram.remote)
+    } else {
+        (datagram.remote, datagram.local)
+    };
+
+    let udp_len = UDP_HEADER_SIZE + datagram.data.len();
+    let mut frame = Vec::with_capacity(SLL_HEADER_SIZE + IPV6_HEADER_SIZE + udp_len);
+
+    // Wireshark shows the packet type as the direction. No link layer address.
+    let packet_type = if datagram.outgoing { SLL_OUTGOING } else { SLL_INCOMING };
+    frame.extend_from_slice(&packet_type.to_be_bytes());
+    frame.extend_from_slice(&1_u16.to_be_bytes());
+    frame.extend_from_slice(&0_u16.to_be_bytes());
+    frame.extend_from_slice(&[0; 8]);
+
+    // Mixed address families happen with dual stack sockets, those go out as IPv6
+    let pseudo_header = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+            let mut header = [0; IPV4_HEADER_SIZE];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&((IPV4_HEADER_SIZE + udp_len) as u16).to_be_bytes());
+            // Don't fragment, time to live of 64
+            header[6] = 0x40;
+            header[8] = 64;
+            header[9] = IP_PROTOCOL_UDP;
+            header[12..16].copy_from_slice(&src.octets());
+            header[16..20].copy_from_slice(&dst.octets());
+            let checksum = checksum(&[&header]);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            frame.extend_from_slice(&header);
+
+            let mut pseudo_header = Vec::with_capacity(12);
+            pseudo_header.extend_from_slice(&src.octets());
+            pseudo_header.extend_from_slice(&dst.octets());
+            pseudo_header.extend_from_slice(&[0, IP_PROTOCOL_UDP]);
+            pseudo_header.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            pseudo_header
+        }
+        (src, dst) => {
+            let src = to_ipv6(src).octets();
+            let dst = to_ipv6(dst).octets();
+            frame.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+            frame.extend_from_slice(&[0x60, 0, 0, 0]);
+            frame.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            frame.extend_from_slice(&[IP_PROTOCOL_UDP, 64]);
+            frame.extend_from_slice(&src);
+            frame.extend_from_slice(&dst);
+
+            let mut pseudo_header = Vec::with_capacity(40);
+            pseudo_header.extend_from_slice(&src);
+            pseudo_header.extend_from_slice(&dst);
+            pseudo_header.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, IP_PROTOCOL_UDP]);
+            pseudo_header
+        }
+    };
+
+    let mut udp_header = [0; UDP_HEADER_SIZE];
+    udp_header[0..2].copy_from_slice(&source.port().to_be_bytes());
+    udp_header[2..4].copy_from_slice(&destination.port().to_be_bytes());
+    udp_header[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    // A checksum of zero means none, so a computed zero is sent as all ones
+    let udp_checksum = match checksum(&[&pseudo_header, &udp_header, &datagram.data]) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp_header[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+    frame.extend_from_slice(&udp_header);
+    frame.extend_from_slice(&datagram.data);
+
+    writer.write_all(&(datagram.time.as_secs() as u32).to_le_bytes())?;
+    writer.write_all(&datagram.time.subsec_micros().to_le_bytes())?;
+    writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+    writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+    writer.write_all(&frame)
+}
+
+fn read_pcap<R: Read>(mut reader: R) -> io::Result<Vec<CapturedDatagram>> {
+    let mut datagrams = Vec::new();
+    loop {
+        let mut header = [0; 16];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(datagrams),
+            Err(e) => return Err(e),
+        }
+        let secs = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let micros = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let len = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if len > SNAPLEN {
+            return Err(invalid_data("frame in the pcap file is longer than the snapshot length"));
+        }
+        let mut frame = vec![0; len as usize];
+        reader.read_exact(&mut frame)?;
+
+        let time = Duration::from_secs(u64::from(secs)) + Duration::from_micros(u64::from(micros));
+        datagrams.push(parse_frame(time, &frame).ok_or_else(|| invalid_data("truncated frame in the pcap file"))?);
+    }
+}
+
+// Undoes `write_pcap`, frames from other tools may have IP options or extension headers we do not handle
+fn parse_frame(time: Duration, frame: &[u8]) -> Option<CapturedDatagram> {
+    let outgoing = u16::from_be_bytes([*frame.first()?, *frame.get(1)?]) == SLL_OUTGOING;
+    let ethertype = u16::from_be_bytes([*frame.get(14)?, *frame.get(15)?]);
+    let ip = frame.get(SLL_HEADER_SIZE..)?;
+
+    let (source, destination, udp) = match ethertype {
+        ETHERTYPE_IPV4 => {
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            (IpAddr::from(src), IpAddr::from(dst), ip.get(IPV4_HEADER_SIZE..)?)
+        }
+        ETHERTYPE_IPV6 => {
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            (IpAddr::from(src), IpAddr::from(dst), ip.get(IPV6_HEADER_SIZE..)?)
+        }
+        _ => return None,
+    };
+
+    let source = SocketAddr::new(source, u16::from_be_bytes([*udp.first()?, *udp.get(1)?]));
+    let destination = SocketAddr::new(destination, u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]));
+    let (local, remote) = if outgoing { (source, destination) } else { (destination, source) };
+
+    Some(CapturedDatagram {
+        time,
+        outgoing,
+        local,
+        remote,
+        data: udp.get(UDP_HEADER_SIZE..)?.to_vec(),
+    })
+}
+
+// The internet checksum over the concatenation of `parts`, all but the last have an even length
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for chunk in part.chunks(2) {
+            let word = if chunk.len() == 2 {
+                u16::from_be_bytes([chunk[0], chunk[1]])
+            } else {
+                u16::from_be_bytes([chunk[0], 0])
+            };
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Feeds a capture back through a `SocketState` of its own, to reproduce the connection state the capturing socket had.
+///
+/// Received datagrams go through `SocketState::process_received`, sent ones update the sequence numbers and the packets waiting for an ack.
+/// Nothing depends on the clock except path MTU discovery, which is left out, and clock sync, so the same capture always ends in the same state otherwise.
+/// Encrypted datagrams can not be replayed, they are counted as malformed.
+pub struct Replay {
+    state: SocketState,
+    compression: Option<Compression>,
+}
+
+impl Replay {
+    pub fn new() -> Replay {
+        Replay {
+            state: SocketState::without_timeout_thread(),
+            compression: None,
+        }
+    }
+
+    /// Decompresses the datagrams with the settings the capturing socket used.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Replays a datagram, returning the packet the application got from it, if any.
+    pub fn feed(&mut self, datagram: &CapturedDatagram) -> Option<Packet> {
+        let raw_packet = match read_raw_packet(datagram.data.clone(), self.compression.as_ref()) {
+            Ok(raw_packet) => raw_packet,
+            Err(_) => {
+                self.state.record_malformed();
+                return None;
+            }
+        };
+
+        let result = if datagram.outgoing {
+            self.state.process_sent(datagram.remote, &raw_packet).map(|_| None)
+        } else {
+            self.state.process_received(datagram.remote, &raw_packet)
+        };
+        match result {
+            Ok(packet) => packet,
+            Err(e) => {
+                error!("Could not replay datagram: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Replays all the datagrams in order, returning the packets the application got.
+    pub fn run(&mut self, datagrams: &[CapturedDatagram]) -> Vec<Packet> {
+        datagrams.iter().filter_map(|datagram| self.feed(datagram)).collect()
+    }
+
+    /// Returns the connection with `addr` as the replay left it.
+    pub fn connection(&self, addr: SocketAddr) -> Option<Arc<RwLock<Connection>>> {
+        self.state.connection(addr)
+    }
+
+    pub fn stats(&self) -> UdpStats {
+        self.state.stats()
+    }
+}
+
+impl Default for Replay {
+    fn default() -> Replay {
+        Replay::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{checksum, Capture, CaptureFormat, Replay, LINKTYPE_LINUX_SLL, NATIVE_MAGIC, PCAP_MAGIC};
+    use net::{Direction, UdpSocket};
+    use packet::Packet;
+    use std::env;
+    use std::fs;
+    use std::io;
+
+    #[test]
+    fn captures_and_replays_a_session() {
+        let pcap_path = env::temp_dir().join("amethyst_capture_12487.pcap");
+        let native_path = env::temp_dir().join("amethyst_capture_12488.cap");
+        let mut server = UdpSocket::bind("127.0.0.1:12487").unwrap();
+        let mut client = UdpSocket::bind("127.0.0.1:12488").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client_addr = client.local_addr().unwrap();
+        server.set_capture(Some(Capture::create(&pcap_path, CaptureFormat::Pcap).unwrap())).unwrap();
+        client.set_capture(Some(Capture::create(&native_path, CaptureFormat::Native).unwrap())).unwrap();
+
+        let mut delivered = Vec::new();
+        for i in 0..5 {
+            client.send(Packet::new(server_addr, vec![i; 20])).unwrap().unwrap();
+            delivered.push(server.recv().unwrap().unwrap());
+            server.send(Packet::new(client_addr, vec![i])).unwrap().unwrap();
+            client.recv().unwrap().unwrap();
+        }
+        // Dropping the captures flushes them
+        server.set_capture(None).unwrap();
+        client.set_capture(None).unwrap();
+
+        let server_capture = Capture::read(&pcap_path).unwrap();
+        let client_capture = Capture::read(&native_path).unwrap();
+        fs::remove_file(&pcap_path).unwrap();
+        fs::remove_file(&native_path).unwrap();
+
+        // Every datagram one side sent, the other side received
+        let sent: Vec<_> = client_capture.iter().filter(|d| d.outgoing).map(|d| d.data.clone()).collect();
+        let received: Vec<_> = server_capture.iter().filter(|d| !d.outgoing).map(|d| d.data.clone()).collect();
+        assert_eq!(sent, received);
+        assert!(server_capture.iter().all(|d| d.local == server_addr && d.remote == client_addr));
+        assert_eq!(server_capture[0].direction(), Direction::Incoming);
+
+        let mut replay = Replay::new();
+        assert_eq!(replay.run(&server_capture), delivered);
+
+        let replayed = replay.connection(client_addr).unwrap();
+        let replayed = replayed.read().unwrap();
+        let stats = replay.stats();
+        assert_eq!(stats.received_packets, server.stats().received_packets);
+        assert_eq!(stats.sent_packets, 5);
+        assert_eq!(stats.malformed_packets, 0);
+        // Both sides number everything they send from zero
+        assert_eq!(replayed.their_acks.last_seq, received.len() as u16 - 1);
+        assert_eq!(replayed.seq_num as usize, server_capture.iter().filter(|d| d.outgoing).count());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = env::temp_dir().join("amethyst_capture_garbage");
+        fs::write(&path, b"not a capture at all").unwrap();
+        assert!(Capture::read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_records_that_are_too_long() {
+        let path = env::temp_dir().join("amethyst_capture_too_long.cap");
+        let mut native = NATIVE_MAGIC.to_vec();
+        native.extend_from_slice(&[0xff; 4]);
+        fs::write(&path, native).unwrap();
+        assert_eq!(Capture::read(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut pcap = PCAP_MAGIC.to_le_bytes().to_vec();
+        pcap.extend_from_slice(&[0; 20]);
+        pcap[20..24].copy_from_slice(&LINKTYPE_LINUX_SLL.to_le_bytes());
+        pcap.extend_from_slice(&[0; 8]);
+        pcap.extend_from_slice(&[0xff; 8]);
+        fs::write(&path, pcap).unwrap();
+        assert_eq!(Capture::read(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn computes_the_internet_checksum() {
+        // An IPv4 header with its checksum zeroed, the checksum is well known
+        let header = [0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7];
+        assert_eq!(checksum(&[&header]), 0xb861);
+        assert_eq!(checksum(&[&header[..10], &header[10..]]), 0xb861);
+    }
+}
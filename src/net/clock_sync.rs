@@ -0,0 +1,241 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// Samples the offset is picked from
+const FILTER_SIZE: usize = 8;
+// Windows of samples the drift is fitted through, one point for every `FILTER_SIZE` samples
+const HISTORY_SIZE: usize = 16;
+// The drift is only estimated once the picked offsets span this long, in microseconds
+const MIN_DRIFT_SPAN: i64 = 1_000_000;
+// Pings go out quickly until the filter is full, then settle down
+const WARMUP_INTERVAL: i64 = 100_000;
+const PING_INTERVAL: i64 = 1_000_000;
+// Pings waiting for an answer, answers to older ones are ignored
+const MAX_OUTSTANDING_PINGS: usize = FILTER_SIZE;
+
+/// Estimates the clock of the other side of a connection from timestamped pings.
+///
+/// Timestamps are microseconds on the clock of the socket that took them. A ping carries the time `t0` it was sent at, the other side answers right away with `t0` and its own time `t1`, and the answer arrives at `t2`.
+/// If the answer took as long as the ping, the other clock is `t1 - (t0 + t2) / 2` ahead of ours.
+/// Queueing delays make the two ways differ, more so the longer the round trip took. So, like the clock filter of NTP, the offset comes from the sample with the shortest round trip of the last eight.
+/// The drift is the slope of a least squares fit through the offset of the best sample of every eight, so it takes about half a minute to show up.
+/// Only answers to pings that were sent and not answered yet count, so the other side can not pick the `t0` of a sample.
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    samples: VecDeque<ClockSample>,
+    // The best sample of every window of samples
+    history: VecDeque<ClockSample>,
+    window: Option<ClockSample>,
+    window_len: usize,
+    next_ping: i64,
+    // Times of the pings sent that were not answered yet
+    outstanding: VecDeque<i64>,
+}
+
+/// A single ping and its answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSample {
+    /// Our time when the answer arrived.
+    pub local_time: i64,
+    /// How far the other clock is ahead of ours, in microseconds.
+    pub offset: i64,
+    /// Round trip time in microseconds.
+    pub rtt: i64,
+}
+
+impl ClockSync {
+    pub fn new() -> ClockSync {
+        ClockSync::default()
+    }
+
+    /// Returns true if a ping should be sent at `now`, and schedules the next one.
+    ///
+    /// The ping must carry `now` as its `t0`, it is the only time an answer is taken for.
+    pub fn ping_due(&mut self, now: i64) -> bool {
+        if now < self.next_ping {
+            return false;
+        }
+        let interval = if self.samples.len() < FILTER_SIZE { WARMUP_INTERVAL } else { PING_INTERVAL };
+        self.next_ping = now + interval;
+        if self.outstanding.len() == MAX_OUTSTANDING_PINGS {
+            self.outstanding.pop_front();
+        }
+        self.outstanding.push_back(now);
+        true
+    }
+
+    /// Adds the sample of a ping sent at `t0`, answered at `t1` on the other clock and received at `t2`.
+    ///
+    /// The sample is ignored unless `t0` is the time of a ping `ping_due` asked for that was not answered yet.
+    pub fn add_sample(&mut self, t0: i64, t1: i64, t2: i64) {
+        match self.outstanding.iter().position(|&sent_at| sent_at == t0) {
+            Some(index) => {
+                self.outstanding.remove(index);
+            }
+            None => return,
+        }
+        if t2 < t0 {
+            return;
+        }
+        let sample = ClockSample {
+            local_time: t2,
+            offset: t1 - (t0 + t2) / 2,
+            rtt: t2 - t0,
+        };
+        if self.samples.len() == FILTER_SIZE {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+
+        // Windows do not overlap, so every point of the fit comes from different samples
+        match self.window {
+            Some(best) if best.rtt <= sample.rtt => {}
+            _ => self.window = Some(sample),
+        }
+        self.window_len += 1;
+        if self.window_len == FILTER_SIZE {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.extend(self.window.take());
+            self.window_len = 0;
+        }
+    }
+
+    /// Returns the sample the estimate comes from, the one with the shortest round trip.
+    pub fn best(&self) -> Option<ClockSample> {
+        self.samples.iter().min_by_key(|sample| sample.rtt).cloned()
+    }
+
+    /// Returns how far the other clock is ahead of ours, in microseconds.
+    pub fn offset(&self) -> Option<i64> {
+        self.best().map(|sample| sample.offset)
+    }
+
+    /// Returns the round trip time of the sample the estimate comes from.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.best().map(|sample| Duration::from_micros(sample.rtt as u64))
+    }
+
+    /// Returns how many microseconds per second the other clock gains on ours, once there is enough history.
+    pub fn drift(&self) -> Option<f64> {
+        let first = self.history.front()?;
+        let last = self.history.back()?;
+        if self.history.len() < 3 || last.local_time - first.local_time < MIN_DRIFT_SPAN {
+            return None;
+        }
+
+        // Relative to the first point, so the sums stay small
+        let n = self.history.len() as f64;
+        let (mut sum_x, mut sum_y, mut sum_xx, mut sum_xy) = (0.0, 0.0, 0.0, 0.0);
+        for sample in &self.history {
+            let x = (sample.local_time - first.local_time) as f64 / 1_000_000.0;
+            let y = (sample.offset - first.offset) as f64;
+            sum_x += x;
+            sum_y += y;
+            sum_xx += x * x;
+            sum_xy += x * y;
+        }
+        let denominator = n * sum_xx - sum_x * sum_x;
+        if denominator == 0.0 {
+            return None;
+        }
+        Some((n * sum_xy - sum_x * sum_y) / denominator)
+    }
+
+    /// Returns the time on the other clock when ours reads `local_time`.
+    pub fn remote_time(&self, local_time: i64) -> Option<i64> {
+        let best = self.best()?;
+        let drift = self.drift().unwrap_or(0.0);
+        let elapsed = (local_time - best.local_time) as f64 / 1_000_000.0;
+        Some(local_time + best.offset + (drift * elapsed) as i64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ClockSync;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // Pings a clock that is `offset` ahead and gains `drift` microseconds per second, over a path of 20ms each way plus up to `jitter` of queueing
+    fn simulate(offset: i64, drift: f64, jitter: i64, seconds: i64) -> ClockSync {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut sync = ClockSync::new();
+        let mut now = 0;
+        while now < seconds * 1_000_000 {
+            if sync.ping_due(now) {
+                let there = now + 20_000 + rng.gen_range(0..=jitter);
+                let back = there + 20_000 + rng.gen_range(0..=jitter);
+                let remote = there + offset + (drift * there as f64 / 1_000_000.0) as i64;
+                sync.add_sample(now, remote, back);
+            }
+            now += 10_000;
+        }
+        sync
+    }
+
+    #[test]
+    fn estimates_the_offset_without_jitter() {
+        let sync = simulate(-123_456, 0.0, 0, 30);
+        assert_eq!(sync.offset(), Some(-123_456));
+        assert_eq!(sync.rtt().unwrap().as_millis(), 40);
+        assert_eq!(sync.drift(), Some(0.0));
+    }
+
+    #[test]
+    fn filters_out_jitter() {
+        let sync = simulate(5_000_000, 0.0, 30_000, 150);
+        let error = (sync.offset().unwrap() - 5_000_000).abs();
+        // A single sample may be off by half the jitter
+        assert!(error < 7_500, "offset is off by {}us", error);
+        let drift = sync.drift().unwrap();
+        assert!(drift.abs() < 100.0, "drift is {}", drift);
+    }
+
+    #[test]
+    fn estimates_the_drift() {
+        let sync = simulate(0, 500.0, 1_000, 60);
+        let drift = sync.drift().unwrap();
+        assert!((drift - 500.0).abs() < 50.0, "drift is {}", drift);
+
+        let expected = 60_000_000 + 500 * 60;
+        let remote_time = sync.remote_time(60_000_000).unwrap();
+        assert!((remote_time - expected).abs() < 1_000, "remote time is off by {}us", remote_time - expected);
+    }
+
+    #[test]
+    fn pings_faster_until_the_filter_is_full() {
+        let mut sync = ClockSync::new();
+        assert!(sync.ping_due(0));
+        assert!(!sync.ping_due(50_000));
+        assert!(sync.ping_due(100_000));
+
+        sync.add_sample(0, 0, 10);
+        sync.add_sample(100_000, 100_000, 100_010);
+        for i in 2..8 {
+            assert!(sync.ping_due(i * 100_000));
+            sync.add_sample(i * 100_000, i * 100_000, i * 100_000 + 10);
+        }
+        assert!(sync.ping_due(800_000));
+        assert!(!sync.ping_due(1_700_000));
+        assert!(sync.ping_due(1_800_000));
+    }
+
+    #[test]
+    fn ignores_answers_to_pings_that_were_not_sent() {
+        let mut sync = ClockSync::new();
+        sync.add_sample(0, 5_000, 100);
+        assert_eq!(sync.best(), None);
+
+        assert!(sync.ping_due(1_000));
+        sync.add_sample(900, 5_000, 1_100);
+        assert_eq!(sync.best(), None);
+        sync.add_sample(1_000, 5_000, 1_100);
+        assert_eq!(sync.offset(), Some(5_000 - 1_050));
+
+        // Every ping is answered once
+        sync.add_sample(1_000, 9_000, 1_200);
+        assert_eq!(sync.offset(), Some(5_000 - 1_050));
+    }
+}
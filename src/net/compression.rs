@@ -0,0 +1,216 @@
+use std::io;
+
+use lz4_flex::block::{compress_into, compress_into_with_dict, decompress_into, decompress_into_with_dict, get_maximum_output_size};
+
+// The first byte of every datagram holds these flags
+const COMPRESSED: u8 = 0b01;
+const DICTIONARY: u8 = 0b10;
+
+// flags + the uncompressed size as a little endian u32
+const HEADER_SIZE: usize = 5;
+
+/// Bytes compression adds to a datagram that does not compress.
+pub const OVERHEAD: usize = 1;
+
+/// Datagrams smaller than this are sent as they are.
+pub const DEFAULT_THRESHOLD: usize = 64;
+/// Datagrams smaller than this are compressed with the preset dictionary, if there is one.
+pub const DEFAULT_DICTIONARY_LIMIT: usize = 512;
+/// Biggest size a received datagram may decompress to.
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 64 * 1024;
+
+/// Compresses the datagrams a `UdpSocket` sends with LZ4.
+///
+/// Every datagram starts with a flags byte that tells if it is compressed, so both sides have to enable compression.
+/// Datagrams below the threshold, and datagrams that do not get smaller, are sent uncompressed.
+///
+/// Small datagrams have little to find repetitions in, so they can be compressed with a preset dictionary instead: sample data that looks like what is sent, for example an old snapshot.
+/// Both sides need the same dictionary.
+///
+/// A socket compresses whole datagrams with `UdpSocket::with_compression`, and the messages of a channel with `UdpSocket::with_channel_compression`, so each channel can have a dictionary of its own.
+///
+/// A compressed datagram states its uncompressed size up front. Datagrams that claim to be bigger than the maximum decompressed size, or that do not decompress to exactly the size they claim, are rejected, so a small datagram can not make us allocate a lot of memory.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    threshold: usize,
+    dictionary: Option<Vec<u8>>,
+    dictionary_limit: usize,
+    max_decompressed_size: usize,
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        Compression {
+            threshold: DEFAULT_THRESHOLD,
+            dictionary: None,
+            dictionary_limit: DEFAULT_DICTIONARY_LIMIT,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        }
+    }
+
+    /// Only compresses datagrams of at least `threshold` bytes.
+    pub fn with_threshold(mut self, threshold: usize) -> Compression {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Compresses datagrams smaller than `limit` bytes with the given preset dictionary.
+    pub fn with_dictionary(mut self, dictionary: Vec<u8>, limit: usize) -> Compression {
+        self.dictionary = Some(dictionary);
+        self.dictionary_limit = limit;
+        self
+    }
+
+    /// Rejects received datagrams that decompress to more than `max` bytes.
+    pub fn with_max_decompressed_size(mut self, max: usize) -> Compression {
+        self.max_decompressed_size = max;
+        self
+    }
+
+    /// Adds the flags byte to a datagram, and compresses it if that is worth it.
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        if data.len() >= self.threshold && data.len() <= u32::MAX as usize {
+            let dictionary = match self.dictionary {
+                Some(ref dictionary) if data.len() < self.dictionary_limit => Some(dictionary),
+                _ => None,
+            };
+
+            let mut compressed = vec![0; HEADER_SIZE + get_maximum_output_size(data.len())];
+            let result = match dictionary {
+                Some(dictionary) => compress_into_with_dict(data, &mut compressed[HEADER_SIZE..], dictionary),
+                None => compress_into(data, &mut compressed[HEADER_SIZE..]),
+            };
+
+            if let Ok(len) = result {
+                if HEADER_SIZE + len < 1 + data.len() {
+                    compressed[0] = if dictionary.is_some() { COMPRESSED | DICTIONARY } else { COMPRESSED };
+                    compressed[1..HEADER_SIZE].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                    compressed.truncate(HEADER_SIZE + len);
+                    return compressed;
+                }
+            }
+        }
+
+        let mut datagram = Vec::with_capacity(1 + data.len());
+        datagram.push(0);
+        datagram.extend_from_slice(data);
+        datagram
+    }
+
+    /// Reads the flags byte of a received datagram and decompresses it if needed.
+    pub fn decompress(&self, datagram: &[u8]) -> io::Result<Vec<u8>> {
+        let flags = match datagram.first() {
+            Some(&flags) => flags,
+            None => return Err(invalid_data("empty datagram")),
+        };
+
+        if flags == 0 {
+            return Ok(datagram[1..].to_vec());
+        }
+        if flags & !(COMPRESSED | DICTIONARY) != 0 || flags & COMPRESSED == 0 || datagram.len() < HEADER_SIZE {
+            return Err(invalid_data("invalid compression header"));
+        }
+
+        let mut size = [0; 4];
+        size.copy_from_slice(&datagram[1..HEADER_SIZE]);
+        let size = u32::from_le_bytes(size) as usize;
+        if size > self.max_decompressed_size {
+            return Err(invalid_data("datagram decompresses to more than the maximum size"));
+        }
+
+        let mut data = vec![0; size];
+        let result = if flags & DICTIONARY != 0 {
+            match self.dictionary {
+                Some(ref dictionary) => decompress_into_with_dict(&datagram[HEADER_SIZE..], &mut data, dictionary),
+                None => return Err(invalid_data("datagram was compressed with a dictionary we do not have")),
+            }
+        } else {
+            decompress_into(&datagram[HEADER_SIZE..], &mut data)
+        };
+
+        match result {
+            Ok(len) if len == size => Ok(data),
+            Ok(_) => Err(invalid_data("datagram did not decompress to the size it claimed")),
+            Err(e) => Err(invalid_data(&e.to_string())),
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Compression, HEADER_SIZE};
+    use std::io;
+
+    fn snapshot(tick: u8) -> Vec<u8> {
+        let mut snapshot = Vec::new();
+        for entity in 0..20_u8 {
+            snapshot.extend_from_slice(&[entity, 0, 0, 0, tick, 1, 0, 0, 0, 0, 128, 63]);
+        }
+        snapshot
+    }
+
+    #[test]
+    fn round_trips_and_skips_small_datagrams() {
+        let compression = Compression::new();
+
+        let small = vec![1, 2, 3];
+        let datagram = compression.compress(&small);
+        assert_eq!(datagram, vec![0, 1, 2, 3]);
+        assert_eq!(compression.decompress(&datagram).unwrap(), small);
+
+        let big = snapshot(1);
+        let datagram = compression.compress(&big);
+        assert_eq!(datagram[0], 1);
+        assert!(datagram.len() < big.len());
+        assert_eq!(compression.decompress(&datagram).unwrap(), big);
+
+        // Data that does not compress is sent as it is
+        let random: Vec<u8> = (0..200_u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        assert_eq!(compression.compress(&random)[0], 0);
+    }
+
+    #[test]
+    fn dictionary_helps_small_datagrams() {
+        let without = Compression::new().with_threshold(0);
+        let with = Compression::new().with_threshold(0).with_dictionary(snapshot(0), 512);
+
+        let message = snapshot(7)[..60].to_vec();
+        let plain = without.compress(&message);
+        let compressed = with.compress(&message);
+        assert_eq!(compressed[0], 3);
+        assert!(compressed.len() < plain.len());
+        assert_eq!(with.decompress(&compressed).unwrap(), message);
+
+        // The other side needs the dictionary as well
+        assert_eq!(without.decompress(&compressed).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_decompression_bombs() {
+        let compression = Compression::new().with_max_decompressed_size(1000);
+
+        // Claims to be bigger than allowed
+        let bomb = Compression::new().compress(&vec![0; 10_000]);
+        assert!(bomb.len() < 100);
+        assert_eq!(compression.decompress(&bomb).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Lies about its size
+        let mut liar = Compression::new().compress(&[0; 900]);
+        liar[1..HEADER_SIZE].copy_from_slice(&100_u32.to_le_bytes());
+        assert!(compression.decompress(&liar).is_err());
+
+        assert!(compression.decompress(&[]).is_err());
+        assert!(compression.decompress(&[4, 0]).is_err());
+        assert!(compression.decompress(&[1, 0]).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bincode::{deserialize, serialize};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use error::{NetworkError, Result};
+
+/// Most server addresses a connect token can hold.
+pub const MAX_SERVER_ADDRESSES: usize = 8;
+/// Biggest private connect data a connect token can hold.
+pub const MAX_USER_DATA_SIZE: usize = 256;
+/// Size of the nonce that identifies a connect token.
+pub const TOKEN_NONCE_SIZE: usize = 24;
+
+// Binds the private part to this format, so data sealed with the same key for something else is never taken for a token
+const TOKEN_VERSION: &[u8] = b"amethyst_protocol connect token 1";
+
+/// A token the backend hands to a client so it can connect to a dedicated server.
+///
+/// The backend and the servers share a secret key. The token has a private part that is encrypted and signed with that key, so the client can not read or change it,
+/// and a public part with the same session keys, so the client can encrypt its traffic with them.
+/// A server only accepts the client if the private part checks out, the token did not expire, the server is in the list of addresses and the token was not used from another address before.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectToken {
+    client_id: u64,
+    server_addresses: Vec<SocketAddr>,
+    expires_at: u64,
+    client_to_server_key: [u8; 32],
+    server_to_client_key: [u8; 32],
+    nonce: [u8; TOKEN_NONCE_SIZE],
+    private: Vec<u8>,
+}
+
+/// A client a server accepted with a connect token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectedClient {
+    /// The id the backend gave the client.
+    pub client_id: u64,
+    /// The private connect data the backend put in the token.
+    pub user_data: Vec<u8>,
+}
+
+// The part of the token only the servers can read
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PrivateConnectToken {
+    pub client_id: u64,
+    pub server_addresses: Vec<SocketAddr>,
+    pub expires_at: u64,
+    pub client_to_server_key: [u8; 32],
+    pub server_to_client_key: [u8; 32],
+    pub user_data: Vec<u8>,
+}
+
+impl ConnectToken {
+    /// Generates a new random key to share between the backend and the servers.
+    pub fn generate_key() -> [u8; 32] {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        key
+    }
+
+    /// Generates a token for `client_id` that is valid on the given servers until `expires_at`, signed with the shared `key`.
+    ///
+    /// `user_data` is only readable by the servers, and can hold up to `MAX_USER_DATA_SIZE` bytes.
+    pub fn generate(
+        key: &[u8; 32],
+        client_id: u64,
+        server_addresses: Vec<SocketAddr>,
+        expires_at: SystemTime,
+        user_data: Vec<u8>,
+    ) -> Result<ConnectToken> {
+        if server_addresses.is_empty() {
+            return Err(NetworkError::InvalidConnectToken.into());
+        }
+        if server_addresses.len() > MAX_SERVER_ADDRESSES || user_data.len() > MAX_USER_DATA_SIZE {
+            return Err(NetworkError::ConnectTokenTooLarge.into());
+        }
+
+        let mut client_to_server_key = [0; 32];
+        let mut server_to_client_key = [0; 32];
+        let mut nonce = [0; TOKEN_NONCE_SIZE];
+        OsRng.fill_bytes(&mut client_to_server_key);
+        OsRng.fill_bytes(&mut server_to_client_key);
+        OsRng.fill_bytes(&mut nonce);
+
+        let private = PrivateConnectToken {
+            client_id,
+            server_addresses: server_addresses.clone(),
+            expires_at: unix_seconds(expires_at),
+            client_to_server_key,
+            server_to_client_key,
+            user_data,
+        };
+        let private = XChaCha20Poly1305::new(Key::from_slice(key))
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &serialize(&private)?,
+                    aad: TOKEN_VERSION,
+                },
+            )
+            .map_err(|_| NetworkError::EncryptionFailed)?;
+
+        Ok(ConnectToken {
+            client_id,
+            server_addresses,
+            expires_at: unix_seconds(expires_at),
+            client_to_server_key,
+            server_to_client_key,
+            nonce,
+            private,
+        })
+    }
+
+    /// Reads a token that was turned into bytes with `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<ConnectToken> {
+        deserialize(bytes).map_err(|_| NetworkError::InvalidConnectToken.into())
+    }
+
+    /// Turns the token into bytes, so the backend can send it to the client.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serialize(self)?)
+    }
+
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    pub fn server_addresses(&self) -> &[SocketAddr] {
+        &self.server_addresses
+    }
+
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.expires_at)
+    }
+
+    pub fn client_to_server_key(&self) -> [u8; 32] {
+        self.client_to_server_key
+    }
+
+    pub fn server_to_client_key(&self) -> [u8; 32] {
+        self.server_to_client_key
+    }
+
+    /// The nonce followed by the private part, which is what the client sends to the server.
+    pub fn request(&self) -> Vec<u8> {
+        let mut request = self.nonce.to_vec();
+        request.extend_from_slice(&self.private);
+        request
+    }
+}
+
+impl PrivateConnectToken {
+    /// Reads the private part from a connection request, returns `None` if it was not signed with `key`.
+    pub fn open(key: &[u8; 32], request: &[u8]) -> Option<([u8; TOKEN_NONCE_SIZE], PrivateConnectToken)> {
+        if request.len() <= TOKEN_NONCE_SIZE {
+            return None;
+        }
+
+        let (nonce, private) = request.split_at(TOKEN_NONCE_SIZE);
+        let plaintext = XChaCha20Poly1305::new(Key::from_slice(key))
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: private, aad: TOKEN_VERSION })
+            .ok()?;
+        let token = deserialize(&plaintext).ok()?;
+
+        let mut id = [0; TOKEN_NONCE_SIZE];
+        id.copy_from_slice(nonce);
+        Some((id, token))
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        unix_seconds(now) >= self.expires_at
+    }
+}
+
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConnectToken, PrivateConnectToken, MAX_USER_DATA_SIZE};
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime};
+
+    fn server_addr() -> SocketAddr {
+        "127.0.0.1:40000".parse().unwrap()
+    }
+
+    fn generate(key: &[u8; 32], expires_in: Duration) -> ConnectToken {
+        ConnectToken::generate(key, 7, vec![server_addr()], SystemTime::now() + expires_in, vec![1, 2, 3]).unwrap()
+    }
+
+    #[test]
+    fn servers_read_the_private_part() {
+        let key = ConnectToken::generate_key();
+        let token = generate(&key, Duration::from_secs(30));
+        let token = ConnectToken::from_bytes(&token.to_bytes().unwrap()).unwrap();
+
+        let (_, private) = PrivateConnectToken::open(&key, &token.request()).unwrap();
+        assert_eq!(private.client_id, 7);
+        assert_eq!(private.server_addresses, vec![server_addr()]);
+        assert_eq!(private.user_data, vec![1, 2, 3]);
+        assert_eq!(private.client_to_server_key, token.client_to_server_key());
+        assert_eq!(private.server_to_client_key, token.server_to_client_key());
+        assert!(!private.is_expired(SystemTime::now()));
+        assert!(private.is_expired(SystemTime::now() + Duration::from_secs(31)));
+    }
+
+    #[test]
+    fn rejects_tokens_with_another_key_or_changes() {
+        let key = ConnectToken::generate_key();
+        let token = generate(&key, Duration::from_secs(30));
+
+        assert!(PrivateConnectToken::open(&ConnectToken::generate_key(), &token.request()).is_none());
+
+        let mut request = token.request();
+        let last = request.len() - 1;
+        request[last] ^= 1;
+        assert!(PrivateConnectToken::open(&key, &request).is_none());
+        assert!(PrivateConnectToken::open(&key, &request[..10]).is_none());
+    }
+
+    #[test]
+    fn checks_token_limits() {
+        let key = ConnectToken::generate_key();
+        let expires_at = SystemTime::now();
+        assert!(ConnectToken::generate(&key, 1, Vec::new(), expires_at, Vec::new()).is_err());
+        assert!(ConnectToken::generate(&key, 1, vec![server_addr()], expires_at, vec![0; MAX_USER_DATA_SIZE + 1]).is_err());
+        assert!(ConnectToken::generate(&key, 1, vec![server_addr(); 9], expires_at, Vec::new()).is_err());
+        assert!(ConnectToken::from_bytes(&[1, 2, 3]).is_err());
+    }
+}
@@ -1,4 +1,5 @@
-use super::{ExternalAcks, LocalAckRecord, Packet};
+use super::{ClockSync, ExternalAcks, LocalAckRecord, Packet, PacketHandle, PathMtu, ReplayBuffer};
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
@@ -8,9 +9,17 @@ use std::time::{Duration, Instant};
 pub struct Connection {
     pub seq_num: u16,
     pub dropped_packets: Vec<Packet>,
+    pub acked_packets: VecDeque<u16>,
+    // Handles of the tracked packets in every sent packet that `DeliveryEvent`s are emitted for
+    pub tracked_packets: HashMap<u16, Vec<PacketHandle>>,
     pub waiting_packets: LocalAckRecord,
     pub their_acks: ExternalAcks,
+    pub received: ReplayBuffer,
+    pub mtu: PathMtu,
+    pub clock: ClockSync,
     pub last_heard: Instant,
+    // Set once a packet from the other side was accepted, which is when `ConnectionEvent::Connected` is emitted
+    pub connected: bool,
     pub remote_address: SocketAddr,
     pub quality: Quality,
 }
@@ -21,9 +30,15 @@ impl Connection {
         Connection {
             seq_num: 0,
             dropped_packets: Vec::new(),
+            acked_packets: VecDeque::new(),
+            tracked_packets: HashMap::new(),
             waiting_packets: LocalAckRecord::new(),
             their_acks: ExternalAcks::new(),
+            received: ReplayBuffer::new(),
+            mtu: PathMtu::new(),
+            clock: ClockSync::new(),
             last_heard: Instant::now(),
+            connected: false,
             quality: Quality::Good,
             remote_address: addr,
         }
@@ -52,6 +67,7 @@ impl fmt::Debug for Connection {
 /// We should use this for handling Congestion Avoidance so that when the network of the client is bad we do not flood the router with small packets.
 ///
 /// When network conditions are `Good` we send 30 packets per-second, and when network conditions are `Bad` we drop to 10 packets per-second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Quality {
     Good,
     Bad,
@@ -62,9 +78,9 @@ mod test {
     use net::connection::Connection;
     use std::net::ToSocketAddrs;
 
-    static TEST_HOST_IP: &'static str = "127.0.0.1";
-    static TEST_BAD_HOST_IP: &'static str = "800.0.0.1";
-    static TEST_PORT: &'static str = "20000";
+    static TEST_HOST_IP: &str = "127.0.0.1";
+    static TEST_BAD_HOST_IP: &str = "800.0.0.1";
+    static TEST_PORT: &str = "20000";
 
     #[test]
     fn test_create_connection() {
@@ -73,4 +89,10 @@ mod test {
             .unwrap();
         let _new_conn = Connection::new(addr.next().unwrap());
     }
+
+    #[test]
+    fn test_invalid_addr_fails() {
+        let addr = format!("{}:{}", TEST_BAD_HOST_IP, TEST_PORT).to_socket_addrs();
+        assert!(addr.is_err());
+    }
 }
@@ -0,0 +1,191 @@
+//! Decodes datagrams for people to read, this is what the `amethyst-protocol-dump` binary prints.
+
+use std::fmt::{self, Write};
+use std::io;
+
+use super::capture::CapturedDatagram;
+use super::compression::Compression;
+use super::message::{self, Channel};
+use super::protocol::read_raw_packet;
+use PacketType;
+
+/// The header and payload of a datagram.
+///
+/// Every datagram carries a single packet, packets are not fragmented. The payload of a packet sent with `UdpSocket::send_message` holds one or more messages, each on its own channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedPacket {
+    pub seq: u16,
+    pub ack_seq: u16,
+    pub ack_field: u32,
+    pub packet_type: PacketType,
+    pub payload: Vec<u8>,
+}
+
+impl DecodedPacket {
+    /// Returns the sequence numbers this packet acknowledges, newest first.
+    pub fn acked(&self) -> Vec<u16> {
+        let mut acked = vec![self.ack_seq];
+        // Bit n acknowledges the packet n + 1 before `ack_seq`
+        for bit in 0..32 {
+            if self.ack_field & (1 << bit) != 0 {
+                acked.push(self.ack_seq.wrapping_sub(bit + 1));
+            }
+        }
+        acked
+    }
+
+    /// Returns the messages in the payload with their channels, if it carries messages.
+    pub fn messages(&self) -> Option<Vec<(Channel, Vec<u8>)>> {
+        message::unpack(&self.payload).ok()
+    }
+}
+
+impl fmt::Display for DecodedPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "seq:     {}", self.seq)?;
+        writeln!(f, "ack_seq: {}", self.ack_seq)?;
+        writeln!(f, "acked:   {:?}", self.acked())?;
+        writeln!(f, "type:    {:?}", self.packet_type)?;
+        writeln!(f, "payload: {} bytes", self.payload.len())?;
+        match self.messages() {
+            Some(messages) => {
+                writeln!(f, "messages: {}", messages.len())?;
+                for (channel, message) in messages {
+                    writeln!(f, "channel {}: {} bytes", channel, message.len())?;
+                    write!(f, "{}", hex_dump(&message))?;
+                }
+                Ok(())
+            }
+            None => write!(f, "{}", hex_dump(&self.payload)),
+        }
+    }
+}
+
+/// Decodes a datagram the way `UdpSocket::recv` does, decompressing it first if `compression` is given.
+///
+/// Encrypted datagrams can not be decoded.
+pub fn decode(data: &[u8], compression: Option<&Compression>) -> io::Result<DecodedPacket> {
+    let raw_packet = read_raw_packet(data.to_vec(), compression)?;
+    Ok(DecodedPacket {
+        seq: raw_packet.seq,
+        ack_seq: raw_packet.ack_seq,
+        ack_field: raw_packet.ack_field,
+        packet_type: raw_packet.packet_type,
+        payload: raw_packet.payload.into_vec(),
+    })
+}
+
+/// Describes a captured datagram: when, which way, and what was in it.
+pub fn dump(datagram: &CapturedDatagram, compression: Option<&Compression>) -> String {
+    let arrow = if datagram.outgoing { "->" } else { "<-" };
+    let mut output = format!(
+        "{}.{:06} {} {} {} ({} bytes)\n",
+        datagram.time.as_secs(),
+        datagram.time.subsec_micros(),
+        datagram.local,
+        arrow,
+        datagram.remote,
+        datagram.data.len()
+    );
+
+    match decode(&datagram.data, compression) {
+        Ok(packet) => {
+            for line in packet.to_string().lines() {
+                let _ = writeln!(output, "  {}", line);
+            }
+        }
+        Err(e) => {
+            let _ = writeln!(output, "  could not decode, it may be encrypted: {}", e);
+            for line in hex_dump(&datagram.data).lines() {
+                let _ = writeln!(output, "  {}", line);
+            }
+        }
+    }
+    output
+}
+
+/// Formats bytes as offset, hex and ASCII columns, 16 bytes per line.
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut output = String::new();
+    for (line, chunk) in data.chunks(16).enumerate() {
+        let _ = write!(output, "{:04x} ", line * 16);
+        for i in 0..16 {
+            match chunk.get(i) {
+                Some(byte) => {
+                    let _ = write!(output, " {:02x}", byte);
+                }
+                None => output.push_str("   "),
+            }
+        }
+        output.push_str("  |");
+        output.extend(chunk.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }));
+        output.push_str("|\n");
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, dump, hex_dump};
+    use bincode::serialize;
+    use net::message;
+    use net::{CapturedDatagram, Compression};
+    use packet::{Packet, PacketType, RawPacket};
+    use std::time::Duration;
+
+    #[test]
+    fn decodes_the_header() {
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let raw_packet = RawPacket::new(40, &Packet::new(addr, b"hello".to_vec()), 7, 0b101);
+        let compression = Compression::new().with_threshold(0);
+        let data = compression.compress(&serialize(&raw_packet).unwrap());
+
+        let packet = decode(&data, Some(&compression)).unwrap();
+        assert_eq!(packet.seq, 40);
+        assert_eq!(packet.packet_type, PacketType::Data);
+        assert_eq!(packet.payload, b"hello");
+        assert_eq!(packet.acked(), vec![7, 6, 4]);
+        assert!(decode(&data, None).is_err());
+    }
+
+    #[test]
+    fn dumps_captured_datagrams() {
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let raw_packet = RawPacket::new(0, &Packet::new(addr, b"hi".to_vec()), 65535, 1);
+        let datagram = CapturedDatagram {
+            time: Duration::from_micros(1_500_000),
+            outgoing: true,
+            local: "127.0.0.1:1000".parse().unwrap(),
+            remote: addr,
+            data: serialize(&raw_packet).unwrap(),
+        };
+
+        let text = dump(&datagram, None);
+        assert!(text.starts_with("1.500000 127.0.0.1:1000 -> 127.0.0.1:12345 (19 bytes)\n"));
+        assert!(text.contains("  acked:   [65535, 65534]\n"));
+        assert!(text.ends_with("  0000  68 69                                            |hi|\n"));
+
+        let messages = message::pack(&[(3, b"abc".to_vec()), (9, b"de".to_vec())], 100).unwrap().remove(0);
+        let raw_packet = RawPacket::new(1, &Packet::new(addr, messages), 0, 0);
+        let packed = CapturedDatagram {
+            data: serialize(&raw_packet).unwrap(),
+            ..datagram.clone()
+        };
+        let text = dump(&packed, None);
+        assert!(text.contains("  messages: 2\n  channel 3: 3 bytes\n  0000  61 62 63"));
+        assert!(text.contains("  channel 9: 2 bytes\n  0000  64 65"));
+
+        let garbage = CapturedDatagram { data: vec![1, 2, 3], ..datagram };
+        assert!(dump(&garbage, None).contains("could not decode"));
+    }
+
+    #[test]
+    fn hex_dumps_full_and_partial_lines() {
+        let data: Vec<u8> = (0x41..0x53).collect();
+        assert_eq!(
+            hex_dump(&data),
+            "0000  41 42 43 44 45 46 47 48 49 4a 4b 4c 4d 4e 4f 50  |ABCDEFGHIJKLMNOP|\n\
+             0010  51 52                                            |QR|\n"
+        );
+    }
+}
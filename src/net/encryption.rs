@@ -0,0 +1,893 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+use super::connect_token::{unix_seconds, ConnectToken, ConnectedClient, PrivateConnectToken, TOKEN_NONCE_SIZE};
+use error::{NetworkError, Result};
+
+// The first byte of every datagram tells what kind of datagram it is
+const HANDSHAKE_INIT: u8 = 1;
+const HANDSHAKE_RESPONSE: u8 = 2;
+const ENCRYPTED: u8 = 3;
+const TOKEN_REQUEST: u8 = 4;
+const TOKEN_ACCEPTED: u8 = 5;
+
+const PUBLIC_KEY_SIZE: usize = 32;
+// kind + 64 bit sequence number
+const HEADER_SIZE: usize = 9;
+const TAG_SIZE: usize = 16;
+
+/// Bytes encryption adds to every datagram.
+pub const OVERHEAD: usize = HEADER_SIZE + TAG_SIZE;
+
+// How long the initiator waits for a handshake response before sending the request again
+const HANDSHAKE_RESEND_INTERVAL_MS: u64 = 250;
+// Packets queued per address while the handshake is running, the rest is dropped
+const MAX_PENDING_PACKETS: usize = 256;
+// How many sequence numbers behind the newest one we still accept
+const REPLAY_WINDOW_SIZE: u64 = 128;
+// Sessions nothing was sent or received with for this long are dropped, the same as the default connection timeout
+const SESSION_TIMEOUT_SECS: u64 = 10;
+// Time between checks of all sessions for the timeout
+const EXPIRY_INTERVAL_MS: u64 = 1000;
+
+/// Encrypts and authenticates everything a `UdpSocket` sends with ChaCha20-Poly1305.
+///
+/// Before the first packet is sent to an address both sides run a handshake: each side sends an ephemeral X25519 public key, and both derive one key per direction from the shared secret.
+/// Packets that are sent while the handshake is running are queued and go out once it is done.
+///
+/// Every encrypted datagram carries a 64 bit sequence number that is used as the nonce and covered by the authentication tag.
+/// We do not use the 16 bit `RawPacket` sequence number for this, because it wraps around and a nonce must never be used twice with the same key.
+/// Sequence numbers that were already received, or that are too old to tell, are rejected so datagrams can not be replayed.
+///
+/// The key exchange itself is not authenticated, so it protects against eavesdropping and forged packets, but not against someone who can intercept and change the handshake.
+/// Connect tokens fix this: a client with a `ConnectToken` sends its private part instead, and both sides use the session keys from the token.
+/// A server with a token key only accepts clients with a valid token.
+/// Since the keys are fixed, a token is good for one session only: once it ended, the client needs a new token to connect again.
+///
+/// Sessions nothing was sent or received with for 10 seconds are dropped. A handshake request for an address that still has a live session is rejected, so whoever can spoof the address can not replace the session; a peer that restarted has to wait for it to expire.
+pub struct Encryption {
+    sessions: HashMap<SocketAddr, Session>,
+    token: Option<ConnectToken>,
+    // Set once a session was established with the token, its keys must not be used for another one
+    token_used: bool,
+    token_server: Option<TokenServer>,
+    last_expiry: Option<Instant>,
+    // Addresses that started a new session with us since the last `take_new_peers`
+    new_peers: Vec<SocketAddr>,
+}
+
+/// What the socket should do with a datagram it received.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Opened {
+    /// The decrypted payload of a packet.
+    Payload(Vec<u8>),
+    /// A handshake datagram, these datagrams have to be sent back to the address it came from.
+    Send(Vec<Vec<u8>>),
+    /// The datagram is malformed, forged, replayed or unexpected and has to be ignored.
+    Rejected(&'static str),
+}
+
+enum Session {
+    Initiating(Initiator),
+    Established(Established),
+}
+
+struct Initiator {
+    handshake: Handshake,
+    sent_at: Instant,
+    pending: Vec<Vec<u8>>,
+}
+
+enum Handshake {
+    KeyExchange { secret: StaticSecret, public: PublicKey },
+    // The session keys are known from the token, we only wait for the server to accept it
+    Token { request: Vec<u8>, session: Established },
+}
+
+struct Established {
+    send_cipher: ChaCha20Poly1305,
+    recv_cipher: ChaCha20Poly1305,
+    send_seq: u64,
+    replay: ReplayWindow,
+    origin: Origin,
+    last_active: Instant,
+}
+
+// How the session was established, used to answer resent handshake requests
+enum Origin {
+    Initiator { public: [u8; PUBLIC_KEY_SIZE] },
+    Responder { initiator_public: [u8; PUBLIC_KEY_SIZE], response: Vec<u8> },
+    TokenClient,
+    TokenServer { id: [u8; TOKEN_NONCE_SIZE], client: ConnectedClient },
+}
+
+struct TokenServer {
+    key: [u8; 32],
+    public_addr: SocketAddr,
+    // Tokens that were used and did not expire yet, with their expiry time
+    used_tokens: HashMap<[u8; TOKEN_NONCE_SIZE], u64>,
+}
+
+impl Encryption {
+    pub fn new() -> Encryption {
+        Encryption {
+            sessions: HashMap::new(),
+            token: None,
+            token_used: false,
+            token_server: None,
+            last_expiry: None,
+            new_peers: Vec::new(),
+        }
+    }
+
+    /// Connects to the servers in the token with the token instead of a key exchange.
+    pub fn with_connect_token(mut self, token: ConnectToken) -> Encryption {
+        self.token = Some(token);
+        self
+    }
+
+    /// Only accepts clients with a connect token signed with `key` that lists `public_addr` as one of its servers.
+    pub fn with_token_key(mut self, key: [u8; 32], public_addr: SocketAddr) -> Encryption {
+        self.token_server = Some(TokenServer {
+            key,
+            public_addr,
+            used_tokens: HashMap::new(),
+        });
+        self
+    }
+
+    /// Encrypts a payload for `addr` and returns the datagrams that should be sent to it.
+    ///
+    /// While the handshake with `addr` is running the payload is queued, and the handshake request is (re)sent instead.
+    pub fn seal(&mut self, addr: SocketAddr, payload: Vec<u8>, now: Instant) -> Result<Vec<Vec<u8>>> {
+        self.expire(now);
+        match self.sessions.get_mut(&addr) {
+            Some(&mut Session::Established(ref mut session)) => {
+                session.last_active = now;
+                return Ok(vec![session.encrypt(ENCRYPTED, &payload)?]);
+            }
+            Some(&mut Session::Initiating(ref mut initiator)) => {
+                if initiator.pending.len() < MAX_PENDING_PACKETS {
+                    initiator.pending.push(payload);
+                } else {
+                    warn!("Dropping packet for {}, too many packets are waiting for the handshake", addr);
+                }
+
+                if now.duration_since(initiator.sent_at) >= Duration::from_millis(HANDSHAKE_RESEND_INTERVAL_MS) {
+                    initiator.sent_at = now;
+                    return Ok(vec![initiator.handshake.request()]);
+                }
+                return Ok(Vec::new());
+            }
+            None => {}
+        }
+
+        // Servers that require tokens wait for clients to connect
+        self.check_can_send(addr)?;
+
+        let handshake = match self.token {
+            Some(ref token) if token.server_addresses().contains(&addr) => {
+                if self.token_used {
+                    return Err(NetworkError::ConnectTokenUsed.into());
+                }
+                let mut request = vec![TOKEN_REQUEST];
+                request.extend(token.request());
+                Handshake::Token {
+                    request,
+                    session: Established::new(token.client_to_server_key(), token.server_to_client_key(), Origin::TokenClient, now),
+                }
+            }
+            _ => {
+                let secret = StaticSecret::random_from_rng(OsRng);
+                let public = PublicKey::from(&secret);
+                Handshake::KeyExchange { secret, public }
+            }
+        };
+
+        let datagram = handshake.request();
+        self.sessions.insert(
+            addr,
+            Session::Initiating(Initiator {
+                handshake,
+                sent_at: now,
+                pending: vec![payload],
+            }),
+        );
+        Ok(vec![datagram])
+    }
+
+    /// Returns an error if nothing can be sent to `addr`, because this is a server that requires connect tokens and no client connected from there.
+    pub fn check_can_send(&self, addr: SocketAddr) -> Result<()> {
+        if self.token_server.is_some() && !self.sessions.contains_key(&addr) {
+            return Err(NetworkError::ClientNotConnected.into());
+        }
+        Ok(())
+    }
+
+    /// Returns the addresses that started a new session with us since the last call. Their sequence numbers start over.
+    pub fn take_new_peers(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.new_peers)
+    }
+
+    /// Handles a datagram received from `addr`.
+    pub fn open(&mut self, addr: SocketAddr, datagram: &[u8], now: Instant) -> Opened {
+        self.expire(now);
+        match datagram.first() {
+            Some(&HANDSHAKE_INIT) if self.token_server.is_some() => Opened::Rejected("a connect token is required"),
+            Some(&HANDSHAKE_INIT) if datagram.len() == 1 + PUBLIC_KEY_SIZE => self.on_init(addr, public_key(&datagram[1..]), now),
+            Some(&HANDSHAKE_RESPONSE) if datagram.len() == 1 + PUBLIC_KEY_SIZE => self.on_response(addr, public_key(&datagram[1..]), now),
+            Some(&TOKEN_REQUEST) => self.on_token_request(addr, &datagram[1..], SystemTime::now(), now),
+            Some(&TOKEN_ACCEPTED) if datagram.len() == HEADER_SIZE + TAG_SIZE => self.on_token_accepted(addr, datagram, now),
+            Some(&ENCRYPTED) if datagram.len() >= HEADER_SIZE + TAG_SIZE => match self.sessions.get_mut(&addr) {
+                Some(&mut Session::Established(ref mut session)) => {
+                    let opened = session.decrypt(datagram);
+                    // Only authenticated datagrams keep the session alive
+                    if let Opened::Payload(_) = opened {
+                        session.last_active = now;
+                    }
+                    opened
+                }
+                _ => Opened::Rejected("no session has been established with the sender"),
+            },
+            _ => Opened::Rejected("malformed datagram"),
+        }
+    }
+
+    /// Returns the client that connected from `addr` with a connect token.
+    pub fn connected_client(&self, addr: SocketAddr) -> Option<&ConnectedClient> {
+        match self.sessions.get(&addr) {
+            Some(&Session::Established(Established {
+                origin: Origin::TokenServer { ref client, .. },
+                ..
+            })) => Some(client),
+            _ => None,
+        }
+    }
+
+    /// Returns true if a session has been established with `addr`.
+    #[cfg(test)]
+    pub fn is_established(&self, addr: SocketAddr) -> bool {
+        matches!(self.sessions.get(&addr), Some(&Session::Established(_)))
+    }
+
+    // We are the responder: answer with our own public key and derive the keys
+    fn on_init(&mut self, addr: SocketAddr, their_public: PublicKey, now: Instant) -> Opened {
+        match self.sessions.get(&addr) {
+            Some(Session::Established(session)) => match session.origin {
+                // The initiator did not get our response
+                Origin::Responder {
+                    ref initiator_public,
+                    ref response,
+                } if initiator_public == their_public.as_bytes() => return Opened::Send(vec![response.clone()]),
+                Origin::Initiator { ref public } if public == their_public.as_bytes() => {
+                    return Opened::Rejected("handshake request for a session we initiated");
+                }
+                // The request is not authenticated, so it may only replace a session that expired
+                _ if !session.is_expired(now) => return Opened::Rejected("handshake request for an established session"),
+                _ => {}
+            },
+            // Both sides started a handshake at the same time, the side with the lower public key stays the initiator
+            Some(Session::Initiating(Initiator {
+                handshake: Handshake::KeyExchange { ref public, .. },
+                ..
+            })) if public.as_bytes() < their_public.as_bytes() => {
+                return Opened::Rejected("simultaneous handshake, we stay the initiator");
+            }
+            _ => {}
+        }
+
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(&their_public);
+        if !shared.was_contributory() {
+            return Opened::Rejected("invalid public key in handshake request");
+        }
+
+        let (initiator_key, responder_key) = derive_keys(&shared, &their_public, &public);
+        let response = handshake_datagram(HANDSHAKE_RESPONSE, &public);
+        let origin = Origin::Responder {
+            initiator_public: *their_public.as_bytes(),
+            response: response.clone(),
+        };
+        let session = Established::new(responder_key, initiator_key, origin, now);
+
+        // Packets queued for a handshake we gave up on are sent with the new session
+        let pending = match self.sessions.remove(&addr) {
+            Some(Session::Initiating(initiator)) => initiator.pending,
+            _ => {
+                self.new_peers.push(addr);
+                Vec::new()
+            }
+        };
+
+        self.establish(addr, session, vec![response], pending)
+    }
+
+    // We are the initiator: derive the keys and send everything that was waiting for the handshake
+    fn on_response(&mut self, addr: SocketAddr, their_public: PublicKey, now: Instant) -> Opened {
+        let (secret, public, pending) = match self.sessions.remove(&addr) {
+            Some(Session::Initiating(Initiator {
+                handshake: Handshake::KeyExchange { secret, public },
+                pending,
+                ..
+            })) => (secret, public, pending),
+            Some(session) => {
+                self.sessions.insert(addr, session);
+                return Opened::Rejected("unexpected handshake response");
+            }
+            None => return Opened::Rejected("unexpected handshake response"),
+        };
+
+        let shared = secret.diffie_hellman(&their_public);
+        if !shared.was_contributory() {
+            let initiator = Initiator {
+                handshake: Handshake::KeyExchange { secret, public },
+                sent_at: now,
+                pending,
+            };
+            self.sessions.insert(addr, Session::Initiating(initiator));
+            return Opened::Rejected("invalid public key in handshake response");
+        }
+
+        let (initiator_key, responder_key) = derive_keys(&shared, &public, &their_public);
+        let session = Established::new(initiator_key, responder_key, Origin::Initiator { public: *public.as_bytes() }, now);
+        self.establish(addr, session, Vec::new(), pending)
+    }
+
+    // We are a server that requires connect tokens: check the token and take the session keys from it
+    fn on_token_request(&mut self, addr: SocketAddr, request: &[u8], now: SystemTime, instant: Instant) -> Opened {
+        let server = match self.token_server {
+            Some(ref mut server) => server,
+            None => return Opened::Rejected("connect tokens are not accepted"),
+        };
+
+        let (id, token) = match PrivateConnectToken::open(&server.key, request) {
+            Some(token) => token,
+            None => return Opened::Rejected("invalid connect token"),
+        };
+        if token.is_expired(now) {
+            return Opened::Rejected("expired connect token");
+        }
+        if !token.server_addresses.contains(&server.public_addr) {
+            return Opened::Rejected("connect token is for another server");
+        }
+
+        let now = unix_seconds(now);
+        server.used_tokens.retain(|_, &mut expires_at| expires_at > now);
+
+        // The client did not get our answer, accept it again
+        if let Some(&mut Session::Established(ref mut session)) = self.sessions.get_mut(&addr) {
+            if let Origin::TokenServer { id: ref session_id, .. } = session.origin {
+                if *session_id == id {
+                    return match session.encrypt(TOKEN_ACCEPTED, &[]) {
+                        Ok(accepted) => Opened::Send(vec![accepted]),
+                        Err(_) => Opened::Rejected("could not accept the connect token"),
+                    };
+                }
+            }
+        }
+
+        // A new session would start over with the same keys and sequence numbers, reusing the nonces of the last one
+        if server.used_tokens.contains_key(&id) {
+            return Opened::Rejected("connect token was already used");
+        }
+        server.used_tokens.insert(id, token.expires_at);
+        self.new_peers.push(addr);
+        let client = ConnectedClient {
+            client_id: token.client_id,
+            user_data: token.user_data,
+        };
+        let mut session = Established::new(token.server_to_client_key, token.client_to_server_key, Origin::TokenServer { id, client }, instant);
+        match session.encrypt(TOKEN_ACCEPTED, &[]) {
+            Ok(accepted) => {
+                self.sessions.insert(addr, Session::Established(session));
+                Opened::Send(vec![accepted])
+            }
+            Err(_) => Opened::Rejected("could not accept the connect token"),
+        }
+    }
+
+    // We are a client with a connect token and the server accepted it
+    fn on_token_accepted(&mut self, addr: SocketAddr, datagram: &[u8], now: Instant) -> Opened {
+        let accepted = match self.sessions.get_mut(&addr) {
+            Some(&mut Session::Initiating(Initiator {
+                handshake: Handshake::Token { ref mut session, .. },
+                ..
+            })) => session.decrypt(datagram) == Opened::Payload(Vec::new()),
+            _ => return Opened::Rejected("unexpected connect token response"),
+        };
+        if !accepted {
+            return Opened::Rejected("connect token response failed authentication");
+        }
+
+        match self.sessions.remove(&addr) {
+            Some(Session::Initiating(Initiator {
+                handshake: Handshake::Token { mut session, .. },
+                pending,
+                ..
+            })) => {
+                session.last_active = now;
+                self.token_used = true;
+                self.establish(addr, session, Vec::new(), pending)
+            }
+            _ => Opened::Rejected("unexpected connect token response"),
+        }
+    }
+
+    // Stores the session and sends the packets that waited for it after the handshake datagrams
+    fn establish(&mut self, addr: SocketAddr, mut session: Established, mut datagrams: Vec<Vec<u8>>, pending: Vec<Vec<u8>>) -> Opened {
+        for payload in pending {
+            match session.encrypt(ENCRYPTED, &payload) {
+                Ok(datagram) => datagrams.push(datagram),
+                Err(e) => error!("Dropping packet for {}: {}", addr, e),
+            }
+        }
+
+        self.sessions.insert(addr, Session::Established(session));
+        Opened::Send(datagrams)
+    }
+
+    // Drops the sessions nothing was sent or received with for the session timeout
+    fn expire(&mut self, now: Instant) {
+        if let Some(last) = self.last_expiry {
+            if now.saturating_duration_since(last) < Duration::from_millis(EXPIRY_INTERVAL_MS) {
+                return;
+            }
+        }
+        self.last_expiry = Some(now);
+        self.sessions.retain(|addr, session| {
+            let expired = session.is_expired(now);
+            if expired {
+                debug!("Session with {} expired", addr);
+            }
+            !expired
+        });
+    }
+}
+
+impl Session {
+    fn is_expired(&self, now: Instant) -> bool {
+        match *self {
+            Session::Initiating(ref initiator) => is_idle(initiator.sent_at, now),
+            Session::Established(ref session) => session.is_expired(now),
+        }
+    }
+}
+
+impl Default for Encryption {
+    fn default() -> Encryption {
+        Encryption::new()
+    }
+}
+
+impl Handshake {
+    fn request(&self) -> Vec<u8> {
+        match *self {
+            Handshake::KeyExchange { ref public, .. } => handshake_datagram(HANDSHAKE_INIT, public),
+            Handshake::Token { ref request, .. } => request.clone(),
+        }
+    }
+}
+
+impl Established {
+    fn new(send_key: [u8; 32], recv_key: [u8; 32], origin: Origin, now: Instant) -> Established {
+        Established {
+            send_cipher: ChaCha20Poly1305::new(Key::from_slice(&send_key)),
+            recv_cipher: ChaCha20Poly1305::new(Key::from_slice(&recv_key)),
+            send_seq: 0,
+            replay: ReplayWindow::new(),
+            origin,
+            last_active: now,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        is_idle(self.last_active, now)
+    }
+
+    // The kind is part of the authenticated header, so one kind of datagram can not be passed off as another
+    fn encrypt(&mut self, kind: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        let seq = self.send_seq;
+        self.send_seq += 1;
+
+        let header = header(kind, seq);
+        let ciphertext = self
+            .send_cipher
+            .encrypt(&nonce(seq), Payload { msg: payload, aad: &header })
+            .map_err(|_| NetworkError::EncryptionFailed)?;
+
+        let mut datagram = header.to_vec();
+        datagram.extend(ciphertext);
+        Ok(datagram)
+    }
+
+    fn decrypt(&mut self, datagram: &[u8]) -> Opened {
+        let mut seq_bytes = [0; 8];
+        seq_bytes.copy_from_slice(&datagram[1..HEADER_SIZE]);
+        let seq = u64::from_le_bytes(seq_bytes);
+
+        if !self.replay.is_fresh(seq) {
+            return Opened::Rejected("replayed datagram");
+        }
+
+        let payload = Payload {
+            msg: &datagram[HEADER_SIZE..],
+            aad: &datagram[..HEADER_SIZE],
+        };
+        match self.recv_cipher.decrypt(&nonce(seq), payload) {
+            Ok(plaintext) => {
+                // Only authenticated datagrams may move the replay window
+                self.replay.mark(seq);
+                Opened::Payload(plaintext)
+            }
+            Err(_) => Opened::Rejected("datagram failed authentication"),
+        }
+    }
+}
+
+/// Remembers which of the last `REPLAY_WINDOW_SIZE` sequence numbers were received.
+#[derive(Debug)]
+struct ReplayWindow {
+    highest: u64,
+    // bit `n` is set if `highest - n` was received
+    received: u128,
+    initialized: bool,
+}
+
+impl ReplayWindow {
+    fn new() -> ReplayWindow {
+        ReplayWindow {
+            highest: 0,
+            received: 0,
+            initialized: false,
+        }
+    }
+
+    fn is_fresh(&self, seq: u64) -> bool {
+        if !self.initialized || seq > self.highest {
+            return true;
+        }
+
+        let age = self.highest - seq;
+        age < REPLAY_WINDOW_SIZE && self.received & (1 << age) == 0
+    }
+
+    fn mark(&mut self, seq: u64) {
+        if !self.initialized {
+            self.initialized = true;
+            self.highest = seq;
+            self.received = 1;
+        } else if seq > self.highest {
+            let shift = seq - self.highest;
+            self.received = if shift >= REPLAY_WINDOW_SIZE { 0 } else { self.received << shift };
+            self.received |= 1;
+            self.highest = seq;
+        } else {
+            self.received |= 1 << (self.highest - seq);
+        }
+    }
+}
+
+fn is_idle(last_active: Instant, now: Instant) -> bool {
+    now.saturating_duration_since(last_active) >= Duration::from_secs(SESSION_TIMEOUT_SECS)
+}
+
+fn handshake_datagram(kind: u8, public: &PublicKey) -> Vec<u8> {
+    let mut datagram = vec![kind];
+    datagram.extend_from_slice(public.as_bytes());
+    datagram
+}
+
+fn public_key(bytes: &[u8]) -> PublicKey {
+    let mut key = [0; PUBLIC_KEY_SIZE];
+    key.copy_from_slice(bytes);
+    PublicKey::from(key)
+}
+
+fn header(kind: u8, seq: u64) -> [u8; HEADER_SIZE] {
+    let mut header = [kind; HEADER_SIZE];
+    header[1..].copy_from_slice(&seq.to_le_bytes());
+    header
+}
+
+fn nonce(seq: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&seq.to_le_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+// Derives one key per direction, bound to both public keys
+fn derive_keys(shared: &SharedSecret, initiator: &PublicKey, responder: &PublicKey) -> ([u8; 32], [u8; 32]) {
+    let mut salt = initiator.as_bytes().to_vec();
+    salt.extend_from_slice(responder.as_bytes());
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+
+    let mut initiator_key = [0; 32];
+    let mut responder_key = [0; 32];
+    // Both outputs are 32 bytes, far below the HKDF limit
+    hkdf.expand(b"amethyst_protocol initiator", &mut initiator_key).expect("valid HKDF output length");
+    hkdf.expand(b"amethyst_protocol responder", &mut responder_key).expect("valid HKDF output length");
+    (initiator_key, responder_key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Encryption, Opened, ReplayWindow, HANDSHAKE_RESEND_INTERVAL_MS, SESSION_TIMEOUT_SECS};
+    use net::ConnectToken;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant, SystemTime};
+
+    fn client_addr() -> SocketAddr {
+        "127.0.0.1:1000".parse().unwrap()
+    }
+
+    fn server_addr() -> SocketAddr {
+        "127.0.0.1:2000".parse().unwrap()
+    }
+
+    fn sent(opened: Opened) -> Vec<Vec<u8>> {
+        match opened {
+            Opened::Send(datagrams) => datagrams,
+            other => panic!("expected datagrams to send, got {:?}", other),
+        }
+    }
+
+    // Runs the handshake and returns the first packet the client queued, encrypted
+    fn handshake(client: &mut Encryption, server: &mut Encryption) -> Vec<u8> {
+        let init = client.seal(server_addr(), b"hello".to_vec(), Instant::now()).unwrap();
+        assert_eq!(init.len(), 1);
+
+        let response = sent(server.open(client_addr(), &init[0], Instant::now()));
+        assert_eq!(response.len(), 1);
+
+        let mut flushed = sent(client.open(server_addr(), &response[0], Instant::now()));
+        assert_eq!(flushed.len(), 1);
+        assert!(client.is_established(server_addr()));
+        assert!(server.is_established(client_addr()));
+        assert_eq!(server.take_new_peers(), vec![client_addr()]);
+        assert!(client.take_new_peers().is_empty());
+        flushed.remove(0)
+    }
+
+    #[test]
+    fn handshake_then_exchange_packets() {
+        let mut client = Encryption::new();
+        let mut server = Encryption::new();
+
+        let first = handshake(&mut client, &mut server);
+        assert_eq!(server.open(client_addr(), &first, Instant::now()), Opened::Payload(b"hello".to_vec()));
+
+        let reply = server.seal(client_addr(), b"world".to_vec(), Instant::now()).unwrap();
+        assert_eq!(client.open(server_addr(), &reply[0], Instant::now()), Opened::Payload(b"world".to_vec()));
+        assert!(!reply[0].windows(5).any(|w| w == b"world"));
+    }
+
+    #[test]
+    fn rejects_tampered_and_replayed_packets() {
+        let mut client = Encryption::new();
+        let mut server = Encryption::new();
+        let first = handshake(&mut client, &mut server);
+
+        let mut tampered = first.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert_eq!(server.open(client_addr(), &tampered, Instant::now()), Opened::Rejected("datagram failed authentication"));
+
+        // Changing the sequence number breaks the authentication as well
+        let mut renumbered = first.clone();
+        renumbered[1] = 5;
+        assert_eq!(server.open(client_addr(), &renumbered, Instant::now()), Opened::Rejected("datagram failed authentication"));
+
+        // A packet from the server can not be reflected back to it
+        let reply = server.seal(client_addr(), b"world".to_vec(), Instant::now()).unwrap();
+        assert_eq!(server.open(client_addr(), &reply[0], Instant::now()), Opened::Rejected("datagram failed authentication"));
+
+        assert_eq!(server.open(client_addr(), &first, Instant::now()), Opened::Payload(b"hello".to_vec()));
+        assert_eq!(server.open(client_addr(), &first, Instant::now()), Opened::Rejected("replayed datagram"));
+    }
+
+    #[test]
+    fn queues_packets_and_resends_handshake() {
+        let mut client = Encryption::new();
+        let mut server = Encryption::new();
+        let now = Instant::now();
+
+        let init = client.seal(server_addr(), vec![1], now).unwrap();
+        assert!(client.seal(server_addr(), vec![2], now).unwrap().is_empty());
+        let resent = client
+            .seal(server_addr(), vec![3], now + Duration::from_millis(HANDSHAKE_RESEND_INTERVAL_MS))
+            .unwrap();
+        assert_eq!(resent, init);
+
+        // The first response got lost, the server answers the resent request the same way
+        let response = sent(server.open(client_addr(), &init[0], Instant::now()));
+        assert_eq!(sent(server.open(client_addr(), &resent[0], Instant::now())), response);
+
+        let flushed = sent(client.open(server_addr(), &response[0], Instant::now()));
+        let payloads: Vec<Opened> = flushed.iter().map(|d| server.open(client_addr(), d, Instant::now())).collect();
+        assert_eq!(payloads, vec![Opened::Payload(vec![1]), Opened::Payload(vec![2]), Opened::Payload(vec![3])]);
+    }
+
+    #[test]
+    fn resolves_simultaneous_handshakes() {
+        let mut a = Encryption::new();
+        let mut b = Encryption::new();
+
+        let init_a = a.seal(server_addr(), vec![1], Instant::now()).unwrap();
+        let init_b = b.seal(client_addr(), vec![2], Instant::now()).unwrap();
+
+        // Exactly one side becomes the responder, `a` is at the client address and `b` at the server address
+        let from_a = a.open(server_addr(), &init_b[0], Instant::now());
+        let from_b = b.open(client_addr(), &init_a[0], Instant::now());
+        let (responder_datagrams, initiator, initiator_addr, responder, responder_addr) = match (from_a, from_b) {
+            (Opened::Send(datagrams), Opened::Rejected(_)) => (datagrams, &mut b, client_addr(), &mut a, server_addr()),
+            (Opened::Rejected(_), Opened::Send(datagrams)) => (datagrams, &mut a, server_addr(), &mut b, client_addr()),
+            other => panic!("unexpected result {:?}", other),
+        };
+
+        // The responder sends its response and the packet it had queued
+        assert_eq!(responder_datagrams.len(), 2);
+        let flushed = sent(initiator.open(initiator_addr, &responder_datagrams[0], Instant::now()));
+        assert!(initiator.is_established(initiator_addr));
+
+        match initiator.open(initiator_addr, &responder_datagrams[1], Instant::now()) {
+            Opened::Payload(_) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match responder.open(responder_addr, &flushed[0], Instant::now()) {
+            Opened::Payload(_) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn only_rekeys_expired_sessions() {
+        let mut client = Encryption::new();
+        let mut server = Encryption::new();
+        handshake(&mut client, &mut server);
+
+        // Someone spoofing the client address can not replace the session
+        let mut intruder = Encryption::new();
+        let init = intruder.seal(server_addr(), vec![1], Instant::now()).unwrap();
+        assert_eq!(server.open(client_addr(), &init[0], Instant::now()), Opened::Rejected("handshake request for an established session"));
+        assert!(server.is_established(client_addr()));
+
+        let later = Instant::now() + Duration::from_secs(SESSION_TIMEOUT_SECS);
+        sent(server.open(client_addr(), &init[0], later));
+        assert!(server.is_established(client_addr()));
+        assert_eq!(server.take_new_peers(), vec![client_addr()]);
+    }
+
+    #[test]
+    fn expires_idle_sessions() {
+        let mut client = Encryption::new();
+        let mut server = Encryption::new();
+        handshake(&mut client, &mut server);
+
+        let later = Instant::now() + Duration::from_secs(SESSION_TIMEOUT_SECS);
+        assert_eq!(server.open(server_addr(), &[], later), Opened::Rejected("malformed datagram"));
+        assert!(!server.is_established(client_addr()));
+
+        // Sending keeps a session alive
+        client.seal(server_addr(), vec![1], later - Duration::from_secs(1)).unwrap();
+        client.seal(server_addr(), vec![2], later).unwrap();
+        assert!(client.is_established(server_addr()));
+    }
+
+    #[test]
+    fn rejects_garbage_and_unknown_senders() {
+        let mut server = Encryption::new();
+        assert_eq!(server.open(client_addr(), &[], Instant::now()), Opened::Rejected("malformed datagram"));
+        assert_eq!(server.open(client_addr(), &[9; 40], Instant::now()), Opened::Rejected("malformed datagram"));
+        assert_eq!(server.open(client_addr(), &[3; 40], Instant::now()), Opened::Rejected("no session has been established with the sender"));
+        assert_eq!(server.open(client_addr(), &[2; 33], Instant::now()), Opened::Rejected("unexpected handshake response"));
+    }
+
+    fn token(key: &[u8; 32], server: SocketAddr, expires_in: Duration) -> ConnectToken {
+        ConnectToken::generate(key, 42, vec![server], SystemTime::now() + expires_in, vec![9]).unwrap()
+    }
+
+    #[test]
+    fn connects_with_a_connect_token() {
+        let key = ConnectToken::generate_key();
+        let mut client = Encryption::new().with_connect_token(token(&key, server_addr(), Duration::from_secs(30)));
+        let mut server = Encryption::new().with_token_key(key, server_addr());
+
+        let request = client.seal(server_addr(), vec![1], Instant::now()).unwrap();
+        let accepted = sent(server.open(client_addr(), &request[0], Instant::now()));
+        let connected = server.connected_client(client_addr()).unwrap();
+        assert_eq!(connected.client_id, 42);
+        assert_eq!(connected.user_data, vec![9]);
+
+        // A resent request is accepted again
+        let accepted_again = sent(server.open(client_addr(), &request[0], Instant::now()));
+        assert_ne!(accepted, accepted_again);
+
+        let flushed = sent(client.open(server_addr(), &accepted[0], Instant::now()));
+        assert_eq!(client.open(server_addr(), &accepted_again[0], Instant::now()), Opened::Rejected("unexpected connect token response"));
+        assert_eq!(server.open(client_addr(), &flushed[0], Instant::now()), Opened::Payload(vec![1]));
+
+        let reply = server.seal(client_addr(), vec![2], Instant::now()).unwrap();
+        assert_eq!(client.open(server_addr(), &reply[0], Instant::now()), Opened::Payload(vec![2]));
+    }
+
+    #[test]
+    fn does_not_reuse_a_connect_token() {
+        let key = ConnectToken::generate_key();
+        let token = token(&key, server_addr(), Duration::from_secs(60));
+        let mut client = Encryption::new().with_connect_token(token.clone());
+        let mut server = Encryption::new().with_token_key(key, server_addr());
+
+        let start = Instant::now();
+        let request = client.seal(server_addr(), vec![1], start).unwrap();
+        let accepted = sent(server.open(client_addr(), &request[0], start));
+        sent(client.open(server_addr(), &accepted[0], start));
+
+        // Both sides dropped the session, a new one would reuse the nonces of the token keys
+        let later = start + Duration::from_secs(SESSION_TIMEOUT_SECS + 1);
+        assert!(client.seal(server_addr(), vec![2], later).is_err());
+
+        let mut restarted = Encryption::new().with_connect_token(token);
+        let request = restarted.seal(server_addr(), vec![2], later).unwrap();
+        assert_eq!(server.open(client_addr(), &request[0], later), Opened::Rejected("connect token was already used"));
+        assert!(server.connected_client(client_addr()).is_none());
+    }
+
+    #[test]
+    fn rejects_clients_without_a_valid_token() {
+        let key = ConnectToken::generate_key();
+        let other_addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let mut server = Encryption::new().with_token_key(key, server_addr());
+
+        // A plain key exchange
+        let mut client = Encryption::new();
+        let init = client.seal(server_addr(), vec![1], Instant::now()).unwrap();
+        assert_eq!(server.open(client_addr(), &init[0], Instant::now()), Opened::Rejected("a connect token is required"));
+
+        let request = |token: ConnectToken| Encryption::new().with_connect_token(token).seal(server_addr(), vec![1], Instant::now()).unwrap().remove(0);
+
+        let forged = request(token(&ConnectToken::generate_key(), server_addr(), Duration::from_secs(30)));
+        assert_eq!(server.open(client_addr(), &forged, Instant::now()), Opened::Rejected("invalid connect token"));
+
+        let expired = request(token(&key, server_addr(), Duration::from_secs(0)));
+        assert_eq!(server.open(client_addr(), &expired, Instant::now()), Opened::Rejected("expired connect token"));
+
+        let elsewhere = ConnectToken::generate(&key, 1, vec![other_addr, server_addr()], SystemTime::now() + Duration::from_secs(30), Vec::new()).unwrap();
+        let mut other_server = Encryption::new().with_token_key(key, client_addr());
+        assert_eq!(other_server.open(other_addr, &request(elsewhere), Instant::now()), Opened::Rejected("connect token is for another server"));
+
+        // A token can only be used from one address
+        let valid = request(token(&key, server_addr(), Duration::from_secs(30)));
+        sent(server.open(client_addr(), &valid, Instant::now()));
+        assert_eq!(server.open(other_addr, &valid, Instant::now()), Opened::Rejected("connect token was already used"));
+        assert!(server.connected_client(other_addr).is_none());
+
+        // The server never starts a handshake itself
+        assert!(server.seal(other_addr, vec![1], Instant::now()).is_err());
+    }
+
+    #[test]
+    fn replay_window_tracks_received_sequence_numbers() {
+        let mut window = ReplayWindow::new();
+        assert!(window.is_fresh(10));
+        window.mark(10);
+        assert!(!window.is_fresh(10));
+        assert!(window.is_fresh(9));
+        window.mark(9);
+        assert!(!window.is_fresh(9));
+
+        window.mark(200);
+        assert!(!window.is_fresh(10));
+        assert!(window.is_fresh(199));
+        assert!(window.is_fresh(73));
+        assert!(!window.is_fresh(72));
+    }
+}
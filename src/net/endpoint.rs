@@ -0,0 +1,490 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use bincode::{deserialize, serialize};
+use rand::random;
+
+use error::{Error, NetworkError, Result};
+use events::{EndpointEvent, TcpEvent};
+use super::{Packet, TcpSocketState, UdpSocket};
+
+// How often the connecting side resends its link request until the other side acknowledges it
+const LINK_RESEND_INTERVAL_MS: u64 = 100;
+
+/// Identifies a peer of an `Endpoint`, no matter whether we talk to it over TCP or UDP.
+///
+/// Ids are handed out by the endpoint itself, so the same player has different ids on the server and on the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId(u64);
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "peer#{}", self.0)
+    }
+}
+
+/// The transport a message is sent or received with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// The reliable, ordered TCP stream.
+    Tcp,
+    /// The UDP socket.
+    Udp,
+}
+
+// Messages the endpoints exchange, both inside TCP frames and UDP packets
+#[derive(Debug, Serialize, Deserialize)]
+enum EndpointMessage {
+    // Sent over TCP by the accepting side, then echoed back over UDP by the connecting side
+    Link { token: u64 },
+    // Confirms over UDP that the UDP address was linked to the peer
+    LinkAck,
+    Data(Vec<u8>),
+}
+
+struct Peer {
+    tcp_addr: SocketAddr,
+    udp_addr: Option<SocketAddr>,
+    // The token that links the UDP address to this peer, once it is known
+    token: Option<u64>,
+    // When the connecting side last sent its link request, `None` once the peer is linked
+    link_sent: Option<Instant>,
+}
+
+/// Serves UDP and TCP for the same peers.
+///
+/// Every peer has one `PeerId`. It is created when the TCP stream connects, after which the accepting side sends a random token over TCP.
+/// The connecting side sends that token back over UDP, which tells the accepting side which UDP address belongs to the peer.
+/// From then on messages from both transports show up in the same event stream with the same `PeerId`.
+///
+/// The UDP socket and the TCP server are bound to the same address, so the connecting side sends its UDP packets to the address it connected to over TCP.
+pub struct Endpoint {
+    udp: UdpSocket,
+    tcp: TcpSocketState,
+    peers: HashMap<PeerId, Peer>,
+    tcp_peers: HashMap<SocketAddr, PeerId>,
+    udp_peers: HashMap<SocketAddr, PeerId>,
+    tokens: HashMap<u64, PeerId>,
+    outgoing: HashSet<PeerId>,
+    next_peer_id: u64,
+}
+
+impl Endpoint {
+    /// Binds the UDP socket of the endpoint. Call `listen` to accept peers, or `connect` to connect to another endpoint.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Endpoint> {
+        let mut udp = UdpSocket::bind(addr)?;
+        udp.set_nonblocking(true)?;
+
+        Ok(Endpoint {
+            udp,
+            tcp: TcpSocketState::new(),
+            peers: HashMap::new(),
+            tcp_peers: HashMap::new(),
+            udp_peers: HashMap::new(),
+            tokens: HashMap::new(),
+            outgoing: HashSet::new(),
+            next_peer_id: 0,
+        })
+    }
+
+    /// Uses the given TCP state, so its connection limits and stream options apply to the peers.
+    pub fn with_tcp(mut self, tcp: TcpSocketState) -> Endpoint {
+        self.tcp = tcp;
+        self
+    }
+
+    /// Starts accepting peers over TCP on the given address, which should be the address the UDP socket is bound to.
+    pub fn listen(&mut self, addr: SocketAddr) -> Result<SocketAddr> {
+        self.tcp.start(addr)
+    }
+
+    /// Connects to the endpoint listening on `addr`. The peer is linked over UDP once the other side sent us its token.
+    pub fn connect(&mut self, addr: SocketAddr) -> Result<PeerId> {
+        self.tcp.connect(addr)?;
+        let peer = self.add_peer(addr);
+        self.outgoing.insert(peer);
+        Ok(peer)
+    }
+
+    /// Returns the address the UDP socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+
+    /// Returns the UDP address of the peer, if it has been linked.
+    pub fn udp_addr(&self, peer: PeerId) -> Option<SocketAddr> {
+        self.peers.get(&peer).and_then(|p| p.udp_addr)
+    }
+
+    /// Returns the address of the TCP stream of the peer.
+    pub fn tcp_addr(&self, peer: PeerId) -> Option<SocketAddr> {
+        self.peers.get(&peer).map(|p| p.tcp_addr)
+    }
+
+    /// Sends a message to the peer over the given transport. Sending over UDP fails until the peer is linked.
+    pub fn send(&mut self, peer: PeerId, transport: Transport, payload: Vec<u8>) -> Result<()> {
+        let (tcp_addr, udp_addr) = match self.peers.get(&peer) {
+            Some(p) => (p.tcp_addr, p.udp_addr),
+            None => return Err(Error::from(NetworkError::PeerNotFound)),
+        };
+
+        let message = serialize(&EndpointMessage::Data(payload))?;
+        match transport {
+            Transport::Tcp => self.tcp.send(tcp_addr, message),
+            Transport::Udp => match udp_addr {
+                Some(addr) => {
+                    self.udp.send(Packet::new(addr, message))??;
+                    Ok(())
+                }
+                None => Err(Error::from(NetworkError::PeerNotLinked)),
+            },
+        }
+    }
+
+    /// Processes everything that was received on both transports since the last call, and returns the resulting events. This does not block.
+    ///
+    /// A reply that can not be sent is logged and skipped, so the events after it are still processed. Only an error of the UDP socket itself is returned.
+    pub fn poll(&mut self) -> Result<Vec<EndpointEvent>> {
+        let mut events = Vec::new();
+
+        let tcp_events: Vec<TcpEvent> = self.tcp.events().collect();
+        for event in tcp_events {
+            if let Err(e) = self.handle_tcp_event(event, &mut events) {
+                warn!("Could not handle a TCP event: {}", e);
+            }
+        }
+
+        loop {
+            match self.udp.recv() {
+                Ok(Some(packet)) => {
+                    let addr = packet.addr();
+                    if let Err(e) = self.handle_udp_packet(packet, &mut events) {
+                        warn!("Could not handle a UDP packet from {}: {}", addr, e);
+                    }
+                }
+                Ok(None) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                    debug!("Ignoring invalid UDP packet: {}", e);
+                }
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+
+        self.resend_link_requests();
+        Ok(events)
+    }
+
+    fn add_peer(&mut self, tcp_addr: SocketAddr) -> PeerId {
+        let id = PeerId(self.next_peer_id);
+        self.next_peer_id += 1;
+
+        self.peers.insert(
+            id,
+            Peer {
+                tcp_addr,
+                udp_addr: None,
+                token: None,
+                link_sent: None,
+            },
+        );
+        self.tcp_peers.insert(tcp_addr, id);
+        id
+    }
+
+    fn handle_tcp_event(&mut self, event: TcpEvent, events: &mut Vec<EndpointEvent>) -> Result<()> {
+        match event {
+            TcpEvent::Connected { addr } => {
+                // Peers we connected to ourselves already have an id
+                let peer = match self.tcp_peers.get(&addr) {
+                    Some(&peer) => peer,
+                    None => {
+                        let peer = self.add_peer(addr);
+                        let token = random::<u64>();
+                        self.tokens.insert(token, peer);
+                        if let Some(p) = self.peers.get_mut(&peer) {
+                            p.token = Some(token);
+                        }
+                        // If the peer is already gone its `Disconnected` event follows, which cleans up after it
+                        if let Err(e) = self.tcp.send(addr, serialize(&EndpointMessage::Link { token })?) {
+                            warn!("Could not send the link token to {}: {}", addr, e);
+                        }
+                        peer
+                    }
+                };
+                events.push(EndpointEvent::Connected { peer });
+            }
+            TcpEvent::Message { addr, payload } => {
+                let peer = match self.tcp_peers.get(&addr) {
+                    Some(&peer) => peer,
+                    None => return Ok(()),
+                };
+
+                match deserialize(&payload) {
+                    Ok(EndpointMessage::Data(payload)) => events.push(EndpointEvent::Message {
+                        peer,
+                        transport: Transport::Tcp,
+                        payload,
+                    }),
+                    Ok(EndpointMessage::Link { token }) if self.outgoing.contains(&peer) => {
+                        if let Some(p) = self.peers.get_mut(&peer) {
+                            p.token = Some(token);
+                        }
+                        self.send_link_request(peer)?;
+                    }
+                    Ok(message) => debug!("Unexpected message from {} over TCP: {:?}", peer, message),
+                    Err(e) => debug!("Ignoring invalid message from {} over TCP: {}", peer, e),
+                }
+            }
+            TcpEvent::Disconnected { addr, reason } => {
+                if let Some(peer) = self.tcp_peers.remove(&addr) {
+                    if let Some(p) = self.peers.remove(&peer) {
+                        if let Some(udp_addr) = p.udp_addr {
+                            self.udp_peers.remove(&udp_addr);
+                        }
+                        if let Some(token) = p.token {
+                            self.tokens.remove(&token);
+                        }
+                    }
+                    self.outgoing.remove(&peer);
+                    events.push(EndpointEvent::Disconnected { peer, reason });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_udp_packet(&mut self, packet: Packet, events: &mut Vec<EndpointEvent>) -> Result<()> {
+        let addr = packet.addr();
+        let message = match deserialize(packet.payload()) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("Ignoring invalid message from {} over UDP: {}", addr, e);
+                return Ok(());
+            }
+        };
+
+        match message {
+            EndpointMessage::Data(payload) => match self.udp_peers.get(&addr) {
+                Some(&peer) => events.push(EndpointEvent::Message {
+                    peer,
+                    transport: Transport::Udp,
+                    payload,
+                }),
+                None => debug!("Ignoring UDP data from {}, it is not linked to a peer", addr),
+            },
+            EndpointMessage::Link { token } => {
+                let peer = match self.tokens.get(&token) {
+                    Some(&peer) => peer,
+                    None => return Ok(()),
+                };
+
+                match self.peers.get(&peer).map(|p| p.udp_addr) {
+                    Some(None) => {
+                        if let Some(p) = self.peers.get_mut(&peer) {
+                            p.udp_addr = Some(addr);
+                        }
+                        self.udp_peers.insert(addr, peer);
+                        events.push(EndpointEvent::Linked { peer, udp_addr: addr });
+                    }
+                    // The link request was resent because our acknowledgement got lost
+                    Some(Some(linked)) if linked == addr => {}
+                    // Tokens link exactly one address, nobody gets to take over a linked peer
+                    _ => return Ok(()),
+                }
+
+                self.udp.send(Packet::new(addr, serialize(&EndpointMessage::LinkAck)?))??;
+            }
+            EndpointMessage::LinkAck => {
+                let peer = self
+                    .outgoing
+                    .iter()
+                    .cloned()
+                    .find(|peer| self.peers.get(peer).is_some_and(|p| p.link_sent.is_some() && p.tcp_addr == addr));
+
+                if let Some(peer) = peer {
+                    if let Some(p) = self.peers.get_mut(&peer) {
+                        p.udp_addr = Some(addr);
+                        p.link_sent = None;
+                    }
+                    self.udp_peers.insert(addr, peer);
+                    events.push(EndpointEvent::Linked { peer, udp_addr: addr });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send_link_request(&mut self, peer: PeerId) -> Result<()> {
+        let (addr, token) = match self.peers.get_mut(&peer) {
+            Some(Peer { tcp_addr, token: Some(token), .. }) => (*tcp_addr, *token),
+            _ => return Ok(()),
+        };
+
+        self.udp.send(Packet::new(addr, serialize(&EndpointMessage::Link { token })?))??;
+        if let Some(p) = self.peers.get_mut(&peer) {
+            p.link_sent = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    // UDP is unreliable, so keep sending the link request until the other side acknowledges it
+    fn resend_link_requests(&mut self) {
+        let resend_interval = Duration::from_millis(LINK_RESEND_INTERVAL_MS);
+        let pending: Vec<PeerId> = self
+            .outgoing
+            .iter()
+            .cloned()
+            .filter(|peer| {
+                self.peers
+                    .get(peer)
+                    .and_then(|p| p.link_sent)
+                    .is_some_and(|sent| sent.elapsed() >= resend_interval)
+            }).collect();
+
+        for peer in pending {
+            if let Err(e) = self.send_link_request(peer) {
+                warn!("Could not resend the link request to {}: {}", peer, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Endpoint, PeerId, Transport};
+    use events::EndpointEvent;
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // Polls both endpoints until `done` returns true for the events collected so far
+    fn poll_until<F>(server: &mut Endpoint, client: &mut Endpoint, mut done: F) -> (Vec<EndpointEvent>, Vec<EndpointEvent>)
+    where
+        F: FnMut(&[EndpointEvent], &[EndpointEvent]) -> bool,
+    {
+        let mut server_events = Vec::new();
+        let mut client_events = Vec::new();
+        let start = Instant::now();
+
+        while !done(&server_events, &client_events) {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out, server: {:?}, client: {:?}", server_events, client_events);
+            server_events.extend(server.poll().unwrap());
+            client_events.extend(client.poll().unwrap());
+            thread::sleep(Duration::from_millis(5));
+        }
+        (server_events, client_events)
+    }
+
+    fn linked(events: &[EndpointEvent]) -> Option<PeerId> {
+        events.iter().filter_map(|e| match *e {
+            EndpointEvent::Linked { peer, .. } => Some(peer),
+            _ => None,
+        }).next()
+    }
+
+    fn messages(events: &[EndpointEvent]) -> Vec<(PeerId, Transport, Vec<u8>)> {
+        events.iter().filter_map(|e| match *e {
+            EndpointEvent::Message { peer, transport, ref payload } => Some((peer, transport, payload.clone())),
+            _ => None,
+        }).collect()
+    }
+
+    fn connected_pair(port: u16) -> (Endpoint, Endpoint, PeerId, PeerId) {
+        let server_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let mut server = Endpoint::bind(server_addr).unwrap();
+        server.listen(server_addr).unwrap();
+
+        let mut client = Endpoint::bind("127.0.0.1:0").unwrap();
+        let server_peer = client.connect(server_addr).unwrap();
+
+        let (server_events, client_events) = poll_until(&mut server, &mut client, |s, c| linked(s).is_some() && linked(c).is_some());
+        assert_eq!(linked(&client_events), Some(server_peer));
+        let client_peer = linked(&server_events).unwrap();
+
+        assert_eq!(server.udp_addr(client_peer), Some(client.local_addr().unwrap()));
+        assert_eq!(client.udp_addr(server_peer), Some(server_addr));
+        (server, client, client_peer, server_peer)
+    }
+
+    #[test]
+    fn links_tcp_and_udp_to_one_peer() {
+        let (mut server, mut client, client_peer, server_peer) = connected_pair(28000);
+
+        client.send(server_peer, Transport::Tcp, vec![1]).unwrap();
+        client.send(server_peer, Transport::Udp, vec![2]).unwrap();
+        server.send(client_peer, Transport::Udp, vec![3]).unwrap();
+        server.send(client_peer, Transport::Tcp, vec![4]).unwrap();
+
+        let (server_events, client_events) = poll_until(&mut server, &mut client, |s, c| messages(s).len() == 2 && messages(c).len() == 2);
+
+        let mut received = messages(&server_events);
+        received.sort_by_key(|m| m.2.clone());
+        assert_eq!(received, vec![(client_peer, Transport::Tcp, vec![1]), (client_peer, Transport::Udp, vec![2])]);
+
+        let mut received = messages(&client_events);
+        received.sort_by_key(|m| m.2.clone());
+        assert_eq!(received, vec![(server_peer, Transport::Udp, vec![3]), (server_peer, Transport::Tcp, vec![4])]);
+    }
+
+    #[test]
+    fn disconnecting_tcp_ends_the_peer() {
+        let (mut server, client, client_peer, _) = connected_pair(28001);
+        let client_udp_addr = client.local_addr().unwrap();
+        drop(client);
+
+        let mut events = Vec::new();
+        let start = Instant::now();
+        while !events.iter().any(|e| matches!(*e, EndpointEvent::Disconnected { .. })) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            events.extend(server.poll().unwrap());
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        match events.pop() {
+            Some(EndpointEvent::Disconnected { peer, .. }) => assert_eq!(peer, client_peer),
+            e => panic!("unexpected event: {:?}", e),
+        }
+        assert_eq!(server.udp_addr(client_peer), None);
+        assert!(!server.udp_peers.contains_key(&client_udp_addr));
+        assert!(server.send(client_peer, Transport::Tcp, vec![1]).is_err());
+    }
+
+    #[test]
+    fn peers_that_leave_before_the_link_are_cleaned_up() {
+        let server_addr: SocketAddr = "127.0.0.1:28003".parse().unwrap();
+        let mut server = Endpoint::bind(server_addr).unwrap();
+        server.listen(server_addr).unwrap();
+
+        // The stream is gone before the server gets to send the link token
+        drop(TcpStream::connect(server_addr).unwrap());
+        thread::sleep(Duration::from_millis(200));
+
+        let mut events = Vec::new();
+        let start = Instant::now();
+        while !events.iter().any(|e| matches!(*e, EndpointEvent::Disconnected { .. })) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            events.extend(server.poll().unwrap());
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        assert!(matches!(events[0], EndpointEvent::Connected { .. }));
+        assert!(server.peers.is_empty());
+        assert!(server.tcp_peers.is_empty());
+        assert!(server.tokens.is_empty());
+    }
+
+    #[test]
+    fn sending_udp_before_linking_fails() {
+        let server_addr: SocketAddr = "127.0.0.1:28002".parse().unwrap();
+        let mut server = Endpoint::bind(server_addr).unwrap();
+        server.listen(server_addr).unwrap();
+
+        let mut client = Endpoint::bind("127.0.0.1:0").unwrap();
+        let server_peer = client.connect(server_addr).unwrap();
+        assert!(client.send(server_peer, Transport::Udp, vec![1]).is_err());
+    }
+}
@@ -43,7 +43,7 @@ impl ExternalAcks {
             }
             self.last_seq = seq_num;
         } else if neg_diff <= 32 {
-            self.field = self.field | (1 << neg_diff - 1);
+            self.field |= 1 << (neg_diff - 1);
         }
     }
 }
@@ -178,6 +178,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn skips_missing_acks_correctly() {
         let mut acks = ExternalAcks::new();
         acks.ack(0);
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Addresses we have not heard from for this long are forgotten
+const IDLE_TIMEOUT_SECS: u64 = 10;
+// How often idle addresses and expired bans are cleaned up
+const PRUNE_INTERVAL_MS: u64 = 1000;
+// Most addresses tracked at once, datagrams from new addresses are dropped while the table is full
+const MAX_TRACKED_ADDRESSES: usize = 65536;
+// Rate limited packets are counted over this window to decide on a ban
+const VIOLATION_WINDOW_MS: u64 = 1000;
+
+/// Protects a `UdpSocket` against floods, per source IP address.
+///
+/// Every IP address gets a token bucket for inbound datagrams, and one for connection attempts. A datagram from an address that has no connection yet counts as a connection attempt.
+/// Addresses that keep going over their packet rate can be banned for a while, and the total number of connections can be capped.
+/// Nothing is limited until it is configured with the `with_*` methods.
+///
+/// The checks run before a datagram is decrypted or turns into a `Connection`, so a flood does not grow the connection map.
+#[derive(Debug, Clone)]
+pub struct FloodProtection {
+    packet_rate: Option<Rate>,
+    connection_rate: Option<Rate>,
+    max_connections: Option<usize>,
+    ban: Option<(u32, Duration)>,
+    addresses: HashMap<IpAddr, AddressState>,
+    bans: HashMap<IpAddr, Instant>,
+    last_prune: Option<Instant>,
+}
+
+/// Why a datagram was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dropped {
+    /// The source address is banned.
+    Banned,
+    /// The source address went over its packet rate.
+    PacketRate,
+    /// The source address went over its connection attempt rate, or too many addresses are being tracked.
+    ConnectionRate,
+    /// The maximum number of connections was reached.
+    ConnectionLimit,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    per_second: f64,
+    burst: f64,
+}
+
+#[derive(Debug, Clone)]
+struct AddressState {
+    packets: TokenBucket,
+    connections: TokenBucket,
+    violations: u32,
+    violations_since: Instant,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl FloodProtection {
+    pub fn new() -> FloodProtection {
+        FloodProtection {
+            packet_rate: None,
+            connection_rate: None,
+            max_connections: None,
+            ban: None,
+            addresses: HashMap::new(),
+            bans: HashMap::new(),
+            last_prune: None,
+        }
+    }
+
+    /// Accepts `per_second` datagrams per second from every IP address, with bursts of up to `burst` datagrams.
+    pub fn with_packet_rate(mut self, per_second: u32, burst: u32) -> FloodProtection {
+        self.packet_rate = Some(Rate::new(per_second, burst));
+        self
+    }
+
+    /// Accepts `per_second` connection attempts per second from every IP address, with bursts of up to `burst` attempts.
+    pub fn with_connection_rate(mut self, per_second: u32, burst: u32) -> FloodProtection {
+        self.connection_rate = Some(Rate::new(per_second, burst));
+        self
+    }
+
+    /// Drops datagrams from new addresses while there are `max` connections.
+    pub fn with_max_connections(mut self, max: usize) -> FloodProtection {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Bans an IP address for `duration` once `violations` of its datagrams were dropped for its packet rate within a second.
+    pub fn with_ban(mut self, violations: u32, duration: Duration) -> FloodProtection {
+        self.ban = Some((violations.max(1), duration));
+        self
+    }
+
+    /// Returns the IP addresses that are banned right now.
+    pub fn banned(&self, now: Instant) -> Vec<IpAddr> {
+        self.bans.iter().filter(|&(_, &until)| until > now).map(|(&ip, _)| ip).collect()
+    }
+
+    /// Checks a datagram from `ip`, `known` tells if its address has a connection already.
+    pub fn check(&mut self, ip: IpAddr, known: bool, connections: usize, now: Instant) -> Result<(), Dropped> {
+        self.prune(now);
+
+        if self.bans.get(&ip).is_some_and(|&until| until > now) {
+            return Err(Dropped::Banned);
+        }
+
+        if !self.addresses.contains_key(&ip) && self.addresses.len() >= MAX_TRACKED_ADDRESSES {
+            return Err(if known { Dropped::PacketRate } else { Dropped::ConnectionRate });
+        }
+
+        let packet_rate = self.packet_rate;
+        let connection_rate = self.connection_rate;
+        let state = self.addresses.entry(ip).or_insert_with(|| AddressState::new(packet_rate, connection_rate, now));
+        state.last_seen = now;
+
+        if let Some(rate) = packet_rate {
+            if !state.packets.take(rate, now) {
+                if now.duration_since(state.violations_since) >= Duration::from_millis(VIOLATION_WINDOW_MS) {
+                    state.violations = 0;
+                    state.violations_since = now;
+                }
+                state.violations += 1;
+
+                if let Some((violations, duration)) = self.ban {
+                    if state.violations >= violations {
+                        warn!("Banning {} for {:?}, it went over its packet rate", ip, duration);
+                        state.violations = 0;
+                        self.bans.insert(ip, now + duration);
+                    }
+                }
+                return Err(Dropped::PacketRate);
+            }
+        }
+
+        if known {
+            return Ok(());
+        }
+
+        if let Some(rate) = connection_rate {
+            if !state.connections.take(rate, now) {
+                return Err(Dropped::ConnectionRate);
+            }
+        }
+
+        match self.max_connections {
+            Some(max) if connections >= max => Err(Dropped::ConnectionLimit),
+            _ => Ok(()),
+        }
+    }
+
+    /// Gives back the packet token a datagram from `ip` took, for datagrams that turned out to be protocol traffic.
+    pub fn refund(&mut self, ip: IpAddr) {
+        if let (Some(rate), Some(state)) = (self.packet_rate, self.addresses.get_mut(&ip)) {
+            state.packets.tokens = (state.packets.tokens + 1.0).min(rate.burst);
+        }
+    }
+
+    // Forgets idle addresses and expired bans, so spoofed addresses do not pile up
+    fn prune(&mut self, now: Instant) {
+        if self.last_prune.is_some_and(|last| now.duration_since(last) < Duration::from_millis(PRUNE_INTERVAL_MS)) {
+            return;
+        }
+        self.last_prune = Some(now);
+
+        let idle = Duration::from_secs(IDLE_TIMEOUT_SECS);
+        self.addresses.retain(|_, state| now.duration_since(state.last_seen) < idle);
+        self.bans.retain(|_, &mut until| until > now);
+    }
+}
+
+impl Default for FloodProtection {
+    fn default() -> FloodProtection {
+        FloodProtection::new()
+    }
+}
+
+impl Rate {
+    fn new(per_second: u32, burst: u32) -> Rate {
+        Rate {
+            per_second: per_second as f64,
+            burst: burst.max(1) as f64,
+        }
+    }
+}
+
+impl AddressState {
+    fn new(packet_rate: Option<Rate>, connection_rate: Option<Rate>, now: Instant) -> AddressState {
+        AddressState {
+            packets: TokenBucket::full(packet_rate, now),
+            connections: TokenBucket::full(connection_rate, now),
+            violations: 0,
+            violations_since: now,
+            last_seen: now,
+        }
+    }
+}
+
+impl TokenBucket {
+    fn full(rate: Option<Rate>, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: rate.map_or(0.0, |rate| rate.burst),
+            updated: now,
+        }
+    }
+
+    // Refills the bucket for the time that passed and takes a token if there is one
+    fn take(&mut self, rate: Rate, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Dropped, FloodProtection, IDLE_TIMEOUT_SECS};
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn allows_everything_by_default() {
+        let mut protection = FloodProtection::new();
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert_eq!(protection.check(ip(1), false, 1000, now), Ok(()));
+        }
+    }
+
+    #[test]
+    fn limits_packets_per_address() {
+        let mut protection = FloodProtection::new().with_packet_rate(10, 5);
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            assert_eq!(protection.check(ip(1), true, 0, now), Ok(()));
+        }
+        assert_eq!(protection.check(ip(1), true, 0, now), Err(Dropped::PacketRate));
+        // Other addresses have their own bucket
+        assert_eq!(protection.check(ip(2), true, 0, now), Ok(()));
+
+        // A refunded token can be used again, but the bucket does not go over its burst
+        protection.refund(ip(1));
+        assert_eq!(protection.check(ip(1), true, 0, now), Ok(()));
+        assert_eq!(protection.check(ip(1), true, 0, now), Err(Dropped::PacketRate));
+        for _ in 0..10 {
+            protection.refund(ip(2));
+        }
+        for _ in 0..5 {
+            assert_eq!(protection.check(ip(2), true, 0, now), Ok(()));
+        }
+        assert_eq!(protection.check(ip(2), true, 0, now), Err(Dropped::PacketRate));
+
+        // The bucket refills at the configured rate
+        let later = now + Duration::from_millis(200);
+        assert_eq!(protection.check(ip(1), true, 0, later), Ok(()));
+        assert_eq!(protection.check(ip(1), true, 0, later), Ok(()));
+        assert_eq!(protection.check(ip(1), true, 0, later), Err(Dropped::PacketRate));
+    }
+
+    #[test]
+    fn limits_connection_attempts_and_connections() {
+        let mut protection = FloodProtection::new().with_connection_rate(1, 2).with_max_connections(3);
+        let now = Instant::now();
+
+        assert_eq!(protection.check(ip(1), false, 0, now), Ok(()));
+        assert_eq!(protection.check(ip(1), false, 0, now), Ok(()));
+        assert_eq!(protection.check(ip(1), false, 0, now), Err(Dropped::ConnectionRate));
+        // Known connections are not attempts
+        assert_eq!(protection.check(ip(1), true, 0, now), Ok(()));
+
+        assert_eq!(protection.check(ip(2), false, 3, now), Err(Dropped::ConnectionLimit));
+        assert_eq!(protection.check(ip(2), true, 3, now), Ok(()));
+    }
+
+    #[test]
+    fn bans_addresses_that_keep_flooding() {
+        let mut protection = FloodProtection::new().with_packet_rate(1, 1).with_ban(3, Duration::from_secs(5));
+        let now = Instant::now();
+
+        assert_eq!(protection.check(ip(1), true, 0, now), Ok(()));
+        for _ in 0..3 {
+            assert_eq!(protection.check(ip(1), true, 0, now), Err(Dropped::PacketRate));
+        }
+        assert_eq!(protection.banned(now), vec![ip(1)]);
+        assert_eq!(protection.check(ip(1), true, 0, now + Duration::from_secs(2)), Err(Dropped::Banned));
+
+        let after_ban = now + Duration::from_secs(IDLE_TIMEOUT_SECS);
+        assert_eq!(protection.check(ip(1), true, 0, after_ban), Ok(()));
+        assert!(protection.banned(after_ban).is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::{self, Write};
+
+/// Biggest payload a single data frame may carry. Bigger frames are treated as a protocol error.
+pub const MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+// Every frame starts with a one byte kind and a four byte big endian payload length.
+const HEADER_SIZE: usize = 5;
+
+const KIND_DATA: u8 = 0;
+const KIND_PING: u8 = 1;
+const KIND_PONG: u8 = 2;
+
+/// A frame of the TCP protocol.
+///
+/// TCP is a stream, so we prefix every message with its kind and length to know where it ends. Ping and pong frames carry no payload and are used for the heartbeat.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// Application data.
+    Data(Vec<u8>),
+    /// Asks the other side to answer with a `Pong`.
+    Ping,
+    /// The answer to a `Ping`.
+    Pong,
+}
+
+impl Frame {
+    /// Writes the frame, header included, to the given writer.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let (kind, payload): (u8, &[u8]) = match *self {
+            Frame::Data(ref payload) => (KIND_DATA, payload),
+            Frame::Ping => (KIND_PING, &[]),
+            Frame::Pong => (KIND_PONG, &[]),
+        };
+
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame payload is too big"));
+        }
+
+        let len = payload.len() as u32;
+        let header = [kind, (len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        writer.write_all(&header)?;
+        writer.write_all(payload)
+    }
+
+    /// Returns the number of bytes the frame takes on the stream, header included.
+    pub fn encoded_len(&self) -> usize {
+        match *self {
+            Frame::Data(ref payload) => HEADER_SIZE + payload.len(),
+            Frame::Ping | Frame::Pong => HEADER_SIZE,
+        }
+    }
+
+    // Decodes the frame at the start of `buffer` and returns it with the number of bytes it takes up
+    fn decode(buffer: &[u8]) -> io::Result<Option<(Frame, usize)>> {
+        if buffer.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let len = (buffer[1] as usize) << 24 | (buffer[2] as usize) << 16 | (buffer[3] as usize) << 8 | buffer[4] as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame payload is too big"));
+        }
+        if buffer.len() < HEADER_SIZE + len {
+            return Ok(None);
+        }
+
+        let frame = match buffer[0] {
+            KIND_DATA => Frame::Data(buffer[HEADER_SIZE..HEADER_SIZE + len].to_vec()),
+            KIND_PING if len == 0 => Frame::Ping,
+            KIND_PONG if len == 0 => Frame::Pong,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid frame")),
+        };
+        Ok(Some((frame, HEADER_SIZE + len)))
+    }
+}
+
+/// The bytes read from a stream that were not decoded into frames yet.
+///
+/// Decoding a frame only moves a read offset forward. The decoded bytes are dropped when more bytes are added, so a read full of small frames is moved once instead of once per frame.
+#[derive(Debug, Default)]
+pub struct FrameBuffer {
+    bytes: Vec<u8>,
+    // Where the first frame that was not decoded yet starts
+    offset: usize,
+}
+
+impl FrameBuffer {
+    pub fn new() -> FrameBuffer {
+        FrameBuffer::default()
+    }
+
+    /// Appends bytes read from the stream.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.bytes.drain(..self.offset);
+        self.offset = 0;
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Takes the next complete frame out of the buffer.
+    ///
+    /// Returns `Ok(None)` if the buffer does not contain a whole frame yet, and an `InvalidData` error if the data is not a valid frame.
+    pub fn decode(&mut self) -> io::Result<Option<Frame>> {
+        match Frame::decode(&self.bytes[self.offset..])? {
+            Some((frame, len)) => {
+                self.offset += len;
+                Ok(Some(frame))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Frame, FrameBuffer, MAX_FRAME_SIZE};
+    use std::io;
+
+    fn encode(frame: &Frame) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        frame.write_to(&mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn round_trips_frames() {
+        let mut buffer = FrameBuffer::new();
+        for frame in &[Frame::Data(vec![1, 2, 3]), Frame::Ping, Frame::Pong, Frame::Data(Vec::new())] {
+            buffer.extend(&encode(frame));
+        }
+
+        assert_eq!(buffer.decode().unwrap(), Some(Frame::Data(vec![1, 2, 3])));
+        assert_eq!(buffer.decode().unwrap(), Some(Frame::Ping));
+        assert_eq!(buffer.decode().unwrap(), Some(Frame::Pong));
+        assert_eq!(buffer.decode().unwrap(), Some(Frame::Data(Vec::new())));
+        assert_eq!(buffer.decode().unwrap(), None);
+        assert_eq!(buffer.offset, buffer.bytes.len());
+    }
+
+    #[test]
+    fn waits_for_partial_frames() {
+        let encoded = encode(&Frame::Data(vec![7; 100]));
+        let mut buffer = FrameBuffer::new();
+        buffer.extend(&encoded[..50]);
+        assert_eq!(buffer.decode().unwrap(), None);
+        assert_eq!(buffer.bytes.len(), 50);
+
+        buffer.extend(&encoded[50..]);
+        assert_eq!(buffer.decode().unwrap(), Some(Frame::Data(vec![7; 100])));
+    }
+
+    #[test]
+    fn drops_decoded_frames_when_more_bytes_arrive() {
+        let mut buffer = FrameBuffer::new();
+        buffer.extend(&encode(&Frame::Ping));
+        let pong = encode(&Frame::Pong);
+        buffer.extend(&pong[..2]);
+
+        assert_eq!(buffer.decode().unwrap(), Some(Frame::Ping));
+        assert_eq!(buffer.bytes.len(), 7);
+
+        buffer.extend(&pong[2..]);
+        assert_eq!(buffer.bytes.len(), 5);
+        assert_eq!(buffer.decode().unwrap(), Some(Frame::Pong));
+        assert_eq!(buffer.offset, buffer.bytes.len());
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        assert_eq!(Frame::decode(&[9, 0, 0, 0, 0]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let len = MAX_FRAME_SIZE + 1;
+        let header = [0, (len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        assert_eq!(Frame::decode(&header).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,257 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Simulates a bad network by dropping, delaying and duplicating datagrams.
+///
+/// Datagrams that are delayed by a different amount because of jitter arrive out of order, so jitter also reorders them.
+/// All the randomness comes from an RNG seeded with the seed given to `new`, so the same seed and traffic always give the same result.
+///
+/// The configured conditions apply to each direction separately, a latency of 100ms adds 200ms to the round trip time.
+#[derive(Debug, Clone)]
+pub struct LinkConditioner {
+    latency: Duration,
+    jitter: Duration,
+    packet_loss: f64,
+    duplication: f64,
+    rng: StdRng,
+    order: u64,
+    outgoing: BinaryHeap<Delayed>,
+    incoming: BinaryHeap<Delayed>,
+}
+
+/// The direction a datagram travels in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Datagrams we send.
+    Outgoing,
+    /// Datagrams we receive.
+    Incoming,
+}
+
+impl LinkConditioner {
+    /// Creates a conditioner that does not change anything yet, with an RNG seeded by `seed`.
+    pub fn new(seed: u64) -> LinkConditioner {
+        LinkConditioner {
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            packet_loss: 0.0,
+            duplication: 0.0,
+            rng: StdRng::seed_from_u64(seed),
+            order: 0,
+            outgoing: BinaryHeap::new(),
+            incoming: BinaryHeap::new(),
+        }
+    }
+
+    /// Delays every datagram by `latency`.
+    pub fn with_latency(mut self, latency: Duration) -> LinkConditioner {
+        self.latency = latency;
+        self
+    }
+
+    /// Adds or subtracts a random delay of up to `jitter` to the latency of every datagram.
+    pub fn with_jitter(mut self, jitter: Duration) -> LinkConditioner {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Drops datagrams with the given chance, between `0.0` and `1.0`.
+    pub fn with_packet_loss(mut self, chance: f64) -> LinkConditioner {
+        self.packet_loss = clamp_chance(chance);
+        self
+    }
+
+    /// Delivers datagrams twice with the given chance, between `0.0` and `1.0`. The copy gets a delay of its own.
+    pub fn with_duplication(mut self, chance: f64) -> LinkConditioner {
+        self.duplication = clamp_chance(chance);
+        self
+    }
+
+    /// Hands a datagram to the conditioner, which drops it or queues it (maybe twice) until it is due.
+    pub fn push(&mut self, direction: Direction, addr: SocketAddr, data: Vec<u8>, now: Instant) {
+        if self.rng.gen_bool(self.packet_loss) {
+            return;
+        }
+
+        if self.rng.gen_bool(self.duplication) {
+            let due = now + self.delay();
+            self.queue(direction, Delayed::new(due, self.order, addr, data.clone()));
+            self.order += 1;
+        }
+
+        let due = now + self.delay();
+        self.queue(direction, Delayed::new(due, self.order, addr, data));
+        self.order += 1;
+    }
+
+    /// Takes the next datagram that is due at `now` out of the queue.
+    pub fn pop(&mut self, direction: Direction, now: Instant) -> Option<(SocketAddr, Vec<u8>)> {
+        let queue = match direction {
+            Direction::Outgoing => &mut self.outgoing,
+            Direction::Incoming => &mut self.incoming,
+        };
+
+        if queue.peek().is_some_and(|delayed| delayed.due <= now) {
+            queue.pop().map(|delayed| (delayed.addr, delayed.data))
+        } else {
+            None
+        }
+    }
+
+    /// Returns when the next datagram in either direction is due, if any are queued.
+    pub fn next_due(&self) -> Option<Instant> {
+        let outgoing = self.outgoing.peek().map(|delayed| delayed.due);
+        let incoming = self.incoming.peek().map(|delayed| delayed.due);
+        match (outgoing, incoming) {
+            (Some(a), Some(b)) => Some(if a < b { a } else { b }),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn queue(&mut self, direction: Direction, delayed: Delayed) {
+        match direction {
+            Direction::Outgoing => self.outgoing.push(delayed),
+            Direction::Incoming => self.incoming.push(delayed),
+        }
+    }
+
+    fn delay(&mut self) -> Duration {
+        if self.jitter == Duration::from_millis(0) {
+            return self.latency;
+        }
+
+        let jitter = self.jitter.as_secs_f64();
+        let offset = self.rng.gen_range(-jitter..=jitter);
+        Duration::from_secs_f64((self.latency.as_secs_f64() + offset).max(0.0))
+    }
+}
+
+fn clamp_chance(chance: f64) -> f64 {
+    if chance.is_nan() {
+        0.0
+    } else {
+        chance.clamp(0.0, 1.0)
+    }
+}
+
+// A datagram waiting in the queue. The heap is a max-heap, so the ordering is reversed to pop the earliest datagram first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Delayed {
+    due: Instant,
+    // Keeps datagrams that are due at the same time in the order they were queued
+    order: u64,
+    addr: SocketAddr,
+    data: Vec<u8>,
+}
+
+impl Delayed {
+    fn new(due: Instant, order: u64, addr: SocketAddr, data: Vec<u8>) -> Delayed {
+        Delayed { due, order, addr, data }
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Delayed) -> Ordering {
+        (other.due, other.order).cmp(&(self.due, self.order))
+    }
+}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Delayed) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Direction, LinkConditioner};
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:12345".parse().unwrap()
+    }
+
+    fn drain(conditioner: &mut LinkConditioner, direction: Direction, now: Instant) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        while let Some((_, data)) = conditioner.pop(direction, now) {
+            datagrams.push(data);
+        }
+        datagrams
+    }
+
+    #[test]
+    fn passes_datagrams_through_by_default() {
+        let mut conditioner = LinkConditioner::new(0);
+        let now = Instant::now();
+        for i in 0..10 {
+            conditioner.push(Direction::Outgoing, addr(), vec![i], now);
+        }
+
+        assert_eq!(drain(&mut conditioner, Direction::Outgoing, now), (0..10).map(|i| vec![i]).collect::<Vec<_>>());
+        assert_eq!(conditioner.pop(Direction::Incoming, now), None);
+    }
+
+    #[test]
+    fn delays_datagrams_by_the_latency() {
+        let mut conditioner = LinkConditioner::new(0).with_latency(Duration::from_millis(200));
+        let now = Instant::now();
+        conditioner.push(Direction::Incoming, addr(), vec![1], now);
+
+        assert_eq!(conditioner.pop(Direction::Incoming, now + Duration::from_millis(199)), None);
+        assert_eq!(conditioner.next_due(), Some(now + Duration::from_millis(200)));
+        assert_eq!(conditioner.pop(Direction::Incoming, now + Duration::from_millis(200)), Some((addr(), vec![1])));
+        assert_eq!(conditioner.next_due(), None);
+    }
+
+    #[test]
+    fn drops_and_duplicates_datagrams() {
+        let now = Instant::now();
+
+        let mut conditioner = LinkConditioner::new(0).with_packet_loss(1.0);
+        conditioner.push(Direction::Outgoing, addr(), vec![1], now);
+        assert!(drain(&mut conditioner, Direction::Outgoing, now).is_empty());
+
+        let mut conditioner = LinkConditioner::new(0).with_duplication(1.0);
+        conditioner.push(Direction::Outgoing, addr(), vec![1], now);
+        assert_eq!(drain(&mut conditioner, Direction::Outgoing, now), vec![vec![1], vec![1]]);
+    }
+
+    #[test]
+    fn jitter_reorders_datagrams() {
+        let mut conditioner = LinkConditioner::new(7)
+            .with_latency(Duration::from_millis(50))
+            .with_jitter(Duration::from_millis(50));
+        let now = Instant::now();
+        for i in 0..100 {
+            conditioner.push(Direction::Outgoing, addr(), vec![i], now);
+        }
+
+        let received = drain(&mut conditioner, Direction::Outgoing, now + Duration::from_millis(100));
+        assert_eq!(received.len(), 100);
+        assert!(received.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn same_seed_gives_same_conditions() {
+        let run = |seed| {
+            let mut conditioner = LinkConditioner::new(seed)
+                .with_packet_loss(0.3)
+                .with_duplication(0.1)
+                .with_jitter(Duration::from_millis(20));
+            let now = Instant::now();
+            for i in 0..200 {
+                conditioner.push(Direction::Outgoing, addr(), vec![i], now);
+            }
+            drain(&mut conditioner, Direction::Outgoing, now + Duration::from_secs(1))
+        };
+
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
+}
@@ -1,4 +1,6 @@
+use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use Packet;
 
 /// Packets waiting for an ack
@@ -8,14 +10,20 @@ use Packet;
 /// Additionally, holds packets "forward" of the current ack packet
 #[derive(Debug)]
 pub struct LocalAckRecord {
-    // packets waiting for acknowledgement.
-    packets: HashMap<u16, Packet>,
+    // packets waiting for acknowledgement, with the time they were sent.
+    packets: HashMap<u16, (Packet, Instant)>,
+    // Round trip time of the packet acked last by its own sequence number
+    rtt: Option<Duration>,
+    // Sequence numbers acked since the last `take_acked`
+    acked: Vec<u16>,
 }
 
 impl LocalAckRecord {
     pub fn new() -> LocalAckRecord {
         LocalAckRecord {
             packets: HashMap::new(),
+            rtt: None,
+            acked: Vec::new(),
         }
     }
 
@@ -33,7 +41,17 @@ impl LocalAckRecord {
     pub fn enqueue(&mut self, seq: u16, packet: Packet) {
         // TODO: Handle overwriting other packet?
         //   That really shouldn't happen, but it should be encoded here
-        self.packets.insert(seq, packet);
+        self.packets.insert(seq, (packet, Instant::now()));
+    }
+
+    /// Returns the round trip time measured by the last `ack`, if it acked a packet by its own sequence number.
+    pub fn take_rtt(&mut self) -> Option<Duration> {
+        self.rtt.take()
+    }
+
+    /// Returns the sequence numbers of the packets acked since the last call, in the order they were acked.
+    pub fn take_acked(&mut self) -> Vec<u16> {
+        std::mem::take(&mut self.acked)
     }
 
     /// Finds and removes acked packets, returning dropped packets
@@ -42,12 +60,12 @@ impl LocalAckRecord {
         let mut dropped_packets = Vec::new();
         let mut acked_packets = Vec::new();
 
-        for key in self.packets.keys().into_iter() {
+        for key in self.packets.keys() {
             let diff = seq.wrapping_sub(*key);
             if diff == 0 {
                 acked_packets.push(*key);
             } else if diff <= 32 {
-                let field_acked = (seq_field & (1 << diff - 1) != 0);
+                let field_acked = (seq_field & (1 << (diff - 1)) != 0);
                 if field_acked {
                     acked_packets.push(*key);
                 }
@@ -56,13 +74,20 @@ impl LocalAckRecord {
             }
         }
 
+        // Oldest first, the keys come in no particular order
+        acked_packets.sort_by_key(|key| Reverse(seq.wrapping_sub(*key)));
         for seq_number in acked_packets.iter() {
-            self.packets.remove(seq_number);
+            self.acked.push(*seq_number);
+            let acked = self.packets.remove(seq_number);
+            // Packets acked through the field were acked late, only the newest one gives an accurate round trip time
+            if *seq_number == seq {
+                self.rtt = acked.map(|(_, sent_at)| sent_at.elapsed());
+            }
         }
 
         dropped_packets
             .into_iter()
-            .map(|seq| (seq, self.packets.remove(&seq).unwrap()))
+            .map(|seq| (seq, self.packets.remove(&seq).unwrap().0))
             .collect()
     }
 }
@@ -93,6 +118,18 @@ mod test {
         assert!(record.is_empty());
     }
 
+    #[test]
+    fn takes_acked_sequence_numbers_oldest_first() {
+        let mut record = LocalAckRecord::new();
+        for i in 0..4_u16 {
+            record.enqueue(i.wrapping_sub(2), dummy_packet());
+        }
+        record.ack(1, 0b101);
+        assert_eq!(record.take_acked(), vec![65534, 0, 1]);
+        assert!(record.take_acked().is_empty());
+        assert_eq!(record.len(), 1);
+    }
+
     #[test]
     fn acking_a_full_set_of_packets() {
         let mut record = LocalAckRecord::new();
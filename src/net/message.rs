@@ -0,0 +1,131 @@
+use std::io;
+use std::net::SocketAddr;
+
+/// Tells messages apart, like the port of a socket. Messages on every channel are delivered the same way.
+pub type Channel = u8;
+
+// First byte of the payload of a packet with messages, so packets sent with `UdpSocket::send` fail to unpack instead of giving garbage
+const MESSAGES_TAG: u8 = 0xa7;
+// Channel and length of a message
+const MESSAGE_HEADER_SIZE: usize = 1 + 2;
+
+/// A message received with `UdpSocket::recv_message`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message<T> {
+    pub addr: SocketAddr,
+    pub channel: Channel,
+    pub message: T,
+}
+
+/// Packs serialized messages into as few packet payloads of at most `max_size` bytes as it can, keeping their order.
+///
+/// A message that does not fit in `max_size` on its own gets a payload of its own.
+pub fn pack<M: AsRef<[u8]>>(messages: &[(Channel, M)], max_size: usize) -> io::Result<Vec<Vec<u8>>> {
+    if messages.iter().any(|(_, message)| message.as_ref().len() > usize::from(u16::MAX)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the message is too big to be sent over UDP"));
+    }
+
+    let mut payloads = Vec::new();
+    let mut rest = messages;
+    while !rest.is_empty() {
+        let count = fit(rest.iter().map(|(_, message)| message.as_ref().len()), max_size);
+        let mut payload = vec![MESSAGES_TAG];
+        for (channel, message) in &rest[..count] {
+            let message = message.as_ref();
+            payload.push(*channel);
+            payload.extend_from_slice(&(message.len() as u16).to_le_bytes());
+            payload.extend_from_slice(message);
+        }
+        payloads.push(payload);
+        rest = &rest[count..];
+    }
+    Ok(payloads)
+}
+
+/// Returns how many of the messages with the lengths `lens` `pack` puts in the first payload, which is at least one.
+pub fn fit<I: IntoIterator<Item = usize>>(lens: I, max_size: usize) -> usize {
+    let mut size = 1;
+    let mut count = 0;
+    for len in lens {
+        if count > 0 && size + MESSAGE_HEADER_SIZE + len > max_size {
+            break;
+        }
+        size += MESSAGE_HEADER_SIZE + len;
+        count += 1;
+    }
+    count
+}
+
+/// Returns the size of a payload with a single message of `len` bytes.
+pub fn packed_size(len: usize) -> usize {
+    1 + MESSAGE_HEADER_SIZE + len
+}
+
+/// Takes the serialized messages out of a packet payload made by `pack`.
+pub fn unpack(payload: &[u8]) -> io::Result<Vec<(Channel, Vec<u8>)>> {
+    if payload.first() != Some(&MESSAGES_TAG) {
+        return Err(invalid_data("the packet does not carry messages"));
+    }
+    // `pack` never makes a payload without messages
+    if payload.len() == 1 {
+        return Err(invalid_data("the packet carries no messages"));
+    }
+
+    let mut messages = Vec::new();
+    let mut rest = &payload[1..];
+    while !rest.is_empty() {
+        if rest.len() < MESSAGE_HEADER_SIZE {
+            return Err(invalid_data("truncated message header"));
+        }
+        let channel = rest[0];
+        let len = usize::from(u16::from_le_bytes([rest[1], rest[2]]));
+        let end = MESSAGE_HEADER_SIZE + len;
+        if rest.len() < end {
+            return Err(invalid_data("truncated message"));
+        }
+        messages.push((channel, rest[MESSAGE_HEADER_SIZE..end].to_vec()));
+        rest = &rest[end..];
+    }
+    Ok(messages)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pack, unpack, MESSAGES_TAG, MESSAGE_HEADER_SIZE};
+
+    #[test]
+    fn packs_messages_up_to_the_size() {
+        let messages: Vec<_> = (0..10_u8).map(|i| (i % 3, vec![i; 97])).collect();
+
+        // Tag plus five messages of 100 bytes each
+        let payloads = pack(&messages, 501).unwrap();
+        assert_eq!(payloads.len(), 2);
+        assert!(payloads.iter().all(|payload| payload.len() == 501));
+
+        let unpacked: Vec<_> = payloads.iter().flat_map(|payload| unpack(payload).unwrap()).collect();
+        assert_eq!(unpacked, messages);
+    }
+
+    #[test]
+    fn big_messages_get_a_payload_of_their_own() {
+        let messages = vec![(0, vec![1; 10]), (1, vec![2; 1000]), (2, vec![3; 10])];
+        let payloads = pack(&messages, 100).unwrap();
+        assert_eq!(payloads.iter().map(|payload| payload.len()).collect::<Vec<_>>(), vec![1 + MESSAGE_HEADER_SIZE + 10, 1 + MESSAGE_HEADER_SIZE + 1000, 1 + MESSAGE_HEADER_SIZE + 10]);
+        assert!(pack(&[(0, vec![0; 70_000])], 100).is_err());
+    }
+
+    #[test]
+    fn rejects_other_payloads() {
+        assert!(unpack(&[1, 2, 3]).is_err());
+        assert!(unpack(&[]).is_err());
+        assert!(unpack(&[MESSAGES_TAG]).is_err());
+
+        let mut payload = pack(&[(4, vec![1, 2, 3])], 100).unwrap().remove(0);
+        payload.pop();
+        assert!(unpack(&payload).is_err());
+    }
+}
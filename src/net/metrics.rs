@@ -0,0 +1,378 @@
+#[cfg(feature = "prometheus")]
+use std::fmt::Write as FmtWrite;
+#[cfg(feature = "prometheus")]
+use std::io::{self, BufRead, BufReader, Read, Write};
+#[cfg(feature = "prometheus")]
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(feature = "prometheus")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "prometheus")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "prometheus")]
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[cfg(feature = "prometheus")]
+use super::{TcpStats, UdpStats};
+
+/// Maximum number of bytes read from a scrape request, headers included.
+#[cfg(feature = "prometheus")]
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+
+/// Upper bounds of the round trip time buckets, in milliseconds.
+pub const RTT_BUCKETS_MS: [u64; 9] = [1, 5, 10, 25, 50, 100, 250, 500, 1000];
+
+/// Histogram of measured round trip times.
+///
+/// Every bucket counts the round trip times up to its bound in `RTT_BUCKETS_MS` that did not fit in the bucket before it; the last bucket counts everything slower.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RttHistogram {
+    pub buckets: [usize; 10],
+    /// Number of round trip times measured.
+    pub count: usize,
+    /// Sum of all measured round trip times, in microseconds.
+    pub sum_micros: u64,
+}
+
+impl RttHistogram {
+    pub fn record(&mut self, rtt: Duration) {
+        let millis = rtt.as_millis() as u64;
+        let bucket = RTT_BUCKETS_MS.iter().position(|&bound| millis <= bound).unwrap_or(RTT_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_micros += rtt.as_micros() as u64;
+    }
+
+    /// Returns the average round trip time, if any was measured.
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(Duration::from_micros(self.sum_micros / self.count as u64))
+        }
+    }
+}
+
+/// Renders stats in the Prometheus text format.
+///
+/// Add the stats of every socket and server under a name of their own, it becomes the `socket` label. Samples of the same metric are grouped, as Prometheus expects.
+#[cfg(feature = "prometheus")]
+#[derive(Debug, Default)]
+pub struct Prometheus {
+    families: Vec<Family>,
+}
+
+#[cfg(feature = "prometheus")]
+#[derive(Debug)]
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    samples: Vec<String>,
+}
+
+#[cfg(feature = "prometheus")]
+impl Prometheus {
+    pub fn new() -> Prometheus {
+        Prometheus::default()
+    }
+
+    /// Adds the stats of a UDP socket.
+    pub fn udp(&mut self, socket: &str, stats: &UdpStats) -> &mut Prometheus {
+        let labels = format!("socket=\"{}\"", escape(socket));
+        let counters = [
+            ("amethyst_udp_bytes_sent_total", "Bytes sent in datagrams.", stats.bytes_sent),
+            ("amethyst_udp_bytes_received_total", "Bytes received in datagrams.", stats.bytes_received),
+            ("amethyst_udp_packets_sent_total", "Packets sent.", stats.sent_packets),
+            ("amethyst_udp_packets_received_total", "Packets delivered.", stats.received_packets),
+            ("amethyst_udp_packets_lost_total", "Packets the other side never acknowledged.", stats.lost_packets),
+            ("amethyst_udp_packets_duplicate_total", "Packets dropped because they were received before.", stats.duplicate_packets),
+            ("amethyst_udp_packets_malformed_total", "Datagrams that could not be decrypted, decompressed or deserialized.", stats.malformed_packets),
+            ("amethyst_udp_packets_deferred_total", "Times a packet was held back for the bandwidth budget.", stats.deferred_packets),
+            ("amethyst_udp_packets_dropped_low_priority_total", "Low priority packets dropped for the bandwidth budget.", stats.dropped_low_priority_packets),
+            ("amethyst_udp_packets_banned_total", "Datagrams dropped because their address is banned.", stats.banned_packets),
+            ("amethyst_udp_packets_rate_limited_total", "Datagrams dropped for the packet rate.", stats.rate_limited_packets),
+            ("amethyst_udp_connections_rate_limited_total", "Connection attempts dropped for the connection rate.", stats.rate_limited_connections),
+            ("amethyst_udp_connections_rejected_total", "Connection attempts dropped for the connection limit.", stats.rejected_connection_limit),
+            ("amethyst_udp_connections_timed_out_total", "Connections removed because nothing was heard from them.", stats.timed_out_connections),
+        ];
+        for &(name, help, value) in counters.iter() {
+            self.sample(name, help, "counter", format!("{}{{{}}} {}", name, labels, value));
+        }
+        self.sample(
+            "amethyst_udp_connections",
+            "Connections right now.",
+            "gauge",
+            format!("amethyst_udp_connections{{{}}} {}", labels, stats.active_connections),
+        );
+
+        // Histogram buckets are cumulative
+        let name = "amethyst_udp_rtt_seconds";
+        let help = "Round trip time of acknowledged packets.";
+        let mut cumulative = 0;
+        for (i, &count) in stats.rtt.buckets.iter().enumerate() {
+            cumulative += count;
+            let bound = match RTT_BUCKETS_MS.get(i) {
+                Some(&ms) => format!("{}", ms as f64 / 1000.0),
+                None => "+Inf".to_string(),
+            };
+            self.sample(name, help, "histogram", format!("{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative));
+        }
+        self.sample(name, help, "histogram", format!("{}_sum{{{}}} {}", name, labels, stats.rtt.sum_micros as f64 / 1_000_000.0));
+        self.sample(name, help, "histogram", format!("{}_count{{{}}} {}", name, labels, stats.rtt.count));
+        self
+    }
+
+    /// Adds the stats of a TCP server.
+    pub fn tcp(&mut self, socket: &str, stats: &TcpStats) -> &mut Prometheus {
+        let labels = format!("socket=\"{}\"", escape(socket));
+        let counters = [
+            ("amethyst_tcp_bytes_sent_total", "Bytes written to client streams.", stats.bytes_sent),
+            ("amethyst_tcp_bytes_received_total", "Bytes read from client streams.", stats.bytes_received),
+            ("amethyst_tcp_messages_sent_total", "Messages written to client streams.", stats.messages_sent),
+            ("amethyst_tcp_messages_received_total", "Messages read from client streams.", stats.messages_received),
+            ("amethyst_tcp_accepted_total", "Streams accepted.", stats.accepted),
+            ("amethyst_tcp_rejected_total", "Streams rejected by the accept policy.", stats.rejected()),
+            ("amethyst_tcp_timeouts_total", "Clients disconnected by the read timeout or the heartbeat.", stats.timeouts),
+            ("amethyst_tcp_frames_malformed_total", "Clients disconnected for an invalid frame.", stats.malformed_frames),
+        ];
+        for &(name, help, value) in counters.iter() {
+            self.sample(name, help, "counter", format!("{}{{{}}} {}", name, labels, value));
+        }
+        self.sample(
+            "amethyst_tcp_connections",
+            "Clients connected right now.",
+            "gauge",
+            format!("amethyst_tcp_connections{{{}}} {}", labels, stats.active_connections),
+        );
+        self
+    }
+
+    /// Returns the text to serve.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for family in &self.families {
+            let _ = writeln!(output, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(output, "# TYPE {} {}", family.name, family.kind);
+            for sample in &family.samples {
+                let _ = writeln!(output, "{}", sample);
+            }
+        }
+        output
+    }
+
+    fn sample(&mut self, name: &'static str, help: &'static str, kind: &'static str, sample: String) {
+        match self.families.iter_mut().find(|family| family.name == name) {
+            Some(family) => family.samples.push(sample),
+            None => self.families.push(Family {
+                name,
+                help,
+                kind,
+                samples: vec![sample],
+            }),
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves the text of a `Prometheus` renderer over HTTP on a thread of its own, for Prometheus to scrape.
+///
+/// It answers every request with the text given to `update` last. Dropping the server stops the thread.
+#[cfg(feature = "prometheus")]
+pub struct MetricsServer {
+    local_addr: SocketAddr,
+    text: Arc<Mutex<String>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(feature = "prometheus")]
+impl MetricsServer {
+    pub fn start<A: ToSocketAddrs>(addr: A) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let text = Arc::new(Mutex::new(String::new()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread_text = text.clone();
+        let thread_running = running.clone();
+        let thread = thread::Builder::new()
+            .name("metrics_server".into())
+            .spawn(move || serve(listener, thread_text, thread_running))?;
+
+        Ok(MetricsServer {
+            local_addr,
+            text,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    /// Replaces the text that is served, usually with `Prometheus::render`.
+    pub fn update(&self, text: String) {
+        if let Ok(mut served) = self.text.lock() {
+            *served = text;
+        }
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops the server and waits for its thread to exit.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+
+        // `accept` can not be interrupted, so we wake the thread up with a connection of our own
+        let mut wake_addr = self.local_addr;
+        if wake_addr.ip().is_unspecified() {
+            wake_addr.set_ip(match wake_addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(wake_addr);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn serve(listener: TcpListener, text: Arc<Mutex<String>>, running: Arc<AtomicBool>) {
+    for stream in listener.incoming() {
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+
+        // Scrapes are rare and small, answering them one at a time is enough
+        if let Err(e) = stream.and_then(|stream| respond(stream, &text)) {
+            debug!("Error serving metrics: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn respond(mut stream: TcpStream, text: &Mutex<String>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // Only the request line matters, the headers are read so the client is not reset
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = text.lock().map(|text| text.clone()).unwrap_or_default();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::RttHistogram;
+    #[cfg(feature = "prometheus")]
+    use super::{MetricsServer, Prometheus, MAX_REQUEST_SIZE};
+    #[cfg(feature = "prometheus")]
+    use net::{TcpStats, UdpStats};
+    #[cfg(feature = "prometheus")]
+    use std::io::{Read, Write};
+    #[cfg(feature = "prometheus")]
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    #[test]
+    fn histogram_sorts_into_buckets() {
+        let mut histogram = RttHistogram::default();
+        histogram.record(Duration::from_micros(500));
+        histogram.record(Duration::from_millis(30));
+        histogram.record(Duration::from_secs(3));
+
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[4], 1);
+        assert_eq!(histogram.buckets[9], 1);
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(1_010_166)));
+    }
+
+    #[test]
+    #[cfg(feature = "prometheus")]
+    fn renders_prometheus_text() {
+        let mut udp = UdpStats {
+            bytes_sent: 1200,
+            active_connections: 3,
+            ..UdpStats::default()
+        };
+        udp.rtt.record(Duration::from_millis(20));
+        let tcp = TcpStats::default();
+
+        let text = Prometheus::new().udp("game", &udp).udp("voice \"eu\"", &UdpStats::default()).tcp("lobby", &tcp).render();
+
+        assert!(text.contains("# TYPE amethyst_udp_bytes_sent_total counter\namethyst_udp_bytes_sent_total{socket=\"game\"} 1200\namethyst_udp_bytes_sent_total{socket=\"voice \\\"eu\\\"\"} 0\n"));
+        assert!(text.contains("amethyst_udp_connections{socket=\"game\"} 3\n"));
+        assert!(text.contains("amethyst_udp_rtt_seconds_bucket{socket=\"game\",le=\"0.01\"} 0\n"));
+        assert!(text.contains("amethyst_udp_rtt_seconds_bucket{socket=\"game\",le=\"0.025\"} 1\n"));
+        assert!(text.contains("amethyst_udp_rtt_seconds_bucket{socket=\"game\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("amethyst_udp_rtt_seconds_count{socket=\"game\"} 1\n"));
+        assert!(text.contains("amethyst_tcp_connections{socket=\"lobby\"} 0\n"));
+        assert_eq!(text.matches("# TYPE amethyst_udp_rtt_seconds histogram").count(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "prometheus")]
+    fn serves_metrics_over_http() {
+        let server = MetricsServer::start("127.0.0.1:27017").unwrap();
+        server.update(Prometheus::new().tcp("lobby", &TcpStats::default()).render());
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("amethyst_tcp_connections{socket=\"lobby\"} 0\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    #[cfg(feature = "prometheus")]
+    fn answers_oversized_requests() {
+        let server = MetricsServer::start("127.0.0.1:27018").unwrap();
+
+        // A request line that never ends must not keep the server reading
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(&[b'a'; MAX_REQUEST_SIZE as usize]).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
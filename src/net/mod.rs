@@ -1,13 +1,62 @@
+#[cfg(feature = "tokio")]
+mod async_tcp;
+#[cfg(feature = "tokio")]
+mod async_udp;
+mod bandwidth;
+mod capture;
+mod clock_sync;
+mod compression;
+mod connect_token;
+mod encryption;
 mod external_ack;
+mod flood_protection;
+mod frame;
+mod link_conditioner;
 mod local_ack;
+mod message;
+mod metrics;
+mod path_mtu;
+mod protocol;
+#[cfg(feature = "mio")]
+mod reactor;
+mod replay_buffer;
+mod rpc;
 mod socket_state;
+mod worker;
 pub mod connection;
+pub mod dump;
+pub mod endpoint;
+pub mod snapshot;
 pub mod udp;
 pub mod tcp;
+#[cfg(feature = "tokio")]
+pub use self::async_tcp::{AsyncTcpListener, AsyncTcpStream};
+#[cfg(feature = "tokio")]
+pub use self::async_udp::AsyncUdpSocket;
+pub use self::bandwidth::Priority;
+pub use self::capture::{Capture, CaptureFormat, CapturedDatagram, Replay};
+pub use self::clock_sync::{ClockSample, ClockSync};
+pub use self::compression::Compression;
+pub use self::connect_token::{ConnectToken, ConnectedClient};
 pub use self::connection::{Connection, Quality};
+pub use self::message::{Channel, Message};
+pub use self::metrics::RttHistogram;
+pub use self::rpc::{MethodId, RequestId, Rpc, RpcError, RpcTransport};
+pub use self::snapshot::{Diff, SnapshotBuffer};
+#[cfg(feature = "prometheus")]
+pub use self::metrics::{MetricsServer, Prometheus};
 use self::external_ack::ExternalAcks;
 use self::local_ack::LocalAckRecord;
+use self::path_mtu::PathMtu;
+use self::replay_buffer::ReplayBuffer;
 use self::socket_state::SocketState;
-use super::{Packet, RawPacket};
+use super::{Packet, PacketHandle, PacketType, RawPacket, RAW_PACKET_HEADER_SIZE};
 use std::net::SocketAddr;
-pub use self::udp::UdpSocket;
+pub use self::udp::{UdpSocket, UdpStats};
+pub use self::worker::Worker;
+#[cfg(feature = "mio")]
+pub use self::reactor::Reactor;
+pub use self::flood_protection::{Dropped, FloodProtection};
+pub use self::link_conditioner::{Direction, LinkConditioner};
+pub use self::endpoint::{Endpoint, PeerId, Transport};
+pub use self::tcp::{TcpSocketState, TcpStats};
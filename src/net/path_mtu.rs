@@ -0,0 +1,185 @@
+use std::time::{Duration, Instant};
+
+/// Datagram size every path is assumed to carry: the 576 byte IPv4 minimum without the IP and UDP headers.
+pub const MIN_DATAGRAM_SIZE: usize = 508;
+/// Biggest datagram size we probe for: an Ethernet MTU of 1500 without the IPv4 and UDP headers.
+pub const MAX_DATAGRAM_SIZE: usize = 1472;
+
+// How long we wait for a probe to be acked before counting it as lost
+const PROBE_TIMEOUT_MS: u64 = 1000;
+// A size is only given up on after this many probes of it were lost, a single loss may have another cause
+const PROBE_ATTEMPTS: u8 = 2;
+// Probes and probe acks received per connection that do not count against flood protection, far more than a search needs
+const FREE_PROBE_PACKETS: u8 = 64;
+
+/// Discovers the biggest datagram the path to the other side carries.
+///
+/// We send probe packets padded to a size and wait for them to be acked. An acked probe confirms its size, a probe that is not acked in time counts as lost.
+/// The first probe tries the biggest size straight away, since that is what most paths carry, after that we binary search between the confirmed size and the smallest size that failed.
+/// Only one probe is in flight at a time.
+#[derive(Debug, Clone)]
+pub struct PathMtu {
+    confirmed: usize,
+    // The smallest size that failed, everything from here up is assumed to fail
+    ceiling: usize,
+    in_flight: Option<Probe>,
+    failures: u8,
+    free_packets: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Probe {
+    seq: u16,
+    size: usize,
+    sent_at: Instant,
+}
+
+impl PathMtu {
+    pub fn new() -> PathMtu {
+        PathMtu {
+            confirmed: MIN_DATAGRAM_SIZE,
+            ceiling: MAX_DATAGRAM_SIZE + 1,
+            in_flight: None,
+            failures: 0,
+            free_packets: FREE_PROBE_PACKETS,
+        }
+    }
+
+    /// The biggest datagram size that is confirmed to reach the other side.
+    pub fn datagram_size(&self) -> usize {
+        self.confirmed
+    }
+
+    /// Returns true once there is nothing left to probe.
+    pub fn is_done(&self) -> bool {
+        self.ceiling - self.confirmed <= 1
+    }
+
+    /// Returns the size of the next probe to send, if one should be sent now.
+    pub fn next_probe(&mut self, now: Instant) -> Option<usize> {
+        if let Some(probe) = self.in_flight {
+            if now.duration_since(probe.sent_at) < Duration::from_millis(PROBE_TIMEOUT_MS) {
+                return None;
+            }
+            self.lost();
+        }
+
+        if self.is_done() {
+            return None;
+        }
+
+        if self.ceiling == MAX_DATAGRAM_SIZE + 1 && self.failures == 0 {
+            Some(MAX_DATAGRAM_SIZE)
+        } else {
+            Some(self.confirmed + (self.ceiling - self.confirmed) / 2)
+        }
+    }
+
+    /// Records that a probe of `size` bytes was sent with sequence number `seq`.
+    pub fn probe_sent(&mut self, seq: u16, size: usize, now: Instant) {
+        self.in_flight = Some(Probe { seq, size, sent_at: now });
+    }
+
+    /// Records that a probe could not be sent at all, the local interface does not take it.
+    pub fn probe_failed(&mut self) {
+        if let Some(probe) = self.in_flight.take() {
+            self.ceiling = self.ceiling.min(probe.size);
+            self.failures = 0;
+        }
+    }
+
+    /// Returns true if a probe or probe ack that was received should not count against flood protection, which is only the case for the first few.
+    pub fn take_free_packet(&mut self) -> bool {
+        if self.free_packets == 0 {
+            return false;
+        }
+        self.free_packets -= 1;
+        true
+    }
+
+    /// Checks if the acks from the other side confirm the probe in flight.
+    pub fn ack(&mut self, ack_seq: u16, ack_field: u32) {
+        let probe = match self.in_flight {
+            Some(probe) => probe,
+            None => return,
+        };
+
+        let diff = ack_seq.wrapping_sub(probe.seq);
+        let acked = diff == 0 || (diff <= 32 && ack_field & (1 << (diff - 1)) != 0);
+        if acked {
+            self.confirmed = self.confirmed.max(probe.size);
+            self.in_flight = None;
+            self.failures = 0;
+        }
+    }
+
+    fn lost(&mut self) {
+        if let Some(probe) = self.in_flight.take() {
+            self.failures += 1;
+            if self.failures >= PROBE_ATTEMPTS || probe.size == MAX_DATAGRAM_SIZE {
+                self.ceiling = self.ceiling.min(probe.size);
+                self.failures = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PathMtu, MAX_DATAGRAM_SIZE, MIN_DATAGRAM_SIZE, PROBE_TIMEOUT_MS};
+    use std::time::{Duration, Instant};
+
+    // Probes until done, acking every probe that fits in `path`
+    fn discover(path: usize) -> (PathMtu, usize) {
+        let mut mtu = PathMtu::new();
+        let mut now = Instant::now();
+        let mut probes = 0;
+
+        for seq in 0..100 {
+            now += Duration::from_millis(PROBE_TIMEOUT_MS);
+            if let Some(size) = mtu.next_probe(now) {
+                probes += 1;
+                mtu.probe_sent(seq, size, now);
+                if size <= path {
+                    mtu.ack(seq + 2, 0b10);
+                }
+            }
+        }
+        (mtu, probes)
+    }
+
+    #[test]
+    fn confirms_the_biggest_size_on_a_good_path() {
+        let (mtu, probes) = discover(MAX_DATAGRAM_SIZE);
+        assert_eq!(mtu.datagram_size(), MAX_DATAGRAM_SIZE);
+        assert!(mtu.is_done());
+        assert_eq!(probes, 1);
+    }
+
+    #[test]
+    fn finds_the_size_of_a_smaller_path() {
+        // A VPN with an MTU of 1400
+        let (mtu, _) = discover(1400 - 28);
+        assert_eq!(mtu.datagram_size(), 1400 - 28);
+        assert!(mtu.is_done());
+
+        let (mtu, _) = discover(MIN_DATAGRAM_SIZE);
+        assert_eq!(mtu.datagram_size(), MIN_DATAGRAM_SIZE);
+    }
+
+    #[test]
+    fn waits_for_the_probe_in_flight() {
+        let mut mtu = PathMtu::new();
+        let now = Instant::now();
+        let size = mtu.next_probe(now).unwrap();
+        mtu.probe_sent(0, size, now);
+        assert_eq!(mtu.next_probe(now + Duration::from_millis(10)), None);
+
+        // A lost probe of a smaller size is tried again before giving up on it
+        mtu.probe_failed();
+        let size = mtu.next_probe(now).unwrap();
+        mtu.probe_sent(1, size, now);
+        let later = now + Duration::from_millis(PROBE_TIMEOUT_MS);
+        assert_eq!(mtu.next_probe(later), Some(size));
+    }
+}
@@ -0,0 +1,246 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+use super::compression::Compression;
+use super::connect_token::ConnectedClient;
+use super::encryption::{Encryption, Opened};
+use super::flood_protection::FloodProtection;
+use super::{compression, encryption, Packet, PacketHandle, PacketType, RawPacket, SocketState, UdpStats, RAW_PACKET_HEADER_SIZE};
+use bincode::deserialize;
+
+use error::Result;
+
+/// Everything between the socket and the application that does not depend on how datagrams are sent and received.
+///
+/// Packets go through the reliability core in `SocketState`, then compression and encryption, and come out as datagrams.
+/// Received datagrams go through flood protection, encryption, compression and `SocketState`.
+/// The blocking and the async `UdpSocket` only move the datagrams.
+pub struct Protocol {
+    pub state: SocketState,
+    pub encryption: Option<Encryption>,
+    pub compression: Option<Compression>,
+    pub flood_protection: Option<FloodProtection>,
+    /// Sends clock sync pings, answering them does not need this.
+    pub clock_sync: bool,
+}
+
+/// What came out of a received datagram.
+#[derive(Debug)]
+pub struct Received {
+    /// The packet for the application, if the datagram carried one.
+    pub packet: Option<Packet>,
+    /// Datagrams that have to be sent back to the address the datagram came from.
+    pub replies: Vec<Vec<u8>>,
+}
+
+/// A datagram waiting to be sent by a non-blocking socket.
+#[cfg(any(feature = "tokio", feature = "mio"))]
+#[derive(Debug)]
+pub struct Datagram {
+    pub addr: SocketAddr,
+    pub data: Vec<u8>,
+    /// Path MTU probes may be too big for the local interface, failing to send them is not an error.
+    pub probe: bool,
+}
+
+impl Protocol {
+    pub fn new(state: SocketState) -> Protocol {
+        Protocol {
+            state,
+            encryption: None,
+            compression: None,
+            flood_protection: None,
+            clock_sync: false,
+        }
+    }
+
+    /// Turns a packet into the datagrams to send to its address.
+    pub fn send(&mut self, packet: Packet) -> Result<(SocketAddr, Vec<Vec<u8>>)> {
+        self.check_can_send(packet.addr())?;
+        let (addr, payload) = self.state.pre_process_packet(packet)?;
+        Ok((addr, self.seal(addr, payload)?))
+    }
+
+    /// Like `send`, and emits a `DeliveryEvent` for each of `handles` once the packet is acked or lost.
+    pub fn send_tracked(&mut self, packet: Packet, handles: Vec<PacketHandle>) -> Result<(SocketAddr, Vec<Vec<u8>>)> {
+        let seq = self.state.next_seq(packet.addr());
+        let (addr, datagrams) = self.send(packet)?;
+        self.state.track(addr, seq, handles)?;
+        Ok((addr, datagrams))
+    }
+
+    /// Returns the datagrams of a path MTU probe to `addr`, if one is due.
+    pub fn probe(&mut self, addr: SocketAddr) -> Result<Vec<Vec<u8>>> {
+        let overhead = self.overhead();
+        match self.state.pre_process_probe(addr, overhead, Instant::now())? {
+            Some(probe) => self.seal(addr, probe),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Returns the datagrams of a clock sync ping to `addr`, if clock sync is on and one is due.
+    pub fn time_ping(&mut self, addr: SocketAddr) -> Result<Vec<Vec<u8>>> {
+        if !self.clock_sync {
+            return Ok(Vec::new());
+        }
+        match self.state.pre_process_time_ping(addr)? {
+            Some(ping) => self.seal(addr, ping),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Records that the probe to `addr` could not be sent, most likely because the local interface does not take datagrams that big.
+    pub fn probe_failed(&mut self, addr: SocketAddr) -> Result<()> {
+        self.state.probe_failed(addr)
+    }
+
+    /// Handles a datagram received from `addr`.
+    pub fn receive(&mut self, addr: SocketAddr, data: Vec<u8>) -> io::Result<Received> {
+        let mut received = Received {
+            packet: None,
+            replies: Vec::new(),
+        };
+        self.state.record_received(data.len());
+
+        if let Some(ref mut flood_protection) = self.flood_protection {
+            let known = self.state.has_connection(addr);
+            if let Err(dropped) = flood_protection.check(addr.ip(), known, self.state.connection_count(), Instant::now()) {
+                self.state.record_dropped(dropped);
+                return Ok(received);
+            }
+        }
+
+        let data = match self.encryption {
+            Some(ref mut encryption) => match encryption.open(addr, &data, Instant::now()) {
+                Opened::Payload(payload) => payload,
+                Opened::Send(datagrams) => {
+                    for peer in encryption.take_new_peers() {
+                        self.state.reset_received(peer);
+                    }
+                    for datagram in &datagrams {
+                        self.state.record_sent(datagram.len());
+                    }
+                    received.replies = datagrams;
+                    return Ok(received);
+                }
+                Opened::Rejected(reason) => {
+                    self.state.record_malformed();
+                    debug!("Ignoring datagram from {}: {}", addr, reason);
+                    return Ok(received);
+                }
+            },
+            None => data,
+        };
+
+        let raw_packet = match read_raw_packet(data, self.compression.as_ref()) {
+            Ok(raw_packet) => raw_packet,
+            Err(e) => {
+                self.state.record_malformed();
+                return Err(e);
+            }
+        };
+        received.packet = self.state.process_received(addr, &raw_packet).map_err(other)?;
+
+        // Path MTU discovery is not the application's traffic, so it does not use up its packet rate
+        if let Some(ref mut flood_protection) = self.flood_protection {
+            if self.state.is_free_packet(addr, raw_packet.packet_type) {
+                flood_protection.refund(addr.ip());
+            }
+        }
+
+        // Answer probes right away, so their ack does not depend on the application sending something
+        if raw_packet.packet_type == PacketType::MtuProbe {
+            let ack = self.state.pre_process_probe_ack(addr).map_err(other)?;
+            received.replies = self.seal(addr, ack).map_err(other)?;
+        }
+
+        // Pings as well, any delay would end up in the clock offset
+        if raw_packet.packet_type == PacketType::TimePing {
+            match self.state.pre_process_time_pong(addr, &raw_packet) {
+                Ok(pong) => received.replies = self.seal(addr, pong).map_err(other)?,
+                Err(e) => debug!("Invalid time ping from {}: {}", addr, e),
+            }
+        }
+
+        Ok(received)
+    }
+
+    /// Returns the biggest payload that fits in a single datagram to `addr`.
+    pub fn max_payload_size(&self, addr: SocketAddr) -> usize {
+        self.state
+            .max_datagram_size(addr)
+            .saturating_sub(self.overhead() + RAW_PACKET_HEADER_SIZE)
+    }
+
+    /// Returns the client that connected from `addr` with a connect token, if any.
+    pub fn connected_client(&self, addr: SocketAddr) -> Option<&ConnectedClient> {
+        self.encryption.as_ref().and_then(|encryption| encryption.connected_client(addr))
+    }
+
+    /// Returns the IP addresses the flood protection banned.
+    pub fn banned_addresses(&self) -> Vec<IpAddr> {
+        self.flood_protection
+            .as_ref()
+            .map_or_else(Vec::new, |flood_protection| flood_protection.banned(Instant::now()))
+    }
+
+    /// Returns the time on the other clock of the connection with `addr`, in microseconds, once clock sync has an estimate.
+    pub fn remote_time(&self, addr: SocketAddr) -> Option<i64> {
+        self.state.clock(addr).and_then(|clock| clock.remote_time(self.state.clock_time()))
+    }
+
+    pub fn stats(&self) -> UdpStats {
+        self.state.stats()
+    }
+
+    // Fails before the packet gets a connection and a sequence number if encryption would not take it
+    fn check_can_send(&self, addr: SocketAddr) -> Result<()> {
+        match self.encryption {
+            Some(ref encryption) => encryption.check_can_send(addr),
+            None => Ok(()),
+        }
+    }
+
+    // Compresses and encrypts a serialized packet
+    fn seal(&mut self, addr: SocketAddr, mut payload: Vec<u8>) -> Result<Vec<Vec<u8>>> {
+        // Compress before encrypting, encrypted data does not compress
+        if let Some(ref compression) = self.compression {
+            payload = compression.compress(&payload);
+        }
+
+        let datagrams = match self.encryption {
+            Some(ref mut encryption) => encryption.seal(addr, payload, Instant::now())?,
+            None => vec![payload],
+        };
+        for datagram in &datagrams {
+            self.state.record_sent(datagram.len());
+        }
+        Ok(datagrams)
+    }
+
+    // Bytes compression and encryption add to every datagram
+    fn overhead(&self) -> usize {
+        let mut overhead = 0;
+        if self.compression.is_some() {
+            overhead += compression::OVERHEAD;
+        }
+        if self.encryption.is_some() {
+            overhead += encryption::OVERHEAD;
+        }
+        overhead
+    }
+}
+
+/// Decompresses a decrypted datagram if compression is on, and deserializes the packet in it.
+pub fn read_raw_packet(data: Vec<u8>, compression: Option<&Compression>) -> io::Result<RawPacket> {
+    let data = match compression {
+        Some(compression) => compression.decompress(&data)?,
+        None => data,
+    };
+    deserialize(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn other(error: ::error::Error) -> io::Error {
+    io::Error::other(error.to_string())
+}
@@ -0,0 +1,691 @@
+use std::cmp;
+use std::collections::vec_deque::Drain;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{self, Shutdown, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use mio::net::{TcpListener, TcpStream, UdpSocket};
+use mio::{Events, Interest, Poll, Token};
+
+use super::compression::Compression;
+use super::connect_token::{ConnectToken, ConnectedClient};
+use super::encryption::Encryption;
+use super::flood_protection::FloodProtection;
+use super::frame::{Frame, FrameBuffer, MAX_FRAME_SIZE};
+use super::protocol::{Datagram, Protocol};
+use super::socket_state::{TimeoutCheck, TIMEOUT_POLL_INTERVAL};
+use super::udp::BUFFER_SIZE;
+use super::{ClockSync, Packet, PacketHandle, SocketState, UdpStats};
+
+use error::{Error, NetworkError, Result};
+use events::{DeliveryEvent, DisconnectReason, ReactorEvent, TcpEvent};
+
+const UDP: Token = Token(0);
+const LISTENER: Token = Token(1);
+// Streams get the tokens after the fixed ones
+const FIRST_STREAM: usize = 2;
+
+// How many readiness events one call to `poll` handles at most
+const EVENTS_CAPACITY: usize = 1024;
+// How much we try to read from a stream at once
+const READ_SIZE: usize = 4096;
+
+/// Runs a UDP socket, a TCP server and TCP streams on a single thread, with mio.
+///
+/// `UdpSocket` needs a thread to check for timeouts, and `TcpSocketState` two threads per client. The reactor registers everything on one poller instead and runs all the timers from `poll`, so one thread can serve thousands of connections.
+/// It speaks the same protocols, so it talks to `UdpSocket` and `TcpSocketState` on the other side.
+///
+/// Call `poll` in a loop and take what happened from `events`.
+pub struct Reactor {
+    poll: Poll,
+    events: Events,
+    udp: Option<UdpSocket>,
+    protocol: Protocol,
+    recv_buffer: [u8; BUFFER_SIZE],
+    outgoing: VecDeque<Datagram>,
+    timeout_check: TimeoutCheck,
+    next_timeout_check: Instant,
+    listener: Option<TcpListener>,
+    streams: HashMap<Token, Stream>,
+    tokens: HashMap<SocketAddr, Token>,
+    next_token: usize,
+    heartbeat: Option<(Duration, Duration)>,
+    pending: VecDeque<ReactorEvent>,
+}
+
+struct Stream {
+    stream: TcpStream,
+    addr: SocketAddr,
+    read_buffer: FrameBuffer,
+    write_buffer: Vec<u8>,
+    next_ping: Option<Instant>,
+    last_pong: Instant,
+}
+
+impl Reactor {
+    pub fn new() -> Result<Reactor> {
+        let state = SocketState::without_timeout_thread();
+        let timeout_check = state.timeout_check();
+
+        Ok(Reactor {
+            poll: Poll::new()?,
+            events: Events::with_capacity(EVENTS_CAPACITY),
+            udp: None,
+            protocol: Protocol::new(state),
+            recv_buffer: [0; BUFFER_SIZE],
+            outgoing: VecDeque::new(),
+            timeout_check,
+            next_timeout_check: Instant::now() + Duration::from_secs(TIMEOUT_POLL_INTERVAL),
+            listener: None,
+            streams: HashMap::new(),
+            tokens: HashMap::new(),
+            next_token: FIRST_STREAM,
+            heartbeat: None,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Encrypts everything sent over UDP, see `UdpSocket::with_encryption`.
+    pub fn with_encryption(mut self) -> Self {
+        self.protocol.encryption = Some(Encryption::new());
+        self
+    }
+
+    /// Connects to the servers in `token` with it, see `UdpSocket::with_connect_token`.
+    pub fn with_connect_token(mut self, token: ConnectToken) -> Self {
+        self.protocol.encryption = Some(self.protocol.encryption.take().unwrap_or_default().with_connect_token(token));
+        self
+    }
+
+    /// Only accepts UDP clients with a connect token signed with `key` that lists `public_addr`, see `UdpSocket::with_token_key`.
+    pub fn with_token_key(mut self, key: [u8; 32], public_addr: SocketAddr) -> Self {
+        self.protocol.encryption = Some(self.protocol.encryption.take().unwrap_or_default().with_token_key(key, public_addr));
+        self
+    }
+
+    /// Compresses the datagrams sent over UDP, see `Compression`.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.protocol.compression = Some(compression);
+        self
+    }
+
+    /// Pings the other side of every UDP connection now and then to estimate its clock, see `UdpSocket::with_clock_sync`.
+    pub fn with_clock_sync(mut self) -> Self {
+        self.protocol.clock_sync = true;
+        self
+    }
+
+    /// Checks every received datagram against the given flood protection before anything else is done with it.
+    pub fn with_flood_protection(mut self, flood_protection: FloodProtection) -> Self {
+        self.protocol.flood_protection = Some(flood_protection);
+        self
+    }
+
+    /// Sends a ping to every TCP stream every `interval`, and disconnects streams that do not answer within `timeout`.
+    pub fn with_heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat = Some((interval, timeout));
+        self
+    }
+
+    /// Binds the UDP socket. Returns the address it is bound to.
+    pub fn bind_udp<A: ToSocketAddrs>(&mut self, addr: A) -> Result<SocketAddr> {
+        let socket = net::UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let mut socket = UdpSocket::from_std(socket);
+        self.poll.registry().register(&mut socket, UDP, Interest::READABLE | Interest::WRITABLE)?;
+
+        let local_addr = socket.local_addr()?;
+        self.udp = Some(socket);
+        Ok(local_addr)
+    }
+
+    /// Starts accepting TCP streams. Returns the address the server is listening on.
+    pub fn listen_tcp<A: ToSocketAddrs>(&mut self, addr: A) -> Result<SocketAddr> {
+        if self.listener.is_some() {
+            return Err(Error::from(NetworkError::TcpServerAlreadyRunning));
+        }
+
+        let listener = net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::from_std(listener);
+        self.poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+
+        let local_addr = listener.local_addr()?;
+        self.listener = Some(listener);
+        Ok(local_addr)
+    }
+
+    /// Connects to a remote TCP server, this blocks until the stream is connected like `TcpSocketState::connect`.
+    pub fn connect_tcp(&mut self, addr: SocketAddr) -> Result<()> {
+        let stream = net::TcpStream::connect(addr)?;
+        stream.set_nonblocking(true)?;
+        self.add_stream(TcpStream::from_std(stream), addr)
+    }
+
+    /// Sends a packet over UDP, and a path MTU probe after it if one is due.
+    pub fn send_udp(&mut self, packet: Packet) -> Result<()> {
+        let (addr, datagrams) = self.protocol.send(packet)?;
+        self.queue_udp(addr, datagrams)
+    }
+
+    /// Like `send_udp`, and returns the handle of the `DeliveryEvent`s about the packet, see `UdpSocket::send_tracked`.
+    pub fn send_udp_tracked(&mut self, packet: Packet) -> Result<PacketHandle> {
+        let handle = self.protocol.state.new_handle(packet.addr());
+        let (addr, datagrams) = self.protocol.send_tracked(packet, vec![handle])?;
+        self.queue_udp(addr, datagrams)?;
+        Ok(handle)
+    }
+
+    /// Returns the packets sent with `send_udp_tracked` that were acked or lost since the last call.
+    pub fn delivery_events(&mut self) -> Vec<DeliveryEvent> {
+        self.protocol.state.take_delivery_events()
+    }
+
+    // Queues the datagrams of a packet, and a path MTU probe and clock sync ping after them if they are due, then sends what it can
+    fn queue_udp(&mut self, addr: SocketAddr, datagrams: Vec<Vec<u8>>) -> Result<()> {
+        for data in datagrams {
+            self.outgoing.push_back(Datagram { addr, data, probe: false });
+        }
+        for data in self.protocol.probe(addr)? {
+            self.outgoing.push_back(Datagram { addr, data, probe: true });
+        }
+        for data in self.protocol.time_ping(addr)? {
+            self.outgoing.push_back(Datagram { addr, data, probe: false });
+        }
+        self.flush_udp()
+    }
+
+    /// Sends a message to the TCP stream with the given address.
+    ///
+    /// A stream that fails to write is disconnected, which shows up as a `Disconnected` event.
+    pub fn send_tcp(&mut self, addr: SocketAddr, payload: Vec<u8>) -> Result<()> {
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(Error::from(NetworkError::TcpMessageTooLarge));
+        }
+
+        let token = *self.tokens.get(&addr).ok_or(NetworkError::TcpClientNotFound)?;
+        let result = match self.streams.get_mut(&token) {
+            Some(stream) => stream.send(Frame::Data(payload)),
+            None => return Err(Error::from(NetworkError::TcpClientNotFound)),
+        };
+        if let Err(e) = result {
+            self.remove_stream(token, DisconnectReason::WriteFailed(e.kind()));
+        }
+        Ok(())
+    }
+
+    /// Waits for the sockets to be ready for at most `timeout`, or until the next timer is due, and handles everything that is ready.
+    pub fn poll(&mut self, timeout: Option<Duration>) -> Result<()> {
+        let now = Instant::now();
+        let until_timer = self.next_timer().saturating_duration_since(now);
+        let timeout = Some(timeout.map_or(until_timer, |timeout| cmp::min(timeout, until_timer)));
+
+        match self.poll.poll(&mut self.events, timeout) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::from(e)),
+        }
+
+        // Errors and closed streams are found by the read or write that follows
+        let ready: Vec<(Token, bool, bool)> = self
+            .events
+            .iter()
+            .map(|event| (event.token(), event.is_readable() || event.is_read_closed() || event.is_error(), event.is_writable()))
+            .collect();
+
+        for (token, readable, writable) in ready {
+            match token {
+                UDP => {
+                    if readable {
+                        self.receive_udp()?;
+                    }
+                    if writable {
+                        self.flush_udp()?;
+                    }
+                }
+                LISTENER => self.accept()?,
+                token => self.stream_ready(token, readable, writable),
+            }
+        }
+
+        self.run_timers(Instant::now());
+        Ok(())
+    }
+
+    /// Returns everything that happened since the last call.
+    pub fn events(&mut self) -> Drain<'_, ReactorEvent> {
+        self.pending.drain(..)
+    }
+
+    /// Stops accepting TCP streams and closes every stream.
+    ///
+    /// A `Disconnected` event with `DisconnectReason::Shutdown` is generated for every stream that was still connected.
+    pub fn shutdown(&mut self) {
+        if let Some(mut listener) = self.listener.take() {
+            let _ = self.poll.registry().deregister(&mut listener);
+        }
+
+        let tokens: Vec<Token> = self.streams.keys().cloned().collect();
+        for token in tokens {
+            self.remove_stream(token, DisconnectReason::Shutdown);
+        }
+    }
+
+    /// Returns the number of TCP streams that are connected.
+    pub fn connection_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Returns the client that connected from `addr` over UDP with a connect token, if any.
+    pub fn connected_client(&self, addr: SocketAddr) -> Option<&ConnectedClient> {
+        self.protocol.connected_client(addr)
+    }
+
+    /// Returns the biggest payload that fits in a single datagram to `addr`, see `UdpSocket::max_payload_size`.
+    pub fn max_payload_size(&self, addr: SocketAddr) -> usize {
+        self.protocol.max_payload_size(addr)
+    }
+
+    /// Returns the clock sync state of the UDP connection with `addr`, with the estimated offset and drift of its clock.
+    pub fn clock(&self, addr: SocketAddr) -> Option<ClockSync> {
+        self.protocol.state.clock(addr)
+    }
+
+    /// Returns the time on our clock sync clock, in microseconds.
+    pub fn clock_time(&self) -> i64 {
+        self.protocol.state.clock_time()
+    }
+
+    /// Returns the time on the clock of `addr` right now, in microseconds, once clock sync has an estimate.
+    pub fn remote_time(&self, addr: SocketAddr) -> Option<i64> {
+        self.protocol.remote_time(addr)
+    }
+
+    /// Returns the packets sent to `addr` over UDP that the other side never acknowledged, so they can be resent.
+    pub fn dropped_packets(&mut self, addr: SocketAddr) -> Result<Vec<Packet>> {
+        self.protocol.state.dropped_packets(addr)
+    }
+
+    /// Returns the sequence numbers of the packets sent to `addr` that the other side acknowledged since the last call, oldest first.
+    pub fn acked_packets(&mut self, addr: SocketAddr) -> Result<Vec<u16>> {
+        self.protocol.state.acked_packets(addr)
+    }
+
+    /// Returns the sequence number the next packet sent to `addr` gets.
+    pub fn next_seq(&self, addr: SocketAddr) -> u16 {
+        self.protocol.state.next_seq(addr)
+    }
+
+    pub fn udp_stats(&self) -> UdpStats {
+        self.protocol.stats()
+    }
+
+    // Reads datagrams until the socket has none left
+    fn receive_udp(&mut self) -> Result<()> {
+        loop {
+            let (len, addr) = match self.udp {
+                Some(ref socket) => match socket.recv_from(&mut self.recv_buffer) {
+                    Ok(received) => received,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(Error::from(e)),
+                },
+                None => return Ok(()),
+            };
+            if len == 0 {
+                continue;
+            }
+
+            // One bad datagram should not stop everyone else's
+            match self.protocol.receive(addr, self.recv_buffer[..len].to_vec()) {
+                Ok(received) => {
+                    for data in received.replies {
+                        self.outgoing.push_back(Datagram { addr, data, probe: false });
+                    }
+                    if let Some(packet) = received.packet {
+                        self.pending.push_back(ReactorEvent::Packet(packet));
+                    }
+                }
+                Err(e) => debug!("Ignoring datagram from {}: {}", addr, e),
+            }
+        }
+        self.flush_udp()
+    }
+
+    // Sends queued datagrams until the socket does not take more, the rest goes out when it is writable again
+    fn flush_udp(&mut self) -> Result<()> {
+        let socket = match self.udp {
+            Some(ref socket) => socket,
+            None => return Ok(()),
+        };
+
+        while let Some(datagram) = self.outgoing.front() {
+            let result = socket.send_to(&datagram.data, datagram.addr);
+            if let Err(ref e) = result {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+            }
+            let datagram = self.outgoing.pop_front().expect("the queue has a datagram");
+
+            if let Err(e) = result {
+                if !datagram.probe {
+                    return Err(Error::from(e));
+                }
+                debug!("Could not send MTU probe to {}: {}", datagram.addr, e);
+                self.protocol.probe_failed(datagram.addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Accepts streams until the listener has none left
+    fn accept(&mut self) -> Result<()> {
+        loop {
+            let accepted = match self.listener {
+                Some(ref listener) => listener.accept(),
+                None => return Ok(()),
+            };
+            match accepted {
+                Ok((stream, addr)) => self.add_stream(stream, addr)?,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+    }
+
+    fn add_stream(&mut self, mut stream: TcpStream, addr: SocketAddr) -> Result<()> {
+        // Game messages are small and should go out right away, like the default `StreamOptions`
+        stream.set_nodelay(true)?;
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        self.poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
+
+        let now = Instant::now();
+        self.streams.insert(
+            token,
+            Stream {
+                stream,
+                addr,
+                read_buffer: FrameBuffer::new(),
+                write_buffer: Vec::new(),
+                next_ping: self.heartbeat.map(|(interval, _)| now + interval),
+                last_pong: now,
+            },
+        );
+        self.tokens.insert(addr, token);
+        self.pending.push_back(ReactorEvent::Tcp(TcpEvent::Connected { addr }));
+        Ok(())
+    }
+
+    fn stream_ready(&mut self, token: Token, readable: bool, writable: bool) {
+        let reason = match self.streams.get_mut(&token) {
+            Some(stream) => {
+                let mut reason = None;
+                if writable {
+                    reason = stream.flush().err().map(|e| DisconnectReason::WriteFailed(e.kind()));
+                }
+                if readable && reason.is_none() {
+                    reason = stream.read(&mut self.pending);
+                }
+                // Answers to pings go out right away
+                if reason.is_none() {
+                    reason = stream.flush().err().map(|e| DisconnectReason::WriteFailed(e.kind()));
+                }
+                reason
+            }
+            None => return,
+        };
+
+        if let Some(reason) = reason {
+            self.remove_stream(token, reason);
+        }
+    }
+
+    fn remove_stream(&mut self, token: Token, reason: DisconnectReason) {
+        if let Some(mut stream) = self.streams.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut stream.stream);
+            let _ = stream.stream.shutdown(Shutdown::Both);
+            self.tokens.remove(&stream.addr);
+
+            debug!("TCP client {} disconnected: {:?}", stream.addr, reason);
+            self.pending.push_back(ReactorEvent::Tcp(TcpEvent::Disconnected { addr: stream.addr, reason }));
+        }
+    }
+
+    // Checks UDP connections for timeouts and runs the TCP heartbeat
+    fn run_timers(&mut self, now: Instant) {
+        if now >= self.next_timeout_check {
+            self.timeout_check.run();
+            self.next_timeout_check = now + Duration::from_secs(TIMEOUT_POLL_INTERVAL);
+        }
+
+        let (interval, timeout) = match self.heartbeat {
+            Some(heartbeat) => heartbeat,
+            None => return,
+        };
+
+        let mut disconnected = Vec::new();
+        for (&token, stream) in &mut self.streams {
+            // A ping goes out every interval, so a live peer answers within one interval plus the timeout
+            if now.duration_since(stream.last_pong) >= interval + timeout {
+                disconnected.push((token, DisconnectReason::HeartbeatTimeout));
+                continue;
+            }
+
+            if stream.next_ping.is_some_and(|at| now >= at) {
+                stream.next_ping = Some(now + interval);
+                if let Err(e) = stream.send(Frame::Ping) {
+                    disconnected.push((token, DisconnectReason::WriteFailed(e.kind())));
+                }
+            }
+        }
+
+        for (token, reason) in disconnected {
+            self.remove_stream(token, reason);
+        }
+    }
+
+    // When `poll` has to wake up at the latest for the timers
+    fn next_timer(&self) -> Instant {
+        let mut next = self.next_timeout_check;
+        if let Some((interval, timeout)) = self.heartbeat {
+            for stream in self.streams.values() {
+                next = cmp::min(next, stream.last_pong + interval + timeout);
+                if let Some(at) = stream.next_ping {
+                    next = cmp::min(next, at);
+                }
+            }
+        }
+        next
+    }
+}
+
+impl Stream {
+    fn send(&mut self, frame: Frame) -> io::Result<()> {
+        frame.write_to(&mut self.write_buffer)?;
+        self.flush()
+    }
+
+    // Writes as much of the write buffer as the stream takes, the rest goes out when it is writable again
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.write_buffer.is_empty() {
+            match self.stream.write(&self.write_buffer) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "the stream does not take any more data")),
+                Ok(len) => {
+                    self.write_buffer.drain(..len);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Reads until the stream has nothing left and handles the complete frames, returns why the stream has to go if it does
+    fn read(&mut self, events: &mut VecDeque<ReactorEvent>) -> Option<DisconnectReason> {
+        let mut chunk = [0; READ_SIZE];
+        let mut closed = None;
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    closed = Some(DisconnectReason::Closed);
+                    break;
+                }
+                Ok(len) => self.read_buffer.extend(&chunk[..len]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    closed = Some(DisconnectReason::from_read_error(&e));
+                    break;
+                }
+            }
+        }
+
+        // Frames that arrived before the stream closed are still delivered
+        loop {
+            match self.read_buffer.decode() {
+                Ok(Some(Frame::Data(payload))) => events.push_back(ReactorEvent::Tcp(TcpEvent::Message { addr: self.addr, payload })),
+                Ok(Some(Frame::Ping)) => {
+                    if let Err(e) = Frame::Pong.write_to(&mut self.write_buffer) {
+                        return Some(DisconnectReason::WriteFailed(e.kind()));
+                    }
+                }
+                Ok(Some(Frame::Pong)) => self.last_pong = Instant::now(),
+                Ok(None) => return closed,
+                Err(e) => {
+                    error!("Received an invalid frame: {}", e);
+                    return Some(DisconnectReason::from_read_error(&e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Reactor;
+    use events::{DisconnectReason, ReactorEvent, TcpEvent};
+    use net::frame::{Frame, FrameBuffer};
+    use net::UdpSocket;
+    use packet::Packet;
+    use std::io::Read;
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::{Duration, Instant};
+
+    // Polls until `count` events were generated
+    fn poll_events(reactor: &mut Reactor, count: usize) -> Vec<ReactorEvent> {
+        let started = Instant::now();
+        let mut events = Vec::new();
+        while events.len() < count {
+            assert!(started.elapsed() < Duration::from_secs(5), "only {} of {} events", events.len(), count);
+            reactor.poll(Some(Duration::from_millis(10))).unwrap();
+            events.extend(reactor.events());
+        }
+        events
+    }
+
+    #[test]
+    fn serves_udp_and_tcp_on_one_thread() {
+        let udp_addr: SocketAddr = "127.0.0.1:12457".parse().unwrap();
+        let tcp_addr: SocketAddr = "127.0.0.1:27014".parse().unwrap();
+        let mut reactor = Reactor::new().unwrap().with_encryption();
+        reactor.bind_udp(udp_addr).unwrap();
+        reactor.listen_tcp(tcp_addr).unwrap();
+
+        let mut udp_client = UdpSocket::bind("127.0.0.1:12458").unwrap().with_encryption();
+        udp_client.send(Packet::new(udp_addr, b"over udp".to_vec())).unwrap().unwrap();
+        // The handshake happens while the client waits for the answer
+        udp_client.set_nonblocking(true).unwrap();
+
+        let mut tcp_client = TcpStream::connect(tcp_addr).unwrap();
+        let tcp_client_addr = tcp_client.local_addr().unwrap();
+        Frame::Data(b"over tcp".to_vec()).write_to(&mut tcp_client).unwrap();
+
+        let started = Instant::now();
+        let mut packet = None;
+        let mut message = None;
+        while packet.is_none() || message.is_none() {
+            assert!(started.elapsed() < Duration::from_secs(5), "no packet or message");
+            let _ = udp_client.recv();
+            reactor.poll(Some(Duration::from_millis(10))).unwrap();
+            for event in reactor.events() {
+                match event {
+                    ReactorEvent::Packet(p) => packet = Some(p),
+                    ReactorEvent::Tcp(TcpEvent::Message { addr, payload }) => message = Some((addr, payload)),
+                    ReactorEvent::Tcp(TcpEvent::Connected { addr }) => assert_eq!(addr, tcp_client_addr),
+                    e => panic!("unexpected event: {:?}", e),
+                }
+            }
+        }
+        assert_eq!(packet.unwrap().payload(), b"over udp");
+        assert_eq!(message.unwrap(), (tcp_client_addr, b"over tcp".to_vec()));
+
+        reactor.send_tcp(tcp_client_addr, b"answer".to_vec()).unwrap();
+        let mut buffer = FrameBuffer::new();
+        let mut chunk = [0; 64];
+        while buffer.decode().unwrap().is_none() {
+            let len = tcp_client.read(&mut chunk).unwrap();
+            buffer.extend(&chunk[..len]);
+        }
+
+        reactor.shutdown();
+        let events: Vec<ReactorEvent> = reactor.events().collect();
+        match events[..] {
+            [ReactorEvent::Tcp(TcpEvent::Disconnected { addr, reason: DisconnectReason::Shutdown })] => assert_eq!(addr, tcp_client_addr),
+            ref e => panic!("unexpected events: {:?}", e),
+        }
+        assert_eq!(reactor.connection_count(), 0);
+    }
+
+    #[test]
+    fn handles_many_streams() {
+        let addr: SocketAddr = "127.0.0.1:27015".parse().unwrap();
+        let mut reactor = Reactor::new().unwrap();
+        reactor.listen_tcp(addr).unwrap();
+
+        let mut clients = Vec::new();
+        let mut events = Vec::new();
+        for i in 0..500_u32 {
+            let mut client = TcpStream::connect(addr).unwrap();
+            Frame::Data(i.to_be_bytes().to_vec()).write_to(&mut client).unwrap();
+            clients.push(client);
+            // Accept as we go, so the listen backlog does not fill up
+            reactor.poll(Some(Duration::from_millis(0))).unwrap();
+            events.extend(reactor.events());
+        }
+
+        let remaining = 1000 - events.len();
+        events.extend(poll_events(&mut reactor, remaining));
+        let messages = events
+            .iter()
+            .filter(|event| matches!(event, ReactorEvent::Tcp(TcpEvent::Message { .. })))
+            .count();
+        assert_eq!(messages, 500);
+        assert_eq!(reactor.connection_count(), 500);
+
+        drop(clients);
+        let events = poll_events(&mut reactor, 500);
+        assert!(events.iter().all(|event| matches!(event, ReactorEvent::Tcp(TcpEvent::Disconnected { reason: DisconnectReason::Closed, .. }))));
+        assert_eq!(reactor.connection_count(), 0);
+    }
+
+    #[test]
+    fn heartbeat_disconnects_silent_streams() {
+        let addr: SocketAddr = "127.0.0.1:27016".parse().unwrap();
+        let mut reactor = Reactor::new().unwrap().with_heartbeat(Duration::from_millis(50), Duration::from_millis(100));
+        reactor.listen_tcp(addr).unwrap();
+
+        // A plain stream never answers the pings
+        let _silent = TcpStream::connect(addr).unwrap();
+        let events = poll_events(&mut reactor, 2);
+        match events[1] {
+            ReactorEvent::Tcp(TcpEvent::Disconnected { reason: DisconnectReason::HeartbeatTimeout, .. }) => {}
+            ref e => panic!("unexpected event: {:?}", e),
+        }
+    }
+}
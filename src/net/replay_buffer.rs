@@ -0,0 +1,120 @@
+// How many sequence numbers behind the newest one we can still tell apart
+const REPLAY_BUFFER_SIZE: usize = 256;
+
+/// Remembers which sequence numbers we received from the other side, so duplicated or replayed packets are only delivered once.
+///
+/// Sequence numbers wrap around, so like `ExternalAcks` we treat a number as newer when it is less than half the range ahead.
+/// Packets that are more than `REPLAY_BUFFER_SIZE` behind the newest one can not be told apart from replays anymore and are rejected as well.
+/// The buffer never starts over by itself, whoever can send packets could use that to replay old ones; it is replaced when the other side starts a new session.
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    most_recent: u16,
+    // The sequence number stored in each slot, indexed by `seq % REPLAY_BUFFER_SIZE`
+    received: Vec<Option<u16>>,
+    initialized: bool,
+}
+
+impl ReplayBuffer {
+    pub fn new() -> ReplayBuffer {
+        ReplayBuffer {
+            most_recent: 0,
+            received: vec![None; REPLAY_BUFFER_SIZE],
+            initialized: false,
+        }
+    }
+
+    /// Returns true if `seq` was received before, or is too old to tell.
+    pub fn already_received(&self, seq: u16) -> bool {
+        if !self.initialized || is_newer(seq, self.most_recent) {
+            return false;
+        }
+
+        let behind = self.most_recent.wrapping_sub(seq) as usize;
+        behind >= REPLAY_BUFFER_SIZE || self.received[slot(seq)] == Some(seq)
+    }
+
+    /// Records `seq` as received.
+    pub fn insert(&mut self, seq: u16) {
+        if !self.initialized {
+            self.initialized = true;
+            self.most_recent = seq;
+        } else if is_newer(seq, self.most_recent) {
+            self.most_recent = seq;
+        }
+
+        self.received[slot(seq)] = Some(seq);
+    }
+}
+
+fn slot(seq: u16) -> usize {
+    seq as usize % REPLAY_BUFFER_SIZE
+}
+
+// `a` is newer than `b` if it is ahead by less than half the sequence number range
+fn is_newer(a: u16, b: u16) -> bool {
+    let diff = a.wrapping_sub(b);
+    diff != 0 && diff < 32768
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReplayBuffer, REPLAY_BUFFER_SIZE};
+
+    #[test]
+    fn rejects_sequence_numbers_that_were_received() {
+        let mut buffer = ReplayBuffer::new();
+        assert!(!buffer.already_received(5));
+        buffer.insert(5);
+        assert!(buffer.already_received(5));
+
+        // Out of order packets are accepted once
+        assert!(!buffer.already_received(3));
+        buffer.insert(3);
+        assert!(buffer.already_received(3));
+        assert!(!buffer.already_received(4));
+        assert!(!buffer.already_received(6));
+    }
+
+    #[test]
+    fn rejects_sequence_numbers_that_are_too_old() {
+        let mut buffer = ReplayBuffer::new();
+        buffer.insert(0);
+        buffer.insert(REPLAY_BUFFER_SIZE as u16 + 10);
+
+        assert!(buffer.already_received(5));
+        assert!(!buffer.already_received(11));
+        // The slot of 0 is reused, a new sequence number in it is not mistaken for the old one
+        assert!(!buffer.already_received(REPLAY_BUFFER_SIZE as u16));
+    }
+
+    #[test]
+    fn does_not_start_over_on_old_sequence_numbers() {
+        let mut buffer = ReplayBuffer::new();
+        for seq in 1000..1010 {
+            buffer.insert(seq);
+        }
+
+        // However many old packets are replayed, they are all rejected and the recent ones stay known
+        for seq in 0..700 {
+            assert!(buffer.already_received(seq));
+        }
+        assert!(buffer.already_received(1005));
+        assert!(!buffer.already_received(1010));
+    }
+
+    #[test]
+    fn handles_wrap_around() {
+        let mut buffer = ReplayBuffer::new();
+        for seq in 0..20_u16 {
+            let seq = seq.wrapping_sub(10);
+            assert!(!buffer.already_received(seq));
+            buffer.insert(seq);
+        }
+
+        assert!(buffer.already_received(65530));
+        assert!(buffer.already_received(5));
+        assert!(!buffer.already_received(10));
+        assert!(!buffer.already_received(65500));
+        assert!(buffer.already_received(65000));
+    }
+}
@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bincode::{deserialize, serialize};
+use rand::random;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{Packet, TcpSocketState, UdpSocket};
+use error::Result;
+
+/// Tells the procedures apart, both sides have to agree on them.
+pub type MethodId = u16;
+/// Tells the calls of an `Rpc` apart until they are answered.
+pub type RequestId = u32;
+
+// First byte of an RPC payload, so other payloads fail to decode instead of giving garbage
+const RPC_TAG: u8 = 0xa8;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_RESEND_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Why a call failed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RpcError {
+    /// The other side has no handler for the method.
+    UnknownMethod(MethodId),
+    /// The arguments did not deserialize to what the handler takes.
+    InvalidArguments(String),
+    /// The handler returned an error.
+    Failed(String),
+    /// The result did not deserialize to what the caller expected.
+    InvalidResult(String),
+    /// No answer arrived within the timeout.
+    TimedOut,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RpcError::UnknownMethod(method) => write!(f, "there is no handler for method {}", method),
+            RpcError::InvalidArguments(ref e) => write!(f, "invalid arguments: {}", e),
+            RpcError::Failed(ref e) => write!(f, "the call failed: {}", e),
+            RpcError::InvalidResult(ref e) => write!(f, "invalid result: {}", e),
+            RpcError::TimedOut => write!(f, "the call timed out"),
+        }
+    }
+}
+
+/// Something RPC payloads can be sent over.
+pub trait RpcTransport {
+    fn send_rpc(&mut self, addr: SocketAddr, payload: Vec<u8>) -> Result<()>;
+
+    /// Returns true if payloads always arrive, so requests are never sent again.
+    fn is_reliable(&self) -> bool;
+}
+
+/// Datagrams get lost, so requests are sent again until they are answered. With aggregation on, payloads only go out on `flush`.
+impl RpcTransport for UdpSocket {
+    fn send_rpc(&mut self, addr: SocketAddr, payload: Vec<u8>) -> Result<()> {
+        self.send(Packet::new(addr, payload))??;
+        Ok(())
+    }
+
+    fn is_reliable(&self) -> bool {
+        false
+    }
+}
+
+impl RpcTransport for TcpSocketState {
+    fn send_rpc(&mut self, addr: SocketAddr, payload: Vec<u8>) -> Result<()> {
+        self.send(addr, payload)
+    }
+
+    fn is_reliable(&self) -> bool {
+        true
+    }
+}
+
+// The session tells the calls of an `Rpc` apart from those of an earlier one at the same address, which numbered its requests the same way
+#[derive(Serialize, Deserialize)]
+enum Frame {
+    Request { session: u64, id: RequestId, method: MethodId, args: Vec<u8> },
+    Response { session: u64, id: RequestId, result: ::std::result::Result<Vec<u8>, RpcError> },
+}
+
+type Handler = Box<dyn FnMut(SocketAddr, &[u8]) -> ::std::result::Result<Vec<u8>, RpcError> + Send>;
+type Callback = Box<dyn FnOnce(::std::result::Result<Vec<u8>, RpcError>) + Send>;
+
+struct PendingCall {
+    addr: SocketAddr,
+    payload: Vec<u8>,
+    callback: Callback,
+    deadline: Instant,
+    next_resend: Instant,
+}
+
+/// Remote procedure calls over any `RpcTransport`, with arguments and results serialized with bincode.
+///
+/// Feed it every payload received from the transport with `receive`, and call `update` every tick so requests are sent again and calls time out.
+/// The crate has no reliable channels, so over UDP the `Rpc` makes calls reliable itself: a request is sent again every `resend_interval` until it is answered.
+/// Answers are kept for the timeout, so a request that arrives twice runs its handler once. Both sides have to use the same timeout, a request sent again after the answer was forgotten runs the handler again.
+pub struct Rpc {
+    handlers: HashMap<MethodId, Handler>,
+    pending: HashMap<RequestId, PendingCall>,
+    // Answers sent, by the address, session and request id they answer
+    answers: HashMap<(SocketAddr, u64, RequestId), (Vec<u8>, Instant)>,
+    session: u64,
+    next_id: RequestId,
+    timeout: Duration,
+    resend_interval: Duration,
+}
+
+impl Rpc {
+    pub fn new() -> Rpc {
+        Rpc {
+            handlers: HashMap::new(),
+            pending: HashMap::new(),
+            answers: HashMap::new(),
+            session: random(),
+            next_id: 0,
+            timeout: DEFAULT_TIMEOUT,
+            resend_interval: DEFAULT_RESEND_INTERVAL,
+        }
+    }
+
+    /// Runs `handler` for calls of `method`. An error it returns is passed to the caller as `RpcError::Failed`.
+    pub fn with_handler<A, R, F>(mut self, method: MethodId, mut handler: F) -> Self
+    where
+        A: DeserializeOwned,
+        R: Serialize,
+        F: FnMut(SocketAddr, A) -> ::std::result::Result<R, String> + Send + 'static,
+    {
+        let handler = move |addr: SocketAddr, args: &[u8]| {
+            let args = deserialize(args).map_err(|e| RpcError::InvalidArguments(e.to_string()))?;
+            let result = handler(addr, args).map_err(RpcError::Failed)?;
+            serialize(&result).map_err(|e| RpcError::Failed(e.to_string()))
+        };
+        self.handlers.insert(method, Box::new(handler));
+        self
+    }
+
+    /// Fails calls that were not answered within `timeout`, and keeps answers that long. Use the same timeout on both sides.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sends unanswered requests over unreliable transports again this often.
+    pub fn with_resend_interval(mut self, resend_interval: Duration) -> Self {
+        self.resend_interval = resend_interval;
+        self
+    }
+
+    /// Calls `method` on `addr`, `callback` gets the result once it arrives or the call times out.
+    pub fn call<T, A, R, F>(&mut self, transport: &mut T, addr: SocketAddr, method: MethodId, args: &A, callback: F) -> Result<RequestId>
+    where
+        T: RpcTransport,
+        A: Serialize,
+        R: DeserializeOwned,
+        F: FnOnce(::std::result::Result<R, RpcError>) + Send + 'static,
+    {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let payload = encode(&Frame::Request {
+            session: self.session,
+            id,
+            method,
+            args: serialize(args)?,
+        })?;
+        transport.send_rpc(addr, payload.clone())?;
+
+        let now = Instant::now();
+        let callback = move |result: ::std::result::Result<Vec<u8>, RpcError>| {
+            callback(result.and_then(|result| deserialize(&result).map_err(|e| RpcError::InvalidResult(e.to_string()))))
+        };
+        self.pending.insert(
+            id,
+            PendingCall {
+                addr,
+                payload,
+                callback: Box::new(callback),
+                deadline: now + self.timeout,
+                next_resend: now + self.resend_interval,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Handles a payload received from `addr`: runs the handler of a request and sends the answer, or passes an answer to the callback of its call.
+    ///
+    /// Payloads that are not RPC are an `InvalidData` error.
+    pub fn receive<T: RpcTransport>(&mut self, transport: &mut T, addr: SocketAddr, payload: &[u8]) -> Result<()> {
+        match decode(payload)? {
+            Frame::Request { session, id, method, args } => {
+                if let Some((answer, _)) = self.answers.get(&(addr, session, id)) {
+                    // The answer got lost, or the request was sent again before it arrived
+                    return transport.send_rpc(addr, answer.clone());
+                }
+
+                let result = match self.handlers.get_mut(&method) {
+                    Some(handler) => handler(addr, &args),
+                    None => Err(RpcError::UnknownMethod(method)),
+                };
+                let answer = encode(&Frame::Response { session, id, result })?;
+                self.answers.insert((addr, session, id), (answer.clone(), Instant::now()));
+                transport.send_rpc(addr, answer)
+            }
+            Frame::Response { session, id, result } => {
+                // Answers to calls that timed out, that were already answered or that an earlier `Rpc` made are ignored
+                if session == self.session && self.pending.get(&id).is_some_and(|call| call.addr == addr) {
+                    if let Some(call) = self.pending.remove(&id) {
+                        (call.callback)(result);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends unanswered requests again if the transport is unreliable, fails the calls that timed out and forgets old answers.
+    ///
+    /// A request that can not be sent again does not keep the others from being sent, the first error is returned after all of them were tried.
+    pub fn update<T: RpcTransport>(&mut self, transport: &mut T, now: Instant) -> Result<()> {
+        let timed_out: Vec<RequestId> = self.pending.iter().filter(|(_, call)| now >= call.deadline).map(|(&id, _)| id).collect();
+        for id in timed_out {
+            if let Some(call) = self.pending.remove(&id) {
+                (call.callback)(Err(RpcError::TimedOut));
+            }
+        }
+
+        let mut error = None;
+        if !transport.is_reliable() {
+            for call in self.pending.values_mut() {
+                if now >= call.next_resend {
+                    call.next_resend = now + self.resend_interval;
+                    if let Err(e) = transport.send_rpc(call.addr, call.payload.clone()) {
+                        error.get_or_insert(e);
+                    }
+                }
+            }
+        }
+
+        let timeout = self.timeout;
+        self.answers.retain(|_, &mut (_, sent_at)| now.saturating_duration_since(sent_at) < timeout);
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the number of calls that were not answered yet.
+    pub fn pending_calls(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl Default for Rpc {
+    fn default() -> Self {
+        Rpc::new()
+    }
+}
+
+fn encode(frame: &Frame) -> Result<Vec<u8>> {
+    let mut payload = vec![RPC_TAG];
+    payload.extend(serialize(frame)?);
+    Ok(payload)
+}
+
+fn decode(payload: &[u8]) -> io::Result<Frame> {
+    if payload.first() != Some(&RPC_TAG) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the payload is not an RPC"));
+    }
+    deserialize(&payload[1..]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Rpc, RpcError, RpcTransport};
+    use error::Result;
+    use events::TcpEvent;
+    use net::{TcpSocketState, UdpSocket};
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const ADD: u16 = 1;
+    const JOIN_TEAM: u16 = 2;
+
+    // Collects what is sent instead of sending it
+    #[derive(Default)]
+    struct Loopback {
+        sent: Vec<(SocketAddr, Vec<u8>)>,
+        reliable: bool,
+        unreachable: Option<SocketAddr>,
+    }
+
+    impl RpcTransport for Loopback {
+        fn send_rpc(&mut self, addr: SocketAddr, payload: Vec<u8>) -> Result<()> {
+            if self.unreachable == Some(addr) {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "unreachable").into());
+            }
+            self.sent.push((addr, payload));
+            Ok(())
+        }
+
+        fn is_reliable(&self) -> bool {
+            self.reliable
+        }
+    }
+
+    fn game_server() -> Rpc {
+        Rpc::new()
+            .with_handler(ADD, |_, (a, b): (u32, u32)| Ok(a + b))
+            .with_handler(JOIN_TEAM, |_, team: String| if team == "red" { Ok(()) } else { Err(format!("there is no team {}", team)) })
+    }
+
+    fn results() -> Arc<Mutex<Vec<std::result::Result<u32, RpcError>>>> {
+        Arc::new(Mutex::new(Vec::new()))
+    }
+
+    #[test]
+    fn answers_calls() {
+        let client_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let server_addr: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let mut client = Rpc::new();
+        let mut server = game_server();
+        let (mut to_server, mut to_client) = (Loopback::default(), Loopback::default());
+
+        let results = results();
+        let sink = results.clone();
+        client.call(&mut to_server, server_addr, ADD, &(2_u32, 3_u32), move |result| sink.lock().unwrap().push(result)).unwrap();
+        let sink = results.clone();
+        client.call(&mut to_server, server_addr, 99, &(), move |result| sink.lock().unwrap().push(result)).unwrap();
+        let failed = Arc::new(Mutex::new(None));
+        let sink = failed.clone();
+        client.call(&mut to_server, server_addr, JOIN_TEAM, &"blue", move |result: std::result::Result<(), RpcError>| *sink.lock().unwrap() = Some(result)).unwrap();
+        assert_eq!(client.pending_calls(), 3);
+
+        for (_, payload) in to_server.sent.drain(..) {
+            server.receive(&mut to_client, client_addr, &payload).unwrap();
+        }
+        for (_, payload) in to_client.sent.drain(..) {
+            client.receive(&mut to_server, server_addr, &payload).unwrap();
+        }
+
+        assert_eq!(*results.lock().unwrap(), vec![Ok(5), Err(RpcError::UnknownMethod(99))]);
+        assert_eq!(*failed.lock().unwrap(), Some(Err(RpcError::Failed("there is no team blue".to_string()))));
+        assert_eq!(client.pending_calls(), 0);
+        assert!(client.receive(&mut to_server, server_addr, &[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn resends_requests_and_runs_them_once() {
+        let client_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let server_addr: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let calls = Arc::new(Mutex::new(0));
+        let counter = calls.clone();
+        let mut server = Rpc::new().with_handler(ADD, move |_, (a, b): (u32, u32)| {
+            *counter.lock().unwrap() += 1;
+            Ok(a + b)
+        });
+        let mut client = Rpc::new().with_resend_interval(Duration::from_millis(100));
+        let (mut to_server, mut to_client) = (Loopback::default(), Loopback::default());
+
+        let results = results();
+        let sink = results.clone();
+        client.call(&mut to_server, server_addr, ADD, &(1_u32, 1_u32), move |result| sink.lock().unwrap().push(result)).unwrap();
+        // The request arrives, the answer is lost
+        server.receive(&mut to_client, client_addr, &to_server.sent.remove(0).1).unwrap();
+        to_client.sent.clear();
+
+        let now = Instant::now();
+        client.update(&mut to_server, now).unwrap();
+        assert!(to_server.sent.is_empty());
+        client.update(&mut to_server, now + Duration::from_millis(200)).unwrap();
+        assert_eq!(to_server.sent.len(), 1);
+
+        server.receive(&mut to_client, client_addr, &to_server.sent.remove(0).1).unwrap();
+        client.receive(&mut to_server, server_addr, &to_client.sent.remove(0).1).unwrap();
+        assert_eq!(*results.lock().unwrap(), vec![Ok(2)]);
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn does_not_answer_a_restarted_client_from_the_cache() {
+        let client_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let server_addr: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let mut server = game_server();
+        let (mut to_server, mut to_client) = (Loopback::default(), Loopback::default());
+
+        let results = results();
+        for args in &[(1_u32, 1_u32), (2, 2)] {
+            // Both clients number their first call the same way
+            let mut client = Rpc::new();
+            let sink = results.clone();
+            client.call(&mut to_server, server_addr, ADD, args, move |result| sink.lock().unwrap().push(result)).unwrap();
+            server.receive(&mut to_client, client_addr, &to_server.sent.remove(0).1).unwrap();
+            client.receive(&mut to_server, server_addr, &to_client.sent.remove(0).1).unwrap();
+        }
+        assert_eq!(*results.lock().unwrap(), vec![Ok(2), Ok(4)]);
+
+        // An answer meant for an earlier client is ignored
+        let mut client = Rpc::new();
+        let sink = results.clone();
+        client.call(&mut to_server, server_addr, ADD, &(3_u32, 3_u32), move |result| sink.lock().unwrap().push(result)).unwrap();
+        let mut earlier = Rpc::new();
+        earlier.call(&mut to_server, server_addr, ADD, &(4_u32, 4_u32), |_: std::result::Result<u32, RpcError>| {}).unwrap();
+        server.receive(&mut to_client, client_addr, &to_server.sent.remove(1).1).unwrap();
+        client.receive(&mut to_server, server_addr, &to_client.sent.remove(0).1).unwrap();
+        assert_eq!(client.pending_calls(), 1);
+    }
+
+    #[test]
+    fn resends_to_every_address_when_one_fails() {
+        let first_addr: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let second_addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let mut client = Rpc::new().with_resend_interval(Duration::from_millis(100));
+        let mut transport = Loopback::default();
+
+        for &addr in &[first_addr, second_addr] {
+            client.call(&mut transport, addr, ADD, &(1_u32, 1_u32), |_: std::result::Result<u32, RpcError>| {}).unwrap();
+        }
+        transport.sent.clear();
+
+        transport.unreachable = Some(first_addr);
+        assert!(client.update(&mut transport, Instant::now() + Duration::from_millis(200)).is_err());
+        assert_eq!(transport.sent.len(), 1);
+        assert_eq!(transport.sent[0].0, second_addr);
+    }
+
+    #[test]
+    fn calls_time_out() {
+        let server_addr: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let mut client = Rpc::new().with_timeout(Duration::from_secs(1));
+        let mut transport = Loopback {
+            reliable: true,
+            ..Loopback::default()
+        };
+
+        let results = results();
+        let sink = results.clone();
+        client.call(&mut transport, server_addr, ADD, &(1_u32, 1_u32), move |result| sink.lock().unwrap().push(result)).unwrap();
+
+        // Reliable transports do not need requests sent again
+        let now = Instant::now();
+        client.update(&mut transport, now + Duration::from_millis(500)).unwrap();
+        assert_eq!(transport.sent.len(), 1);
+        assert!(results.lock().unwrap().is_empty());
+
+        client.update(&mut transport, now + Duration::from_secs(2)).unwrap();
+        assert_eq!(*results.lock().unwrap(), vec![Err(RpcError::TimedOut)]);
+        assert_eq!(client.pending_calls(), 0);
+    }
+
+    #[test]
+    fn calls_over_udp() {
+        let mut client = UdpSocket::bind("127.0.0.1:12557").unwrap();
+        let mut server = UdpSocket::bind("127.0.0.1:12558").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let mut client_rpc = Rpc::new();
+        let mut server_rpc = game_server();
+
+        let results = results();
+        let sink = results.clone();
+        client_rpc.call(&mut client, server_addr, ADD, &(40_u32, 2_u32), move |result| sink.lock().unwrap().push(result)).unwrap();
+
+        let request = server.recv().unwrap().unwrap();
+        server_rpc.receive(&mut server, request.addr(), request.payload()).unwrap();
+        let answer = client.recv().unwrap().unwrap();
+        client_rpc.receive(&mut client, answer.addr(), answer.payload()).unwrap();
+        assert_eq!(*results.lock().unwrap(), vec![Ok(42)]);
+    }
+
+    #[test]
+    fn calls_over_tcp() {
+        let server_addr: SocketAddr = "127.0.0.1:27018".parse().unwrap();
+        let mut server = TcpSocketState::new();
+        server.start(server_addr).unwrap();
+        let mut client = TcpSocketState::new();
+        client.connect(server_addr).unwrap();
+        let mut client_rpc = Rpc::new();
+        let mut server_rpc = game_server();
+
+        let results = results();
+        let sink = results.clone();
+        client_rpc.call(&mut client, server_addr, ADD, &(40_u32, 2_u32), move |result| sink.lock().unwrap().push(result)).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while results.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "no answer over TCP");
+            let requests: Vec<_> = server.events().collect();
+            for event in requests {
+                if let TcpEvent::Message { addr, payload } = event {
+                    server_rpc.receive(&mut server, addr, &payload).unwrap();
+                }
+            }
+            let answers: Vec<_> = client.events().collect();
+            for event in answers {
+                if let TcpEvent::Message { addr, payload } = event {
+                    client_rpc.receive(&mut client, addr, &payload).unwrap();
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(*results.lock().unwrap(), vec![Ok(42)]);
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+
+// Snapshots kept per connection that were not acked yet, a packet older than 32 sequence numbers counts as dropped anyway
+const DEFAULT_MAX_PENDING: usize = 64;
+
+/// A state that can be sent as the difference from an older state of the same kind.
+///
+/// Implement it for the game state to send field level deltas, `Vec<u8>` already has byte level ones.
+pub trait Diff {
+    type Delta;
+
+    /// Returns what changed from `baseline` to `self`.
+    fn diff(&self, baseline: &Self) -> Self::Delta;
+
+    /// Rebuilds the state that `delta` was computed for, with `self` as the baseline.
+    fn apply(&self, delta: &Self::Delta) -> io::Result<Self>
+    where
+        Self: Sized;
+}
+
+impl Diff for Vec<u8> {
+    type Delta = Vec<u8>;
+
+    fn diff(&self, baseline: &Vec<u8>) -> Vec<u8> {
+        delta(baseline, self)
+    }
+
+    fn apply(&self, delta: &Vec<u8>) -> io::Result<Vec<u8>> {
+        apply_delta(self, delta)
+    }
+}
+
+/// Keeps the snapshots sent to every connection by the sequence number of their packet, and the newest one that was acked as the baseline for the next delta.
+///
+/// The sender inserts every snapshot it sends with `UdpSocket::next_seq`, and acks the sequence numbers from `UdpSocket::acked_packets`.
+/// The receiver can use one as well, inserting the snapshots it rebuilt and acking the baselines deltas were made against.
+/// Once a snapshot is acked, the older ones are never used as a baseline again and are evicted.
+#[derive(Debug, Clone)]
+pub struct SnapshotBuffer<T> {
+    connections: HashMap<SocketAddr, Snapshots<T>>,
+    max_pending: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Snapshots<T> {
+    // Oldest first, the acked baseline is the first one if there is one
+    snapshots: VecDeque<(u16, T)>,
+    baseline: Option<u16>,
+}
+
+impl<T> SnapshotBuffer<T> {
+    pub fn new() -> SnapshotBuffer<T> {
+        SnapshotBuffer {
+            connections: HashMap::new(),
+            max_pending: DEFAULT_MAX_PENDING,
+        }
+    }
+
+    /// Keeps at most `max_pending` snapshots per connection that were not acked, the oldest are evicted first.
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// Stores the snapshot sent to `addr` in the packet with sequence number `seq`.
+    pub fn insert(&mut self, addr: SocketAddr, seq: u16, snapshot: T) {
+        let connection = self.connections.entry(addr).or_insert_with(|| Snapshots {
+            snapshots: VecDeque::new(),
+            baseline: None,
+        });
+        connection.snapshots.retain(|&(old_seq, _)| old_seq != seq);
+        connection.snapshots.push_back((seq, snapshot));
+
+        let pending = connection.snapshots.len() - connection.baseline.map_or(0, |_| 1);
+        if pending > self.max_pending {
+            let oldest_pending = if connection.baseline.is_some() { 1 } else { 0 };
+            connection.snapshots.remove(oldest_pending);
+        }
+    }
+
+    /// Records that the snapshot sent to `addr` with `seq` arrived, making it the baseline if it is newer than the current one.
+    pub fn ack(&mut self, addr: SocketAddr, seq: u16) {
+        let connection = match self.connections.get_mut(&addr) {
+            Some(connection) => connection,
+            None => return,
+        };
+        if let Some(baseline) = connection.baseline {
+            if !is_newer(seq, baseline) {
+                return;
+            }
+        }
+        if let Some(position) = connection.snapshots.iter().position(|&(old_seq, _)| old_seq == seq) {
+            connection.snapshots.drain(..position);
+            connection.baseline = Some(seq);
+        }
+    }
+
+    /// Returns the newest snapshot `addr` acked, with its sequence number.
+    pub fn baseline(&self, addr: SocketAddr) -> Option<(u16, &T)> {
+        let connection = self.connections.get(&addr)?;
+        let baseline = connection.baseline?;
+        connection.snapshots.front().map(|(seq, snapshot)| {
+            debug_assert_eq!(*seq, baseline);
+            (*seq, snapshot)
+        })
+    }
+
+    /// Returns the snapshot sent to `addr` with `seq`, if it was not evicted.
+    pub fn get(&self, addr: SocketAddr, seq: u16) -> Option<&T> {
+        self.connections
+            .get(&addr)?
+            .snapshots
+            .iter()
+            .find(|&&(old_seq, _)| old_seq == seq)
+            .map(|(_, snapshot)| snapshot)
+    }
+
+    /// Forgets everything about `addr`, for when it disconnects.
+    pub fn remove(&mut self, addr: SocketAddr) {
+        self.connections.remove(&addr);
+    }
+}
+
+impl<T: Diff> SnapshotBuffer<T> {
+    /// Returns the delta from the baseline of `addr` to `snapshot`, with the sequence number of the baseline. `None` means there is no baseline yet, so send the full snapshot.
+    pub fn delta(&self, addr: SocketAddr, snapshot: &T) -> Option<(u16, T::Delta)> {
+        self.baseline(addr).map(|(seq, baseline)| (seq, snapshot.diff(baseline)))
+    }
+}
+
+impl<T> Default for SnapshotBuffer<T> {
+    fn default() -> Self {
+        SnapshotBuffer::new()
+    }
+}
+
+// True if `seq` was sent after `other`, allowing for the sequence numbers to wrap around
+fn is_newer(seq: u16, other: u16) -> bool {
+    let diff = seq.wrapping_sub(other);
+    diff != 0 && diff < 32768
+}
+
+/// Encodes the bytes of `current` that differ from `baseline`.
+///
+/// The bytes are XORed with the baseline, so unchanged ones become zero runs which are skipped. The delta is the length of `current`, followed by runs of the number of unchanged bytes, the number of changed bytes and the changed bytes XORed, with the counts as u16 little endian.
+/// Bytes past the end of the baseline always count as changed, so a delta is never shorter than what `current` adds to the baseline.
+pub fn delta(baseline: &[u8], current: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    delta.extend_from_slice(&(current.len() as u32).to_le_bytes());
+
+    let xored: Vec<u8> = current.iter().enumerate().map(|(i, &byte)| byte ^ baseline.get(i).cloned().unwrap_or(0)).collect();
+    let is_changed = |i: usize| i >= baseline.len() || xored[i] != 0;
+    let mut position = 0;
+    while position < xored.len() {
+        let skip = (position..xored.len()).take(usize::from(u16::MAX)).take_while(|&i| !is_changed(i)).count();
+        position += skip;
+        let changed = (position..xored.len()).take(usize::from(u16::MAX)).take_while(|&i| is_changed(i)).count();
+        if changed == 0 && position == xored.len() {
+            break;
+        }
+        delta.extend_from_slice(&(skip as u16).to_le_bytes());
+        delta.extend_from_slice(&(changed as u16).to_le_bytes());
+        delta.extend_from_slice(&xored[position..position + changed]);
+        position += changed;
+    }
+    delta
+}
+
+/// Rebuilds the bytes a delta from `delta` was made for, on top of the same `baseline`.
+pub fn apply_delta(baseline: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    if delta.len() < 4 {
+        return Err(invalid_data("truncated delta length"));
+    }
+    let len = u32::from_le_bytes([delta[0], delta[1], delta[2], delta[3]]) as usize;
+    // Every byte past the baseline is in the delta, so a made up length is caught before it is allocated
+    if len > baseline.len() + delta.len() - 4 {
+        return Err(invalid_data("delta length is longer than its runs can write"));
+    }
+    let mut current: Vec<u8> = (0..len).map(|i| baseline.get(i).cloned().unwrap_or(0)).collect();
+
+    let mut position = 0;
+    let mut rest = &delta[4..];
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(invalid_data("truncated delta run"));
+        }
+        let skip = usize::from(u16::from_le_bytes([rest[0], rest[1]]));
+        let changed = usize::from(u16::from_le_bytes([rest[2], rest[3]]));
+        rest = &rest[4..];
+        position += skip;
+        if rest.len() < changed || position + changed > len {
+            return Err(invalid_data("delta run out of bounds"));
+        }
+        for (byte, xor) in current[position..position + changed].iter_mut().zip(rest) {
+            *byte ^= xor;
+        }
+        position += changed;
+        rest = &rest[changed..];
+    }
+    Ok(current)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_delta, delta, Diff, SnapshotBuffer};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn byte_deltas_only_carry_the_changes() {
+        let baseline: Vec<u8> = (0..200).collect();
+        let mut current = baseline.clone();
+        current[10] = 0;
+        current[150..153].copy_from_slice(&[7, 7, 7]);
+
+        let delta = current.diff(&baseline);
+        assert_eq!(delta.len(), 4 + 4 + 1 + 4 + 3);
+        assert_eq!(baseline.apply(&delta).unwrap(), current);
+    }
+
+    #[test]
+    fn byte_deltas_handle_other_lengths() {
+        let baseline = vec![1; 100];
+        for current in &[vec![1; 50], vec![2; 300], Vec::new(), vec![0; 70_000]] {
+            assert_eq!(&apply_delta(&baseline, &delta(&baseline, current)).unwrap(), current);
+        }
+        assert!(apply_delta(&baseline, &[1, 0, 0, 0, 0, 0, 2, 0]).is_err());
+        assert!(apply_delta(&baseline, &[0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(apply_delta(&[], &[9, 0, 0, 0, 0, 0, 4, 0, 1, 2, 3, 4]).is_err());
+    }
+
+    #[test]
+    fn acking_a_snapshot_evicts_the_older_ones() {
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let mut buffer = SnapshotBuffer::new();
+        for seq in 65534..=65535 {
+            buffer.insert(addr, seq, vec![seq as u8]);
+        }
+        for seq in 0..3 {
+            buffer.insert(addr, seq, vec![seq as u8]);
+        }
+        assert!(buffer.baseline(addr).is_none());
+        assert!(buffer.delta(addr, &vec![1]).is_none());
+
+        buffer.ack(addr, 0);
+        assert_eq!(buffer.baseline(addr), Some((0, &vec![0])));
+        assert!(buffer.get(addr, 65535).is_none());
+        assert_eq!(buffer.get(addr, 2), Some(&vec![2]));
+
+        // Acks that arrive late do not move the baseline back
+        buffer.ack(addr, 65535);
+        buffer.ack(addr, 2);
+        buffer.ack(addr, 1);
+        assert_eq!(buffer.baseline(addr), Some((2, &vec![2])));
+        assert_eq!(buffer.delta(addr, &vec![2, 3]).unwrap().0, 2);
+    }
+
+    #[test]
+    fn evicts_the_oldest_pending_snapshots() {
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let mut buffer = SnapshotBuffer::new().with_max_pending(2);
+        buffer.insert(addr, 0, ());
+        buffer.ack(addr, 0);
+        for seq in 1..4 {
+            buffer.insert(addr, seq, ());
+        }
+        assert_eq!(buffer.baseline(addr), Some((0, &())));
+        assert!(buffer.get(addr, 1).is_none());
+        assert!(buffer.get(addr, 3).is_some());
+    }
+}
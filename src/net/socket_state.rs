@@ -1,39 +1,92 @@
-use bincode::serialize;
+use bincode::{deserialize, serialize};
+use crossbeam_channel::Sender;
+use rand::{thread_rng, RngCore};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, Weak};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use super::{Connection, Packet, RawPacket, SocketAddr};
+use super::path_mtu::MIN_DATAGRAM_SIZE;
+use super::{ClockSync, Connection, Dropped, ExternalAcks, Packet, PacketHandle, PacketType, Quality, RawPacket, ReplayBuffer, SocketAddr, UdpStats, RAW_PACKET_HEADER_SIZE};
 use error::{NetworkError, Result};
+use events::{ConnectionEvent, DeliveryEvent, SocketEvent};
 
 // Type aliases
 // Number of seconds we will wait until we consider a Connection to have timed out
 type ConnectionTimeout = u64;
 type ConnectionMap = Arc<RwLock<HashMap<SocketAddr, Arc<RwLock<Connection>>>>>;
+// Shared with the timeout check, which may run on another thread
+type EventSender = Arc<RwLock<Option<Sender<SocketEvent>>>>;
 
 // Default timeout of 10 seconds
 const TIMEOUT_DEFAULT: ConnectionTimeout = 10;
 
 // Default time between checks of all clients for timeouts in seconds
-const TIMEOUT_POLL_INTERVAL: u64 = 1;
+pub const TIMEOUT_POLL_INTERVAL: u64 = 1;
+
+// Acked sequence numbers kept for `acked_packets` per connection
+const MAX_ACKED_PACKETS: usize = 1024;
+
+// Dropped packets kept for `dropped_packets` per connection
+const MAX_DROPPED_PACKETS: usize = 1024;
 
 /// This holds the 'virtual connections' currently (connected) to the udp socket.
 pub struct SocketState {
     timeout: ConnectionTimeout,
     connections: ConnectionMap,
+    stats: UdpStats,
+    events: EventSender,
+    // Counted by the timeout check
+    timeouts: Arc<AtomicUsize>,
+    // Clock sync timestamps count from here
+    epoch: Instant,
+    // Delivery events waiting for `take_delivery_events` while there is no event sender
+    delivery_events: Vec<DeliveryEvent>,
+    // Id of the next `PacketHandle`, shared with the worker so it can hand out handles from other threads
+    next_handle: Arc<AtomicU64>,
 }
 
 impl SocketState {
     pub fn new() -> SocketState {
-        let mut socket_state = SocketState {
-            connections: Arc::new(RwLock::new(HashMap::new())),
-            timeout: TIMEOUT_DEFAULT,
-        };
+        let mut socket_state = SocketState::without_timeout_thread();
         socket_state.check_for_timeouts();
         socket_state
     }
 
+    /// Creates a socket state that does not start the thread checking for timeouts, whoever drives it runs the `TimeoutCheck` instead.
+    pub fn without_timeout_thread() -> SocketState {
+        SocketState {
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            timeout: TIMEOUT_DEFAULT,
+            stats: UdpStats::default(),
+            events: Arc::new(RwLock::new(None)),
+            timeouts: Arc::new(AtomicUsize::new(0)),
+            epoch: Instant::now(),
+            delivery_events: Vec::new(),
+            next_handle: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Sends a `ConnectionEvent` to `sender` when a connection is created or times out, and the `DeliveryEvent`s of tracked packets.
+    pub fn set_event_sender(&mut self, sender: Sender<SocketEvent>) {
+        if let Ok(mut events) = self.events.write() {
+            *events = Some(sender);
+        }
+    }
+
+    /// Returns the check that removes timed out connections, it should run every `TIMEOUT_POLL_INTERVAL` seconds.
+    pub fn timeout_check(&self) -> TimeoutCheck {
+        TimeoutCheck {
+            connections: Arc::downgrade(&self.connections),
+            timeout: Duration::from_secs(self.timeout),
+            events: self.events.clone(),
+            timeouts: self.timeouts.clone(),
+        }
+    }
+
+    #[allow(dead_code)]
     pub fn with_client_timeout(mut self, timeout: ConnectionTimeout) -> SocketState {
         self.timeout = timeout;
         self
@@ -48,13 +101,12 @@ impl SocketState {
             lock.waiting_packets.enqueue(seq_num, packet.clone());
         }
 
-        let raw_packet: RawPacket;
         // initialize packet data, seq, acked_seq etc.
         let mut l = connection
             .write()
             .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;
 
-        raw_packet = RawPacket::new(
+        let raw_packet = RawPacket::new(
             l.seq_num,
             &packet,
             l.their_acks.last_seq,
@@ -62,59 +114,382 @@ impl SocketState {
         );
         // increase sequence number
         l.seq_num = l.seq_num.wrapping_add(1);
+        self.stats.sent_packets += 1;
         let buffer = serialize(&raw_packet)?;
         Ok((packet.addr, buffer))
     }
 
-    /// This will return all dropped packets from this connection.
-    pub fn dropped_packets(&mut self, addr: SocketAddr) -> Result<Vec<Packet>> {
+    /// Does the bookkeeping of `pre_process_packet` for a packet that was already serialized, used to replay captures.
+    pub fn process_sent(&mut self, addr: SocketAddr, packet: &RawPacket) -> Result<()> {
+        let connection = self.create_connection_if_not_exists(&addr)?;
+        let mut lock = connection
+            .write()
+            .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;
+
+        if packet.packet_type == PacketType::Data {
+            lock.waiting_packets.enqueue(packet.seq, Packet {
+                addr,
+                payload: packet.payload.clone(),
+            });
+            self.stats.sent_packets += 1;
+        }
+        lock.seq_num = packet.seq.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Builds a path MTU probe for `addr` if one is due, padded so the datagram ends up at the size being probed.
+    ///
+    /// `overhead` is what compression and encryption add to the serialized packet. The padding is random, so it does not compress.
+    pub fn pre_process_probe(&mut self, addr: SocketAddr, overhead: usize, now: Instant) -> Result<Option<Vec<u8>>> {
+        let connection = self.create_connection_if_not_exists(&addr)?;
+        let mut lock = connection
+            .write()
+            .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;
+
+        let size = match lock.mtu.next_probe(now) {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+
+        let mut padding = vec![0; size.saturating_sub(overhead + RAW_PACKET_HEADER_SIZE)];
+        thread_rng().fill_bytes(&mut padding);
+
+        // Probes are not queued for acks, a lost probe is not a dropped packet
+        let seq = lock.seq_num;
+        let raw_packet = RawPacket {
+            seq,
+            ack_seq: lock.their_acks.last_seq,
+            ack_field: lock.their_acks.field,
+            packet_type: PacketType::MtuProbe,
+            payload: padding.into_boxed_slice(),
+        };
+        lock.mtu.probe_sent(seq, size, now);
+        lock.seq_num = seq.wrapping_add(1);
+        Ok(Some(serialize(&raw_packet)?))
+    }
+
+    /// Builds a clock sync ping for `addr` if one is due.
+    pub fn pre_process_time_ping(&mut self, addr: SocketAddr) -> Result<Option<Vec<u8>>> {
+        let now = self.clock_time();
         let connection = self.create_connection_if_not_exists(&addr)?;
         let mut lock = connection
             .write()
             .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;
 
+        if !lock.clock.ping_due(now) {
+            return Ok(None);
+        }
+
+        // Like probes, pings are not queued for acks
+        let raw_packet = RawPacket {
+            seq: lock.seq_num,
+            ack_seq: lock.their_acks.last_seq,
+            ack_field: lock.their_acks.field,
+            packet_type: PacketType::TimePing,
+            payload: serialize(&now)?.into_boxed_slice(),
+        };
+        lock.seq_num = lock.seq_num.wrapping_add(1);
+        Ok(Some(serialize(&raw_packet)?))
+    }
+
+    /// Builds the answer to a clock sync ping from `addr`, with the time of the ping and our time.
+    pub fn pre_process_time_pong(&mut self, addr: SocketAddr, ping: &RawPacket) -> Result<Vec<u8>> {
+        let sent_at: i64 = deserialize(&ping.payload)?;
+        let now = self.clock_time();
+        let connection = self.create_connection_if_not_exists(&addr)?;
+        let mut lock = connection
+            .write()
+            .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;
+
+        let raw_packet = RawPacket {
+            seq: lock.seq_num,
+            ack_seq: lock.their_acks.last_seq,
+            ack_field: lock.their_acks.field,
+            packet_type: PacketType::TimePong,
+            payload: serialize(&(sent_at, now))?.into_boxed_slice(),
+        };
+        lock.seq_num = lock.seq_num.wrapping_add(1);
+        Ok(serialize(&raw_packet)?)
+    }
+
+    /// Returns the time on the clock used for clock sync, in microseconds.
+    pub fn clock_time(&self) -> i64 {
+        self.epoch.elapsed().as_micros() as i64
+    }
+
+    /// Builds the answer to an MTU probe from `addr`.
+    pub fn pre_process_probe_ack(&mut self, addr: SocketAddr) -> Result<Vec<u8>> {
+        let connection = self.create_connection_if_not_exists(&addr)?;
+        let mut lock = connection
+            .write()
+            .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;
+
+        let raw_packet = RawPacket {
+            seq: lock.seq_num,
+            ack_seq: lock.their_acks.last_seq,
+            ack_field: lock.their_acks.field,
+            packet_type: PacketType::MtuProbeAck,
+            payload: Box::new([]),
+        };
+        lock.seq_num = lock.seq_num.wrapping_add(1);
+        Ok(serialize(&raw_packet)?)
+    }
+
+    /// Records that the probe in flight to `addr` could not be sent.
+    pub fn probe_failed(&mut self, addr: SocketAddr) -> Result<()> {
+        let connection = self.create_connection_if_not_exists(&addr)?;
+        let mut lock = connection
+            .write()
+            .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;
+        lock.mtu.probe_failed();
+        Ok(())
+    }
+
+    /// Returns the biggest datagram size confirmed to reach `addr`.
+    pub fn max_datagram_size(&self, addr: SocketAddr) -> usize {
+        self.connections
+            .read()
+            .ok()
+            .and_then(|connections| connections.get(&addr).and_then(|connection| connection.read().ok().map(|lock| lock.mtu.datagram_size())))
+            .unwrap_or(MIN_DATAGRAM_SIZE)
+    }
+
+    /// This will return the dropped packets from this connection since the last call, at most the newest 1024.
+    pub fn dropped_packets(&mut self, addr: SocketAddr) -> Result<Vec<Packet>> {
+        let connection = match self.connection(addr) {
+            Some(connection) => connection,
+            None => return Ok(Vec::new()),
+        };
+        let mut lock = connection
+            .write()
+            .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;
+
         let packets = lock.dropped_packets.drain(..).collect();
         Ok(packets)
     }
 
+    /// Returns the sequence numbers of the packets sent to `addr` that were acked since the last call, oldest first.
+    pub fn acked_packets(&mut self, addr: SocketAddr) -> Result<Vec<u16>> {
+        let connection = match self.connection(addr) {
+            Some(connection) => connection,
+            None => return Ok(Vec::new()),
+        };
+        let mut lock = connection
+            .write()
+            .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;
+
+        Ok(lock.acked_packets.drain(..).collect())
+    }
+
+    /// Returns a new handle for a packet to `addr`.
+    pub fn new_handle(&self, addr: SocketAddr) -> PacketHandle {
+        PacketHandle {
+            addr,
+            id: self.next_handle.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the counter `new_handle` takes the ids from.
+    pub fn handle_ids(&self) -> Arc<AtomicU64> {
+        self.next_handle.clone()
+    }
+
+    /// Emits a `DeliveryEvent` for each of `handles` once the packet sent to `addr` with `seq` is acked or lost.
+    pub fn track(&mut self, addr: SocketAddr, seq: u16, handles: Vec<PacketHandle>) -> Result<()> {
+        if handles.is_empty() {
+            return Ok(());
+        }
+        let connection = self.create_connection_if_not_exists(&addr)?;
+        let mut lock = connection
+            .write()
+            .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;
+
+        lock.tracked_packets.insert(seq, handles);
+        Ok(())
+    }
+
+    /// Returns the delivery events since the last call. These go to the event sender instead when there is one.
+    pub fn take_delivery_events(&mut self) -> Vec<DeliveryEvent> {
+        std::mem::take(&mut self.delivery_events)
+    }
+
+    fn emit_delivery(&mut self, event: DeliveryEvent) {
+        if let Ok(events) = self.events.read() {
+            if let Some(ref sender) = *events {
+                let _ = sender.send(SocketEvent::Delivery(event));
+                return;
+            }
+        }
+        self.delivery_events.push(event);
+    }
+
+    /// Returns the sequence number the next packet to `addr` is sent with.
+    pub fn next_seq(&self, addr: SocketAddr) -> u16 {
+        self.connection(addr)
+            .and_then(|connection| connection.read().ok().map(|lock| lock.seq_num))
+            .unwrap_or(0)
+    }
+
     /// This will process an incoming packet and update acknowledgement information.
-    pub fn process_received(&mut self, addr: SocketAddr, packet: &RawPacket) -> Result<Packet> {
+    ///
+    /// Returns `None` for packets that were already received, these are only counted in the stats, and for packets the protocol uses itself.
+    pub fn process_received(&mut self, addr: SocketAddr, packet: &RawPacket) -> Result<Option<Packet>> {
         let connection = self.create_connection_if_not_exists(&addr)?;
         let mut lock = connection
             .write()
-            .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;;
+            .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;
+
+        if lock.received.already_received(packet.seq) {
+            // Only the packets of the application count, the protocol's own are none of its business
+            if packet.packet_type == PacketType::Data {
+                self.stats.duplicate_packets += 1;
+            }
+            return Ok(None);
+        }
+        lock.received.insert(packet.seq);
+        lock.last_heard = Instant::now();
+        if !lock.connected {
+            lock.connected = true;
+            send_event(&self.events, ConnectionEvent::Connected { conn: connection.clone() });
+        }
 
         lock.their_acks.ack(packet.seq);
+        lock.mtu.ack(packet.ack_seq, packet.ack_field);
         // Update dropped packets if there are any.
         let dropped_packets = lock.waiting_packets.ack(packet.ack_seq, packet.ack_field);
-        lock.dropped_packets = dropped_packets.into_iter().map(|(_, p)| p).collect();
-        Ok(Packet {
+        self.stats.lost_packets += dropped_packets.len();
+        if let Some(rtt) = lock.waiting_packets.take_rtt() {
+            self.stats.rtt.record(rtt);
+        }
+        for &(seq, _) in &dropped_packets {
+            for handle in lock.tracked_packets.remove(&seq).unwrap_or_default() {
+                self.emit_delivery(DeliveryEvent::Lost(handle));
+            }
+        }
+        // Keep the packets that were dropped earlier until they are taken by `dropped_packets`, but only the newest in case nobody takes them
+        lock.dropped_packets.extend(dropped_packets.into_iter().map(|(_, p)| p));
+        let excess = lock.dropped_packets.len().saturating_sub(MAX_DROPPED_PACKETS);
+        lock.dropped_packets.drain(..excess);
+        // Same for the acked ones, but only the newest in case nobody takes them
+        for seq in lock.waiting_packets.take_acked() {
+            for handle in lock.tracked_packets.remove(&seq).unwrap_or_default() {
+                self.emit_delivery(DeliveryEvent::Acked(handle));
+            }
+            if lock.acked_packets.len() == MAX_ACKED_PACKETS {
+                lock.acked_packets.pop_front();
+            }
+            lock.acked_packets.push_back(seq);
+        }
+
+        if packet.packet_type == PacketType::TimePong {
+            match deserialize::<(i64, i64)>(&packet.payload) {
+                Ok((sent_at, answered_at)) => lock.clock.add_sample(sent_at, answered_at, self.clock_time()),
+                Err(e) => debug!("Invalid time pong from {}: {}", addr, e),
+            }
+        }
+
+        if packet.packet_type != PacketType::Data {
+            return Ok(None);
+        }
+
+        self.stats.received_packets += 1;
+        Ok(Some(Packet {
             addr,
             payload: packet.payload.clone(),
-        })
+        }))
+    }
+
+    pub fn stats(&self) -> UdpStats {
+        let mut stats = self.stats;
+        stats.timed_out_connections = self.timeouts.load(Ordering::Relaxed);
+        stats.active_connections = self.connection_count();
+        stats
+    }
+
+    /// Counts the bytes of a datagram that was sent.
+    pub fn record_sent(&mut self, bytes: usize) {
+        self.stats.bytes_sent += bytes;
+    }
+
+    /// Counts the bytes of a datagram that was received.
+    pub fn record_received(&mut self, bytes: usize) {
+        self.stats.bytes_received += bytes;
+    }
+
+    /// Counts a datagram that could not be decrypted, decompressed or deserialized.
+    pub fn record_malformed(&mut self) {
+        self.stats.malformed_packets += 1;
+    }
+
+    /// Counts a datagram the flood protection dropped.
+    pub fn record_dropped(&mut self, dropped: Dropped) {
+        let counter = match dropped {
+            Dropped::Banned => &mut self.stats.banned_packets,
+            Dropped::PacketRate => &mut self.stats.rate_limited_packets,
+            Dropped::ConnectionRate => &mut self.stats.rate_limited_connections,
+            Dropped::ConnectionLimit => &mut self.stats.rejected_connection_limit,
+        };
+        *counter += 1;
+    }
+
+    /// Returns the connection with the given address, if there is one.
+    pub fn connection(&self, addr: SocketAddr) -> Option<Arc<RwLock<Connection>>> {
+        self.connections.read().ok().and_then(|connections| connections.get(&addr).cloned())
+    }
+
+    /// Forgets the sequence numbers received from `addr`, for when it started a new session and counts from scratch.
+    pub fn reset_received(&mut self, addr: SocketAddr) {
+        if let Some(connection) = self.connection(addr) {
+            if let Ok(mut lock) = connection.write() {
+                lock.received = ReplayBuffer::new();
+                lock.their_acks = ExternalAcks::new();
+            }
+        }
+    }
+
+    /// Returns the clock sync of the connection with `addr`, if there is one.
+    pub fn clock(&self, addr: SocketAddr) -> Option<ClockSync> {
+        self.connection(addr).and_then(|connection| connection.read().ok().map(|lock| lock.clock.clone()))
+    }
+
+    /// Returns the quality of the connection with `addr`, `Good` if there is none yet.
+    pub fn quality(&self, addr: SocketAddr) -> Quality {
+        self.connection(addr)
+            .and_then(|connection| connection.read().ok().map(|lock| lock.quality))
+            .unwrap_or(Quality::Good)
+    }
+
+    /// Returns true if a packet of `packet_type` received from `addr` should not count against flood protection, see `PathMtu::take_free_packet`.
+    pub fn is_free_packet(&self, addr: SocketAddr, packet_type: PacketType) -> bool {
+        if packet_type != PacketType::MtuProbe && packet_type != PacketType::MtuProbeAck {
+            return false;
+        }
+        self.connection(addr)
+            .and_then(|connection| connection.write().ok().map(|mut lock| lock.mtu.take_free_packet()))
+            .unwrap_or(false)
+    }
+
+    /// Returns true if there is a connection with the given address.
+    pub fn has_connection(&self, addr: SocketAddr) -> bool {
+        self.connections.read().is_ok_and(|connections| connections.contains_key(&addr))
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.connections.read().map(|connections| connections.len()).unwrap_or(0)
     }
 
     // Regularly checks the last_heard attribute of all the connections in the manager to see if any have timed out
     fn check_for_timeouts(&mut self) {
-        let connections_lock = self.connections.clone();
-        let sleepy_time = Duration::from_secs(self.timeout);
+        let check = self.timeout_check();
         let poll_interval = Duration::from_secs(TIMEOUT_POLL_INTERVAL);
 
         thread::Builder::new()
             .name("check_for_timeouts".into())
-            .spawn(move || loop {
-                let connections = connections_lock.read().expect("Unable to aquire read lock");
-
-                for (key, value) in connections.iter() {
-                    let connection = value.read().expect("Unable to aquire read lock");
-                    let last_heard = connection.last_heard();
-                    if last_heard >= sleepy_time {
-                        // TODO: pass up client TimedOut event
-                        error!("Client has timed out: {:?}", key);
-                    }
+            .spawn(move || {
+                // The thread ends once the socket state is dropped
+                while check.run() {
+                    thread::sleep(poll_interval)
                 }
-
-                thread::sleep(poll_interval)
             }).unwrap();
     }
 
@@ -124,35 +499,102 @@ impl SocketState {
         &mut self,
         addr: &SocketAddr,
     ) -> Result<Arc<RwLock<Connection>>> {
+        // Most datagrams come from known addresses, those only need the read lock
+        if let Some(connection) = self
+            .connections
+            .read()
+            .map_err(|_| NetworkError::AddConnectionToManagerFailed)?
+            .get(addr)
+        {
+            return Ok(connection.clone());
+        }
+
         let mut lock = self
             .connections
             .write()
             .map_err(|_| NetworkError::AddConnectionToManagerFailed)?;
 
-        let connection = lock
-            .entry(*addr)
-            .or_insert_with(|| Arc::new(RwLock::new(Connection::new(*addr))));
+        let connection = match lock.entry(*addr) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => entry.insert(Arc::new(RwLock::new(Connection::new(*addr)))).clone(),
+        };
+
+        Ok(connection)
+    }
+}
+
+fn send_event(events: &EventSender, event: ConnectionEvent) {
+    if let Ok(events) = events.read() {
+        if let Some(ref sender) = *events {
+            // Nobody is listening anymore once the receiver is gone, that is fine
+            let _ = sender.send(SocketEvent::Connection(event));
+        }
+    }
+}
+
+/// Removes the connections of a `SocketState` that have not been heard from within the timeout.
+///
+/// It only holds a weak reference to the connections, so it does not keep a dropped socket state alive.
+pub struct TimeoutCheck {
+    connections: Weak<RwLock<HashMap<SocketAddr, Arc<RwLock<Connection>>>>>,
+    timeout: Duration,
+    events: EventSender,
+    timeouts: Arc<AtomicUsize>,
+}
+
+impl TimeoutCheck {
+    /// Removes timed out connections. Returns false once the socket state is gone.
+    pub fn run(&self) -> bool {
+        let connections_lock = match self.connections.upgrade() {
+            Some(connections) => connections,
+            None => return false,
+        };
+
+        // The read lock is dropped at the end of this block, timed out connections are removed under the write lock
+        let timed_out: Vec<SocketAddr> = {
+            let connections = connections_lock.read().expect("Unable to aquire read lock");
+
+            connections
+                .iter()
+                .filter(|&(_, value)| value.read().expect("Unable to aquire read lock").last_heard() >= self.timeout)
+                .map(|(key, _)| *key)
+                .collect()
+        };
 
-        Ok(connection.clone())
+        // Forget timed out connections, so they do not count against the connection limit
+        if !timed_out.is_empty() {
+            let mut connections = connections_lock.write().expect("Unable to aquire write lock");
+            for key in timed_out {
+                error!("Client has timed out: {:?}", key);
+                if let Some(connection) = connections.remove(&key) {
+                    self.timeouts.fetch_add(1, Ordering::Relaxed);
+                    send_event(&self.events, ConnectionEvent::TimedOut { conn: connection });
+                }
+            }
+        }
+        true
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::SocketState;
+    use super::{SocketState, MAX_DROPPED_PACKETS};
+    use crossbeam_channel::unbounded;
+    use events::{ConnectionEvent, SocketEvent};
     use net::connection::Connection;
+    use packet::{Packet, RawPacket};
     use std::net::ToSocketAddrs;
     use std::{thread, time};
-    static TEST_HOST_IP: &'static str = "127.0.0.1";
-    static TEST_BAD_HOST_IP: &'static str = "800.0.0.1";
-    static TEST_PORT: &'static str = "20000";
+    static TEST_HOST_IP: &str = "127.0.0.1";
+    static TEST_BAD_HOST_IP: &str = "800.0.0.1";
+    static TEST_PORT: &str = "20000";
 
     #[test]
     fn test_create_connection() {
         let addr = format!("{}:{}", TEST_HOST_IP, TEST_PORT).to_socket_addrs();
         assert!(addr.is_ok());
         let mut addr = addr.unwrap();
-        let new_conn = Connection::new(addr.next().unwrap());
+        let _new_conn = Connection::new(addr.next().unwrap());
     }
 
     #[test]
@@ -161,6 +603,92 @@ mod test {
         assert!(addr.is_err());
     }
 
+    #[test]
+    fn drops_and_counts_duplicate_packets() {
+        let mut socket_state = SocketState::new();
+        let addr = format!("{}:{}", TEST_HOST_IP, TEST_PORT).to_socket_addrs().unwrap().next().unwrap();
+        let packet = RawPacket::new(7, &Packet::new(addr, vec![1]), 0, 0);
+
+        assert!(socket_state.process_received(addr, &packet).unwrap().is_some());
+        assert!(socket_state.process_received(addr, &packet).unwrap().is_none());
+        assert!(socket_state.process_received(addr, &packet).unwrap().is_none());
+
+        let stats = socket_state.stats();
+        assert_eq!(stats.received_packets, 1);
+        assert_eq!(stats.duplicate_packets, 2);
+    }
+
+    #[test]
+    fn connects_when_a_packet_is_received() {
+        let mut socket_state = SocketState::without_timeout_thread();
+        let (sender, receiver) = unbounded();
+        socket_state.set_event_sender(sender);
+        let addr = format!("{}:{}", TEST_HOST_IP, TEST_PORT).to_socket_addrs().unwrap().next().unwrap();
+
+        // Looking up an unknown address does not connect it
+        assert!(socket_state.dropped_packets(addr).unwrap().is_empty());
+        assert!(socket_state.acked_packets(addr).unwrap().is_empty());
+        assert!(!socket_state.has_connection(addr));
+
+        socket_state.pre_process_packet(Packet::new(addr, vec![1])).unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        for seq in 0..2 {
+            let packet = RawPacket::new(seq, &Packet::new(addr, vec![1]), 0, 0);
+            socket_state.process_received(addr, &packet).unwrap();
+        }
+        match receiver.try_recv() {
+            Ok(SocketEvent::Connection(ConnectionEvent::Connected { conn })) => assert_eq!(conn.read().unwrap().remote_address, addr),
+            e => panic!("unexpected event: {:?}", e),
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn keeps_a_limited_number_of_dropped_packets() {
+        let mut socket_state = SocketState::without_timeout_thread();
+        let addr = format!("{}:{}", TEST_HOST_IP, TEST_PORT).to_socket_addrs().unwrap().next().unwrap();
+        for _ in 0..2000 {
+            socket_state.pre_process_packet(Packet::new(addr, vec![1])).unwrap();
+        }
+
+        // Acks only the last one, so everything older than 32 packets is dropped
+        let packet = RawPacket::new(0, &Packet::new(addr, vec![1]), 1999, 0);
+        socket_state.process_received(addr, &packet).unwrap();
+        assert_eq!(socket_state.stats().lost_packets, 2000 - 33);
+        assert_eq!(socket_state.dropped_packets(addr).unwrap().len(), MAX_DROPPED_PACKETS);
+        assert!(socket_state.dropped_packets(addr).unwrap().is_empty());
+    }
+
+    #[test]
+    fn connections_that_keep_receiving_do_not_time_out() {
+        let mut socket_state = SocketState::without_timeout_thread().with_client_timeout(1);
+        let check = socket_state.timeout_check();
+        let addr = format!("{}:{}", TEST_HOST_IP, TEST_PORT).to_socket_addrs().unwrap().next().unwrap();
+
+        for seq in 0..8 {
+            let packet = RawPacket::new(seq, &Packet::new(addr, vec![1]), 0, 0);
+            socket_state.process_received(addr, &packet).unwrap();
+            thread::sleep(time::Duration::from_millis(200));
+            check.run();
+            assert!(socket_state.has_connection(addr));
+        }
+
+        thread::sleep(time::Duration::from_millis(1000));
+        check.run();
+        assert!(!socket_state.has_connection(addr));
+    }
+
+    #[test]
+    fn timeout_check_ends_with_the_socket_state() {
+        let socket_state = SocketState::without_timeout_thread();
+        let check = socket_state.timeout_check();
+        assert!(check.run());
+
+        drop(socket_state);
+        assert!(!check.run());
+    }
+
     #[test]
     fn test_poll_for_invalid_clients() {
         let mut socket_state = SocketState::new();
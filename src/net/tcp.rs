@@ -1,16 +1,23 @@
+use std::cmp;
 use std::io;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::TcpListener;
-use std::net::{SocketAddr};
-use std::io::{BufRead, Write};
-use std::io::{BufReader, BufWriter};
+use std::net::{IpAddr, SocketAddr};
+use std::io::{BufWriter, Read, Write};
 use std::net::{Shutdown, TcpStream};
 use std::thread;
 use std::thread::JoinHandle;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::*;
+use std::time::{Duration, Instant};
 
 use error::{Error, Result, NetworkError};
+use events::{DisconnectReason, TcpEvent};
+use super::frame::{Frame, FrameBuffer, MAX_FRAME_SIZE};
+
+// How long the listening thread sleeps when no stream is waiting, which is also how long `TcpServer::stop` may wait for it
+const ACCEPT_POLL_INTERVAL_MS: u64 = 10;
 
 /* Summary of How This Works
 This module has three main components:
@@ -19,92 +26,504 @@ This module has three main components:
 3. The TcpClient
 
 The desired flow is:
-1. Asynchronously listen for new client connections in a background thread. This thread blocks until a new connection is attempted.
-2. Create a TCP client and add it to the connections hash
-3. The TCP client starts a background thread that listens for incoming data, which it can then do whatever it wants with
-4. The TCP client starts a background thread that listens for incoming data on the *Rust mpsc channel*, and sends it out to the client. This is an important distinction. The TCP client has incoming data from both the application (game) and the remote endpoint. How each of those send data to the client is different.
+1. Asynchronously listen for new client connections in a background thread. The listener is non-blocking, so this thread polls it until a new connection is attempted or the server is stopped.
+2. Check the new stream against the `AcceptPolicy` (IP lists, accept callback, then the connection limit while inserting it into the connections hash). Rejected streams are shut down and counted in the stats.
+3. Create a TCP client and add it to the connections hash
+4. The TCP client starts a background thread that listens for incoming frames, which it passes on as `TcpEvent::Message`s and answers pings with pongs
+5. The TCP client starts a background thread that listens for incoming data on the *Rust mpsc channel*, and sends it out to the client. When the heartbeat is enabled it also sends a ping every interval. This is an important distinction. The TCP client has incoming data from both the application (game) and the remote endpoint. How each of those send data to the client is different.
+6. When either of the client threads sees the stream end (EOF, reset, a failed write, a read timeout or a missed heartbeat) it removes the client from the connections hash and emits a `TcpEvent::Disconnected`. Removing the client shuts the stream down and drops the channel sender, which makes the other thread exit as well.
+7. `TcpSocketState::shutdown` stops the listening thread, closes every client stream and joins all the threads that were started.
 */
 
 // Type alias for a thread-safe hashmap of connections
 type Connections = Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<TcpClient>>>>>;
-type MessageSender = Option<Sender<String>>;
-type MessageReceiver = Option<Receiver<String>>;
+type MessageSender = Option<Sender<Frame>>;
+type MessageReceiver = Option<Receiver<Frame>>;
+type EventSender = Sender<TcpEvent>;
+type AcceptFilter = Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>;
+
+/// Decides which incoming streams the TCP server turns into clients.
+#[derive(Clone, Default)]
+pub struct AcceptPolicy {
+    max_connections: Option<usize>,
+    allowed_ips: HashSet<IpAddr>,
+    denied_ips: HashSet<IpAddr>,
+    filter: Option<AcceptFilter>,
+}
+
+impl AcceptPolicy {
+    /// Returns why a stream from `addr` should be rejected, or `None` if it may be accepted. The connection limit is checked separately, see `check_limit`.
+    ///
+    /// The accept callback only runs when the IP checks passed.
+    fn check(&self, addr: SocketAddr) -> Option<Rejection> {
+        if self.denied_ips.contains(&addr.ip()) || (!self.allowed_ips.is_empty() && !self.allowed_ips.contains(&addr.ip())) {
+            return Some(Rejection::Ip);
+        }
+
+        match self.filter {
+            Some(ref filter) if !filter(addr) => Some(Rejection::Filter),
+            _ => None,
+        }
+    }
+
+    /// Returns `Rejection::ConnectionLimit` if `inbound` accepted clients are already connected and no more are allowed.
+    fn check_limit(&self, inbound: usize) -> Option<Rejection> {
+        match self.max_connections {
+            Some(max) if inbound >= max => Some(Rejection::ConnectionLimit),
+            _ => None,
+        }
+    }
+}
+
+/// Why an incoming stream was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rejection {
+    ConnectionLimit,
+    Ip,
+    Filter,
+}
+
+/// Counters kept by the TCP server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpStats {
+    /// Streams that were accepted and turned into clients.
+    pub accepted: usize,
+    /// Streams rejected because the maximum number of clients was reached.
+    pub rejected_connection_limit: usize,
+    /// Streams rejected by the IP allow or deny list.
+    pub rejected_ip: usize,
+    /// Streams rejected by the accept callback.
+    pub rejected_filter: usize,
+    /// Clients that are connected right now.
+    pub active_connections: usize,
+    /// Bytes written to client streams, frame headers included.
+    pub bytes_sent: usize,
+    /// Bytes read from client streams.
+    pub bytes_received: usize,
+    /// Data frames written to client streams.
+    pub messages_sent: usize,
+    /// Data frames read from client streams.
+    pub messages_received: usize,
+    /// Clients disconnected by the read timeout or the heartbeat.
+    pub timeouts: usize,
+    /// Clients disconnected because they sent an invalid frame.
+    pub malformed_frames: usize,
+}
+
+impl TcpStats {
+    /// Returns the total number of rejected streams.
+    pub fn rejected(&self) -> usize {
+        self.rejected_connection_limit + self.rejected_ip + self.rejected_filter
+    }
+}
+
+// The live counters behind `TcpStats`, shared with the listening and client threads
+#[derive(Debug, Default)]
+struct StatsCounters {
+    accepted: AtomicUsize,
+    rejected_connection_limit: AtomicUsize,
+    rejected_ip: AtomicUsize,
+    rejected_filter: AtomicUsize,
+    bytes_sent: AtomicUsize,
+    bytes_received: AtomicUsize,
+    messages_sent: AtomicUsize,
+    messages_received: AtomicUsize,
+    timeouts: AtomicUsize,
+    malformed_frames: AtomicUsize,
+}
+
+impl StatsCounters {
+    fn record_rejection(&self, rejection: Rejection) {
+        let counter = match rejection {
+            Rejection::ConnectionLimit => &self.rejected_connection_limit,
+            Rejection::Ip => &self.rejected_ip,
+            Rejection::Filter => &self.rejected_filter,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, active_connections: usize) -> TcpStats {
+        TcpStats {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            rejected_connection_limit: self.rejected_connection_limit.load(Ordering::Relaxed),
+            rejected_ip: self.rejected_ip.load(Ordering::Relaxed),
+            rejected_filter: self.rejected_filter.load(Ordering::Relaxed),
+            active_connections,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            malformed_frames: self.malformed_frames.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Socket options and heartbeat settings applied to the stream of every client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamOptions {
+    nodelay: bool,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    heartbeat: Option<Heartbeat>,
+}
+
+impl StreamOptions {
+    // How long a read may block before the incoming loop checks the read timeout and heartbeat deadline again
+    fn poll_interval(&self) -> Option<Duration> {
+        match (self.read_timeout, self.heartbeat) {
+            (Some(timeout), Some(heartbeat)) => Some(cmp::min(timeout, heartbeat.timeout)),
+            (Some(timeout), None) => Some(timeout),
+            (None, Some(heartbeat)) => Some(heartbeat.timeout),
+            (None, None) => None,
+        }
+    }
+}
+
+impl Default for StreamOptions {
+    fn default() -> StreamOptions {
+        // Game messages are small and should go out right away, so Nagle's algorithm is off by default
+        StreamOptions {
+            nodelay: true,
+            read_timeout: None,
+            write_timeout: None,
+            heartbeat: None,
+        }
+    }
+}
+
+// Sockets reject a zero timeout and a zero ping interval would spin, so zero means disabled like it does for `None`
+fn non_zero(duration: Duration) -> Option<Duration> {
+    if duration == Duration::from_secs(0) {
+        None
+    } else {
+        Some(duration)
+    }
+}
+
+// Send a ping every `interval` and expect a pong within `timeout` after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+}
 
 /// Container struct that keeps the hash map of connections
 pub struct TcpSocketState {
     connections: Connections,
+    server: Option<TcpServer>,
+    policy: AcceptPolicy,
+    options: StreamOptions,
+    stats: Arc<StatsCounters>,
+    event_tx: EventSender,
+    event_rx: Receiver<TcpEvent>,
 }
 
 impl TcpSocketState {
     /// Creates and returns a new TcpSocketState
     pub fn new() -> TcpSocketState {
+        let (event_tx, event_rx) = channel();
         TcpSocketState{
-            connections: Arc::new(Mutex::new(HashMap::new()))
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            server: None,
+            policy: AcceptPolicy::default(),
+            options: StreamOptions::default(),
+            stats: Arc::new(StatsCounters::default()),
+            event_tx,
+            event_rx,
         }
     }
 
+    /// Limits the number of clients the server accepts that can be connected at the same time. Streams opened with `connect` do not count.
+    pub fn with_max_connections(mut self, max_connections: usize) -> TcpSocketState {
+        self.policy.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Only accepts streams from the given IP. Can be called several times to allow more IPs; when it is never called every IP is allowed.
+    pub fn with_allowed_ip(mut self, ip: IpAddr) -> TcpSocketState {
+        self.policy.allowed_ips.insert(ip);
+        self
+    }
+
+    /// Rejects every stream from the given IP, even if it is also on the allow list.
+    pub fn with_denied_ip(mut self, ip: IpAddr) -> TcpSocketState {
+        self.policy.denied_ips.insert(ip);
+        self
+    }
+
+    /// Sets a callback that gets the address of every incoming stream before a client is created for it. Streams for which it returns `false` are rejected.
+    pub fn with_accept_filter<F>(mut self, filter: F) -> TcpSocketState
+    where
+        F: Fn(SocketAddr) -> bool + Send + Sync + 'static,
+    {
+        self.policy.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Enables or disables `TCP_NODELAY` (Nagle's algorithm) on client streams. It is enabled by default.
+    pub fn with_nodelay(mut self, nodelay: bool) -> TcpSocketState {
+        self.options.nodelay = nodelay;
+        self
+    }
+
+    /// Disconnects clients we have not received anything from for `timeout`. A zero `timeout` disables it.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> TcpSocketState {
+        self.options.read_timeout = non_zero(timeout);
+        self
+    }
+
+    /// Disconnects clients when writing to their stream blocks for longer than `timeout`. A zero `timeout` disables it.
+    pub fn with_write_timeout(mut self, timeout: Duration) -> TcpSocketState {
+        self.options.write_timeout = non_zero(timeout);
+        self
+    }
+
+    /// Sends a ping to every client each `interval` and disconnects clients that do not answer with a pong within `timeout`. A zero `interval` or `timeout` disables the heartbeat.
+    pub fn with_heartbeat(mut self, interval: Duration, timeout: Duration) -> TcpSocketState {
+        self.options.heartbeat = match (non_zero(interval), non_zero(timeout)) {
+            (Some(interval), Some(timeout)) => Some(Heartbeat { interval, timeout }),
+            _ => None,
+        };
+        self
+    }
+
     /// This starts a TCP server on the provided SocketAddr. It is important to note that it also passes an Arc reference down to the server.
-    pub fn start(&mut self, addr: SocketAddr) -> Result<JoinHandle<()>> {
-        TcpServer::listen(addr, self.connections.clone())
+    ///
+    /// The listening socket is bound before this returns, so binding errors are returned here. On success the address the server is listening on is returned.
+    pub fn start(&mut self, addr: SocketAddr) -> Result<SocketAddr> {
+        if self.server.is_some() {
+            return Err(Error::from(NetworkError::TcpServerAlreadyRunning));
+        }
+
+        let server = TcpServer::listen(
+            addr,
+            self.connections.clone(),
+            self.event_tx.clone(),
+            self.policy.clone(),
+            self.options,
+            self.stats.clone(),
+        )?;
+        let local_addr = server.local_addr();
+        self.server = Some(server);
+        Ok(local_addr)
+    }
+
+    /// Connects to a remote TCP server. The stream is handled exactly like the streams accepted by `start`, so it shows up in the connections hash under `addr` and generates the same events.
+    pub fn connect(&mut self, addr: SocketAddr) -> Result<()> {
+        let stream = TcpStream::connect(addr)?;
+        TcpServer::handle_connection(stream, self.connections.clone(), self.event_tx.clone(), None, self.options, self.stats.clone())
+    }
+
+    /// Stops accepting new connections, closes the stream of every connected client and joins all the background threads.
+    ///
+    /// A `Disconnected` event with `DisconnectReason::Shutdown` is generated for every client that was still connected.
+    pub fn shutdown(&mut self) {
+        if let Some(mut server) = self.server.take() {
+            server.stop();
+        }
+
+        // The lock is released before joining, because the client threads lock the hash when they stop
+        let clients: Vec<(SocketAddr, Arc<Mutex<TcpClient>>)> = match self.connections.lock() {
+            Ok(mut l) => l.drain().collect(),
+            Err(poisoned) => poisoned.into_inner().drain().collect(),
+        };
+
+        for (addr, client) in clients {
+            let threads = match client.lock() {
+                Ok(mut l) => l.close(),
+                Err(poisoned) => poisoned.into_inner().close(),
+            };
+            for thread in threads {
+                let _ = thread.join();
+            }
+            let _ = self.event_tx.send(TcpEvent::Disconnected { addr, reason: DisconnectReason::Shutdown });
+        }
+    }
+
+    /// Returns true if the server is accepting new connections.
+    pub fn is_running(&self) -> bool {
+        self.server.is_some()
+    }
+
+    /// Returns an iterator over all the events that have been generated since the last call, without blocking.
+    pub fn events(&self) -> TryIter<'_, TcpEvent> {
+        self.event_rx.try_iter()
+    }
+
+    /// Queues a message to be written to the client with the given address.
+    pub fn send(&self, addr: SocketAddr, payload: Vec<u8>) -> Result<()> {
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(Error::from(NetworkError::TcpMessageTooLarge));
+        }
+
+        let client = self
+            .connections
+            .lock()
+            .map_err(|_| NetworkError::TcpClientConnectionsHashPoisoned)?
+            .get(&addr)
+            .cloned()
+            .ok_or(NetworkError::TcpClientNotFound)?;
+
+        let l = client.lock().map_err(|_| NetworkError::TcpClientLockFailed)?;
+        match l.tx {
+            Some(ref tx) if tx.send(Frame::Data(payload)).is_ok() => Ok(()),
+            _ => Err(Error::from(NetworkError::TcpClientNotFound)),
+        }
+    }
+
+    /// Returns a snapshot of the counters.
+    pub fn stats(&self) -> TcpStats {
+        self.stats.snapshot(self.connection_count())
+    }
+
+    /// Returns the number of clients that are currently connected.
+    pub fn connection_count(&self) -> usize {
+        match self.connections.lock() {
+            Ok(l) => l.len(),
+            Err(poisoned) => poisoned.into_inner().len(),
+        }
+    }
+}
+
+impl Default for TcpSocketState {
+    fn default() -> TcpSocketState {
+        TcpSocketState::new()
+    }
+}
+
+impl Drop for TcpSocketState {
+    fn drop(&mut self) {
+        self.shutdown();
     }
 }
 
 /// Wrapper around a TcpListener
-pub struct TcpServer;
+pub struct TcpServer {
+    local_addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
 
-/// Using `self` to do deal with the threading proved to be very complicated. That is why these functions do use `self`.
+/// Using `self` to do deal with the threading proved to be very complicated. That is why the functions that run on the background threads do not use `self`.
 impl TcpServer {
 
-    /// Starts the TcpServer listening socket. When a new connection is accepted, it spawns a new thread dedicated to that client and goes back to listening for more connections.
-    pub fn listen(addr: SocketAddr, connections: Connections) -> Result<JoinHandle<()>> {
-        Ok(thread::spawn(move || {
-            let listener = match TcpListener::bind(addr) {
-                Ok(l) => { l }
-                Err(e) => {
-                    error!("Error binding to listening socket: {}", e);
-                    return;
-                }
+    /// Binds the TcpServer listening socket and starts accepting on a background thread. When a new connection is accepted, it spawns new threads dedicated to that client and goes back to listening for more connections.
+    fn listen(addr: SocketAddr, connections: Connections, events: EventSender, policy: AcceptPolicy, options: StreamOptions, stats: Arc<StatsCounters>) -> Result<TcpServer> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+
+        let thread = thread::Builder::new()
+            .name("tcp_listener".into())
+            .spawn(move || TcpServer::accept_loop(listener, thread_running, connections, events, policy, options, stats))?;
+
+        Ok(TcpServer {
+            local_addr,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting new connections and waits for the listening thread to exit.
+    pub fn stop(&mut self) {
+        // The listening thread checks the flag every poll interval, so this does not depend on the network
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    fn accept_loop(listener: TcpListener, running: Arc<AtomicBool>, connections: Connections, events: EventSender, policy: AcceptPolicy, options: StreamOptions, stats: Arc<StatsCounters>) {
+        while running.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    // Some platforms pass the non-blocking flag of the listener on to the streams it accepts
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        error!("Error accepting new TCP connection: {}", e);
+                        continue;
+                    }
+                    if let Some(rejection) = TcpServer::check_policy(&stream, &policy) {
+                        TcpServer::reject(&stream, rejection, &stats);
+                        continue;
+                    }
+
+                    // Now we call a function and pass it the stream, and a clone of the connections hash
+                    match TcpServer::handle_connection(stream, connections.clone(), events.clone(), Some(&policy), options, stats.clone()) {
+                        Ok(c) => {
+                            debug!("New TCP connection: {:?}", c);
+                        },
+                        Err(e) => {
+                            error!("Error accepting new TCP connection: {}", e);
+                        }
+                    };
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(ACCEPT_POLL_INTERVAL_MS)),
+                Err(e) => { debug!("Error accepting new TCP stream: {}", e); }
             };
-            // This is a blocking call, so the thread waits here until it gets a new connection
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        // Now we call a function and pass it the stream, and a clone of the connections hash
-                        match TcpServer::handle_connection(stream, connections.clone()) {
-                            Ok(c) => {
-                                debug!("New TCP connection: {:?}", c);
-                            },
-                            Err(e) => {
-                                error!("Error accepting new TCP connection: {}", e);
-                            }
-                        };
-                    },
-                    Err(e) => { debug!("Error accepting new TCP stream: {}", e); }
-                };
-            }
-        }))
+        }
+    }
+
+    // Runs the IP checks and the accept callback for a new stream, outside the connections lock so the callback may use the `TcpSocketState`
+    fn check_policy(stream: &TcpStream, policy: &AcceptPolicy) -> Option<Rejection> {
+        match stream.peer_addr() {
+            Ok(addr) => policy.check(addr),
+            // The stream is already gone, `handle_connection` will report the error
+            Err(_) => None,
+        }
+    }
+
+    fn reject(stream: &TcpStream, rejection: Rejection, stats: &StatsCounters) {
+        debug!("Rejected TCP stream from {:?}: {:?}", stream.peer_addr(), rejection);
+        stats.record_rejection(rejection);
+        let _ = stream.shutdown(Shutdown::Both);
     }
 
     /// This function inserts a reference to the connection into the connections hash
-    pub fn handle_connection(stream: TcpStream, connections: Connections) -> Result<()> {
+    ///
+    /// Streams accepted by the server pass their `policy`, its connection limit is checked under the same lock as the insert and only counts the other accepted streams.
+    /// Streams we connected ourselves pass `None`.
+    fn handle_connection(stream: TcpStream, connections: Connections, events: EventSender, policy: Option<&AcceptPolicy>, options: StreamOptions, stats: Arc<StatsCounters>) -> Result<()> {
         let peer_addr = stream.peer_addr()?;
         let tmp_stream = stream.try_clone()?;
-        let tcp_client = Arc::new(Mutex::new(TcpClient::new(stream)?));
+        let mut client = TcpClient::new(stream, &options)?;
+        client.inbound = policy.is_some();
+        let tcp_client = Arc::new(Mutex::new(client));
 
         if !connections.is_poisoned() {
             if let Ok(mut locked_connections) = connections.lock() {
+                if let Some(policy) = policy {
+                    let inbound = locked_connections.values().filter(|c| TcpClient::is_inbound(c)).count();
+                    if let Some(rejection) = policy.check_limit(inbound) {
+                        TcpServer::reject(&tmp_stream, rejection, &stats);
+                        return Ok(());
+                    }
+                    stats.accepted.fetch_add(1, Ordering::Relaxed);
+                }
                 locked_connections.insert(peer_addr, tcp_client.clone());
-                // Pass it off to a function to handle setting up the client-specific background threads
-                TcpClient::run(tcp_client);
-                Ok(())
             } else {
                 // If we can't get the lock, send a shutdown to the client and they will have to try again
-                tmp_stream.shutdown(Shutdown::Both);
-                Ok(())
+                let _ = tmp_stream.shutdown(Shutdown::Both);
+                return Ok(());
+            }
+
+            let _ = events.send(TcpEvent::Connected { addr: peer_addr });
+            // Pass it off to a function to handle setting up the client-specific background threads
+            if let Err(e) = TcpClient::run(tcp_client, peer_addr, connections.clone(), events.clone(), options, stats) {
+                TcpClient::disconnect(peer_addr, &connections, &events, DisconnectReason::Closed);
+                return Err(e);
             }
+            Ok(())
         } else {
-            tmp_stream.shutdown(Shutdown::Both);
-            return Err(Error::from(NetworkError::TcpClientConnectionsHashPoisoned));
+            let _ = tmp_stream.shutdown(Shutdown::Both);
+            Err(Error::from(NetworkError::TcpClientConnectionsHashPoisoned))
         }
     }
 }
@@ -112,88 +531,165 @@ impl TcpServer {
 /// A remote client connected via a TcpStream
 #[derive(Debug)]
 pub struct TcpClient {
-    reader: BufReader<TcpStream>,
-    writer: BufWriter<TcpStream>,
     raw_stream: TcpStream,
     tx: MessageSender,
     rx: MessageReceiver,
+    threads: Vec<JoinHandle<()>>,
+    // Accepted by our server, as opposed to a stream we connected ourselves
+    inbound: bool,
 }
 
 impl TcpClient {
-    /// Creates and returns a new TcpClient. It makes a few references to the raw stream and wraps them in BufReader and BufWriter for convenience.
-    pub fn new(stream: TcpStream) -> Result<TcpClient> {
-        let reader = BufReader::new(stream.try_clone()?);
-        let writer = BufWriter::new(stream.try_clone()?);
+    /// Creates and returns a new TcpClient and applies the socket options to its stream. Data for the client is written by a background thread, see `TcpSocketState::send`.
+    pub fn new(stream: TcpStream, options: &StreamOptions) -> Result<TcpClient> {
+        stream.set_nodelay(options.nodelay)?;
+        stream.set_write_timeout(options.write_timeout)?;
+        stream.set_read_timeout(options.poll_interval())?;
+
         let (tx, rx) = channel();
         Ok(TcpClient{
-            reader,
-            writer,
             raw_stream: stream,
             tx: Some(tx),
             rx: Some(rx),
+            threads: Vec::new(),
+            inbound: false,
         })
     }
 
-    /// Sets up the background loop that waits for data to be received on the rx channel that is meant to be sent to the remote client, then enters a loop to watch for input *from* the remote endpoint.
-    pub fn run(client: Arc<Mutex<TcpClient>>) -> Result<()>{
-        TcpClient::start_recv(client.clone())?;
-        let mut buf = String::new();
-        loop {
-            if let Ok(mut l) = client.lock() {
-                match l.reader.read_line(&mut buf) {
-                    Ok(_) => {
-                        // TODO: Generate an event here with the payload?
-                    }
-                    Err(e) => {
-                        error!("Error receiving: {:#?}", e);
-                    }
-                }
-            } else {
-                return Err(Error::from(NetworkError::TcpClientLockFailed));
-            }
+    fn is_inbound(client: &Mutex<TcpClient>) -> bool {
+        match client.lock() {
+            Ok(l) => l.inbound,
+            Err(poisoned) => poisoned.into_inner().inbound,
         }
     }
 
-    fn start_recv(client: Arc<Mutex<TcpClient>>) -> Result<()> {
-        if let Ok(mut l) = client.lock() {
-            match l.outgoing_loop() {
-                Ok(_) => {
-                    Ok(())
-                }
-                Err(e) => {
-                    return Err(e);
-                }
+    /// Sets up the background loop that waits for data to be received on the rx channel that is meant to be sent to the remote client, and a second background loop that watches for input *from* the remote endpoint.
+    fn run(client: Arc<Mutex<TcpClient>>, addr: SocketAddr, connections: Connections, events: EventSender, options: StreamOptions, stats: Arc<StatsCounters>) -> Result<()> {
+        let (reader, tx) = if let Ok(l) = client.lock() {
+            let reader = match l.raw_stream.try_clone() {
+                Ok(stream) => stream,
+                Err(_) => return Err(Error::from(NetworkError::TcpStreamCloneFailed)),
+            };
+            // The incoming loop needs a sender of its own to answer pings
+            match l.tx {
+                Some(ref tx) => (reader, tx.clone()),
+                None => return Err(Error::from(NetworkError::TcpClientNotFound)),
             }
         } else {
             return Err(Error::from(NetworkError::TcpClientLockFailed));
+        };
+
+        let ping_interval = options.heartbeat.map(|heartbeat| heartbeat.interval);
+        let outgoing = TcpClient::start_recv(client.clone(), addr, connections.clone(), events.clone(), ping_interval, stats.clone())?;
+        let incoming = thread::spawn(move || TcpClient::incoming_loop(reader, addr, connections, events, options, tx, stats));
+
+        // Keep the handles around so `TcpSocketState::shutdown` can join the threads
+        if let Ok(mut l) = client.lock() {
+            l.threads.push(outgoing);
+            l.threads.push(incoming);
         }
+        Ok(())
     }
 
-    pub fn write(&mut self, msg: &str) -> bool {
-        match self.writer.write_all(msg.as_bytes()) {
-            Ok(_) => {
-                match self.writer.flush() {
-                    Ok(_) => {
-                        true
-                    }
-                    Err(e) => {
-                        error!("Error flushing to client: {}", e);
-                        false
+    fn start_recv(client: Arc<Mutex<TcpClient>>, addr: SocketAddr, connections: Connections, events: EventSender, ping_interval: Option<Duration>, stats: Arc<StatsCounters>) -> Result<JoinHandle<()>> {
+        if let Ok(mut l) = client.lock() {
+            l.outgoing_loop(addr, connections, events, ping_interval, stats)
+        } else {
+            Err(Error::from(NetworkError::TcpClientLockFailed))
+        }
+    }
+
+    /// Shuts the stream down and drops the channel sender so both client threads stop, returning their handles so they can be joined.
+    fn close(&mut self) -> Vec<JoinHandle<()>> {
+        let _ = self.raw_stream.shutdown(Shutdown::Both);
+        self.tx = None;
+        self.threads.drain(..).collect()
+    }
+
+    /// Removes the client from the connections hash, shuts its stream down and emits a `Disconnected` event.
+    ///
+    /// Both client threads call this when they stop, so only the first call for a client has any effect.
+    fn disconnect(addr: SocketAddr, connections: &Connections, events: &EventSender, reason: DisconnectReason) {
+        // We are tearing the client down, so a poisoned hash should not keep it around
+        let client = match connections.lock() {
+            Ok(mut l) => l.remove(&addr),
+            Err(poisoned) => poisoned.into_inner().remove(&addr),
+        };
+
+        if let Some(client) = client {
+            if let Ok(l) = client.lock() {
+                let _ = l.raw_stream.shutdown(Shutdown::Both);
+            }
+            debug!("TCP client {} disconnected: {:?}", addr, reason);
+            let _ = events.send(TcpEvent::Disconnected { addr, reason });
+        }
+    }
+
+    // Watches for incoming frames from the remote endpoint until the stream ends, fails or the peer stops answering
+    fn incoming_loop(mut reader: TcpStream, addr: SocketAddr, connections: Connections, events: EventSender, options: StreamOptions, tx: Sender<Frame>, stats: Arc<StatsCounters>) {
+        let mut buffer = FrameBuffer::new();
+        let mut chunk = [0; 4096];
+        let mut last_received = Instant::now();
+        let mut last_pong = Instant::now();
+
+        let reason = 'read: loop {
+            match reader.read(&mut chunk) {
+                Ok(0) => break DisconnectReason::Closed,
+                Ok(len) => {
+                    last_received = Instant::now();
+                    stats.bytes_received.fetch_add(len, Ordering::Relaxed);
+                    buffer.extend(&chunk[..len]);
+                    loop {
+                        match buffer.decode() {
+                            Ok(Some(Frame::Data(payload))) => {
+                                stats.messages_received.fetch_add(1, Ordering::Relaxed);
+                                let _ = events.send(TcpEvent::Message { addr, payload });
+                            }
+                            Ok(Some(Frame::Ping)) => {
+                                let _ = tx.send(Frame::Pong);
+                            }
+                            Ok(Some(Frame::Pong)) => last_pong = Instant::now(),
+                            Ok(None) => break,
+                            Err(e) => {
+                                error!("Received an invalid frame: {}", e);
+                                stats.malformed_frames.fetch_add(1, Ordering::Relaxed);
+                                break 'read DisconnectReason::from_read_error(&e);
+                            }
+                        }
                     }
                 }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // The read timeout expired, the checks below decide if the peer is still alive
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => {
+                    error!("Error receiving: {:#?}", e);
+                    break DisconnectReason::from_read_error(&e);
+                }
+            }
+
+            if let Some(timeout) = options.read_timeout {
+                if last_received.elapsed() >= timeout {
+                    break DisconnectReason::TimedOut;
+                }
             }
-            Err(e) => {
-                error!("Error writing to client: {}", e);
-                false
+            if let Some(heartbeat) = options.heartbeat {
+                // A ping goes out every interval, so a live peer answers within one interval plus the timeout
+                if last_pong.elapsed() >= heartbeat.interval + heartbeat.timeout {
+                    break DisconnectReason::HeartbeatTimeout;
+                }
             }
+        };
+        if reason == DisconnectReason::TimedOut || reason == DisconnectReason::HeartbeatTimeout {
+            stats.timeouts.fetch_add(1, Ordering::Relaxed);
         }
+        TcpClient::disconnect(addr, &connections, &events, reason);
     }
 
-    // Starts a thread that watches for incoming messages from the application and writes it to the client
-    fn outgoing_loop(&mut self) -> Result<JoinHandle<()>> {
+    // Starts a thread that watches for incoming messages from the application and writes it to the client, sending a ping every `ping_interval` in between
+    fn outgoing_loop(&mut self, addr: SocketAddr, connections: Connections, events: EventSender, ping_interval: Option<Duration>, stats: Arc<StatsCounters>) -> Result<JoinHandle<()>> {
         let mut writer = match self.raw_stream.try_clone() {
-            Ok(w) => { w },
-            Err(e) => {
+            Ok(w) => { BufWriter::new(w) },
+            Err(_) => {
                 return Err(Error::from(NetworkError::TcpStreamCloneFailed));
             }
         };
@@ -208,22 +704,32 @@ impl TcpClient {
         };
 
         Ok(thread::spawn(move || {
-            // TODO: Remove this when error handling is figured out
+            let mut next_ping = ping_interval.map(|interval| Instant::now() + interval);
+            // The loop ends once the client has been removed, because that drops the last sender
             loop {
-                match rx.recv() {
-                    Ok(msg) => {
-                        match writer.write_all(msg.as_bytes()) {
-                            Ok(_) => {},
-                            Err(_e) => {
-
-                            }
-                        };
-                        match writer.flush() {
-                            Ok(_) => {},
-                            Err(_e) => {}
+                let frame = match (next_ping, ping_interval) {
+                    (Some(at), Some(interval)) => match rx.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                        Ok(frame) => frame,
+                        Err(RecvTimeoutError::Timeout) => {
+                            next_ping = Some(Instant::now() + interval);
+                            Frame::Ping
                         }
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    },
+                    _ => match rx.recv() {
+                        Ok(frame) => frame,
+                        Err(_) => return,
                     },
-                    Err(_e) => {}
+                };
+
+                if let Err(e) = frame.write_to(&mut writer).and_then(|_| writer.flush()) {
+                    error!("Error writing to client: {}", e);
+                    TcpClient::disconnect(addr, &connections, &events, DisconnectReason::WriteFailed(e.kind()));
+                    return;
+                }
+                stats.bytes_sent.fetch_add(frame.encoded_len(), Ordering::Relaxed);
+                if let Frame::Data(_) = frame {
+                    stats.messages_sent.fetch_add(1, Ordering::Relaxed);
                 }
             }
         }))
@@ -233,24 +739,318 @@ impl TcpClient {
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::sync::{Arc, Mutex};
+    use std::io::Read;
     use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn wait_for_event(state: &TcpSocketState) -> TcpEvent {
+        state.event_rx.recv_timeout(Duration::from_secs(5)).expect("no TCP event was generated")
+    }
 
     #[test]
     fn test_create_tcp_socket_state() {
-        let test_state = TcpSocketState::new();
+        let _test_state = TcpSocketState::new();
     }
 
     #[test]
     fn test_lock_poisoning() {
         let addr: SocketAddr = ("127.0.0.1".to_string() + ":"+ "27000").parse().unwrap();
         let mut test_state = TcpSocketState::new();
-        test_state.start(addr);
+        let _ = test_state.start(addr);
         let test_lock = test_state.connections.clone();
         let _ = thread::spawn(move || {
             let _lock = test_lock.lock().unwrap();
             panic!();
         }).join();
-        assert_eq!(test_state.connections.is_poisoned(), true);
+        assert!(test_state.connections.is_poisoned());
+    }
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        TcpStream::connect(addr).expect("could not connect to the server")
+    }
+
+    #[test]
+    fn test_client_eof_removes_connection() {
+        let addr: SocketAddr = "127.0.0.1:27001".parse().unwrap();
+        let mut test_state = TcpSocketState::new();
+        test_state.start(addr).unwrap();
+
+        let stream = connect(addr);
+        let local_addr = stream.local_addr().unwrap();
+
+        match wait_for_event(&test_state) {
+            TcpEvent::Connected { addr } => assert_eq!(addr, local_addr),
+            e => panic!("unexpected event: {:?}", e),
+        }
+        assert_eq!(test_state.connection_count(), 1);
+
+        drop(stream);
+
+        match wait_for_event(&test_state) {
+            TcpEvent::Disconnected { addr, reason } => {
+                assert_eq!(addr, local_addr);
+                assert_eq!(reason, DisconnectReason::Closed);
+            }
+            e => panic!("unexpected event: {:?}", e),
+        }
+        assert_eq!(test_state.connection_count(), 0);
+    }
+
+    #[test]
+    fn test_start_returns_bind_error() {
+        let addr: SocketAddr = "127.0.0.1:27002".parse().unwrap();
+        let mut first_state = TcpSocketState::new();
+        let mut second_state = TcpSocketState::new();
+
+        assert_eq!(first_state.start(addr).unwrap(), addr);
+        assert!(second_state.start(addr).is_err());
+        assert!(!second_state.is_running());
+        assert!(first_state.start(addr).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_closes_clients() {
+        let addr: SocketAddr = "127.0.0.1:27003".parse().unwrap();
+        let mut test_state = TcpSocketState::new();
+        test_state.start(addr).unwrap();
+
+        let mut stream = connect(addr);
+        match wait_for_event(&test_state) {
+            TcpEvent::Connected { .. } => {}
+            e => panic!("unexpected event: {:?}", e),
+        }
+
+        test_state.shutdown();
+        assert!(!test_state.is_running());
+        assert_eq!(test_state.connection_count(), 0);
+
+        match wait_for_event(&test_state) {
+            TcpEvent::Disconnected { reason, .. } => assert_eq!(reason, DisconnectReason::Shutdown),
+            e => panic!("unexpected event: {:?}", e),
+        }
+
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn test_stop_does_not_wait_for_a_connection() {
+        let mut test_state = TcpSocketState::new();
+        test_state.start("0.0.0.0:0".parse().unwrap()).unwrap();
+
+        let start = Instant::now();
+        test_state.shutdown();
+        assert!(!test_state.is_running());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_accept_policy_checks() {
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let other: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+
+        let state = TcpSocketState::new().with_max_connections(2);
+        assert_eq!(state.policy.check_limit(1), None);
+        assert_eq!(state.policy.check_limit(2), Some(Rejection::ConnectionLimit));
+        assert_eq!(state.policy.check(addr), None);
+
+        let state = TcpSocketState::new().with_allowed_ip(addr.ip());
+        assert_eq!(state.policy.check(addr), None);
+        assert_eq!(state.policy.check(other), Some(Rejection::Ip));
+
+        let state = TcpSocketState::new().with_allowed_ip(addr.ip()).with_denied_ip(addr.ip());
+        assert_eq!(state.policy.check(addr), Some(Rejection::Ip));
+
+        let state = TcpSocketState::new().with_accept_filter(|addr: SocketAddr| addr.port() != 5000);
+        assert_eq!(state.policy.check(addr), Some(Rejection::Filter));
+    }
+
+    #[test]
+    fn test_rejects_streams_over_the_connection_limit() {
+        let addr: SocketAddr = "127.0.0.1:27004".parse().unwrap();
+        let mut test_state = TcpSocketState::new().with_max_connections(1);
+        test_state.start(addr).unwrap();
+
+        let _first = connect(addr);
+        match wait_for_event(&test_state) {
+            TcpEvent::Connected { .. } => {}
+            e => panic!("unexpected event: {:?}", e),
+        }
+
+        let mut second = connect(addr);
+        let mut buf = [0; 8];
+        assert_eq!(second.read(&mut buf).unwrap(), 0);
+
+        assert_eq!(test_state.connection_count(), 1);
+        let stats = test_state.stats();
+        assert_eq!(stats.accepted, 1);
+        assert_eq!(stats.rejected_connection_limit, 1);
+        assert_eq!(stats.rejected(), 1);
+        assert_eq!(stats.active_connections, 1);
+    }
+
+    #[test]
+    fn test_outgoing_streams_do_not_count_against_the_limit() {
+        let addr: SocketAddr = "127.0.0.1:27019".parse().unwrap();
+        let remote = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut test_state = TcpSocketState::new().with_max_connections(1);
+        test_state.start(addr).unwrap();
+
+        test_state.connect(remote.local_addr().unwrap()).unwrap();
+        let _outgoing = remote.accept().unwrap();
+        let _incoming = connect(addr);
+        for _ in 0..2 {
+            match wait_for_event(&test_state) {
+                TcpEvent::Connected { .. } => {}
+                e => panic!("unexpected event: {:?}", e),
+            }
+        }
+
+        assert_eq!(test_state.connection_count(), 2);
+        let stats = test_state.stats();
+        assert_eq!(stats.accepted, 1);
+        assert_eq!(stats.rejected(), 0);
+    }
+
+    #[test]
+    fn test_accept_filter_rejects_streams() {
+        let addr: SocketAddr = "127.0.0.1:27005".parse().unwrap();
+        let mut test_state = TcpSocketState::new().with_accept_filter(|_| false);
+        test_state.start(addr).unwrap();
+
+        let mut stream = connect(addr);
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        assert_eq!(test_state.connection_count(), 0);
+        assert_eq!(test_state.stats().rejected_filter, 1);
+        assert!(test_state.events().next().is_none());
+    }
+
+    fn read_frame(stream: &mut TcpStream, buffer: &mut FrameBuffer) -> Frame {
+        let mut chunk = [0; 256];
+        loop {
+            if let Some(frame) = buffer.decode().unwrap() {
+                return frame;
+            }
+            let len = stream.read(&mut chunk).unwrap();
+            assert!(len > 0, "stream closed before a whole frame was read");
+            buffer.extend(&chunk[..len]);
+        }
+    }
+
+    fn wait_for_connected(state: &TcpSocketState) -> SocketAddr {
+        match wait_for_event(state) {
+            TcpEvent::Connected { addr } => addr,
+            e => panic!("unexpected event: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_exchanges_data_frames() {
+        let addr: SocketAddr = "127.0.0.1:27006".parse().unwrap();
+        let mut test_state = TcpSocketState::new();
+        test_state.start(addr).unwrap();
+
+        let mut stream = connect(addr);
+        let client_addr = wait_for_connected(&test_state);
+
+        Frame::Data(vec![1, 2, 3]).write_to(&mut stream).unwrap();
+        match wait_for_event(&test_state) {
+            TcpEvent::Message { addr, payload } => {
+                assert_eq!(addr, client_addr);
+                assert_eq!(payload, vec![1, 2, 3]);
+            }
+            e => panic!("unexpected event: {:?}", e),
+        }
+
+        test_state.send(client_addr, vec![4, 5]).unwrap();
+        assert_eq!(read_frame(&mut stream, &mut FrameBuffer::new()), Frame::Data(vec![4, 5]));
+
+        Frame::Ping.write_to(&mut stream).unwrap();
+        assert_eq!(read_frame(&mut stream, &mut FrameBuffer::new()), Frame::Pong);
+    }
+
+    #[test]
+    fn test_applies_stream_options() {
+        let addr: SocketAddr = "127.0.0.1:27007".parse().unwrap();
+        let mut test_state = TcpSocketState::new()
+            .with_nodelay(false)
+            .with_write_timeout(Duration::from_secs(3));
+        test_state.start(addr).unwrap();
+
+        let _stream = connect(addr);
+        let client_addr = wait_for_connected(&test_state);
+
+        let connections = test_state.connections.lock().unwrap();
+        let client = connections[&client_addr].lock().unwrap();
+        assert!(!client.raw_stream.nodelay().unwrap());
+        assert_eq!(client.raw_stream.write_timeout().unwrap(), Some(Duration::from_secs(3)));
+        assert_eq!(client.raw_stream.read_timeout().unwrap(), None);
+    }
+
+    #[test]
+    fn test_zero_timeouts_are_disabled() {
+        let test_state = TcpSocketState::new()
+            .with_read_timeout(Duration::from_secs(0))
+            .with_write_timeout(Duration::from_secs(0))
+            .with_heartbeat(Duration::from_secs(0), Duration::from_secs(1));
+        assert_eq!(test_state.options, StreamOptions::default());
+        assert_eq!(test_state.options.poll_interval(), None);
+    }
+
+    #[test]
+    fn test_read_timeout_disconnects_idle_clients() {
+        let addr: SocketAddr = "127.0.0.1:27008".parse().unwrap();
+        let mut test_state = TcpSocketState::new().with_read_timeout(Duration::from_millis(100));
+        test_state.start(addr).unwrap();
+
+        let _stream = connect(addr);
+        wait_for_connected(&test_state);
+
+        match wait_for_event(&test_state) {
+            TcpEvent::Disconnected { reason, .. } => assert_eq!(reason, DisconnectReason::TimedOut),
+            e => panic!("unexpected event: {:?}", e),
+        }
+        assert_eq!(test_state.connection_count(), 0);
+    }
+
+    #[test]
+    fn test_heartbeat_disconnects_silent_clients() {
+        let addr: SocketAddr = "127.0.0.1:27009".parse().unwrap();
+        let mut test_state = TcpSocketState::new().with_heartbeat(Duration::from_millis(50), Duration::from_millis(100));
+        test_state.start(addr).unwrap();
+
+        let mut stream = connect(addr);
+        wait_for_connected(&test_state);
+
+        // The client gets pinged but never answers
+        assert_eq!(read_frame(&mut stream, &mut FrameBuffer::new()), Frame::Ping);
+
+        match wait_for_event(&test_state) {
+            TcpEvent::Disconnected { reason, .. } => assert_eq!(reason, DisconnectReason::HeartbeatTimeout),
+            e => panic!("unexpected event: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_heartbeat_keeps_answering_clients() {
+        let addr: SocketAddr = "127.0.0.1:27010".parse().unwrap();
+        let mut test_state = TcpSocketState::new().with_heartbeat(Duration::from_millis(50), Duration::from_millis(100));
+        test_state.start(addr).unwrap();
+
+        let mut stream = connect(addr);
+        wait_for_connected(&test_state);
+
+        let mut buffer = FrameBuffer::new();
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(500) {
+            assert_eq!(read_frame(&mut stream, &mut buffer), Frame::Ping);
+            Frame::Pong.write_to(&mut stream).unwrap();
+        }
+
+        assert_eq!(test_state.connection_count(), 1);
+        assert!(test_state.events().next().is_none());
     }
 }
@@ -1,17 +1,92 @@
+use std::collections::{HashMap, VecDeque};
 use std::io;
-use std::net::{self, ToSocketAddrs};
+use std::mem;
+use std::net::{self, IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use super::{Packet, RawPacket, SocketState};
-use bincode::deserialize;
+use super::bandwidth::{self, Priority, Queued, TokenBucket};
+use super::capture::Capture;
+use super::compression::Compression;
+use super::connect_token::{ConnectToken, ConnectedClient};
+use super::encryption::Encryption;
+use super::flood_protection::FloodProtection;
+use super::link_conditioner::{Direction, LinkConditioner};
+use super::message::{self, Channel, Message};
+use super::metrics::RttHistogram;
+use super::path_mtu::MAX_DATAGRAM_SIZE;
+use super::protocol::Protocol;
+use super::worker::Worker;
+use super::{ClockSync, Packet, PacketHandle, Quality, SocketState};
 
+use bincode::{deserialize, serialize};
+use crossbeam_channel::unbounded;
 use error::Result;
+use events::DeliveryEvent;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
-const BUFFER_SIZE: usize = 1024;
+// Big enough for the biggest MTU probe
+pub const BUFFER_SIZE: usize = MAX_DATAGRAM_SIZE;
+
+/// Counters kept by a `UdpSocket`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UdpStats {
+    /// Bytes sent in datagrams, after compression and encryption.
+    pub bytes_sent: usize,
+    /// Bytes received in datagrams, including the ones that were dropped.
+    pub bytes_received: usize,
+    /// Packets that were sent.
+    pub sent_packets: usize,
+    /// Packets that were delivered.
+    pub received_packets: usize,
+    /// Packets that were dropped because a packet with the same sequence number was received before.
+    pub duplicate_packets: usize,
+    /// Datagrams the flood protection dropped because their address is banned.
+    pub banned_packets: usize,
+    /// Datagrams the flood protection dropped because their address went over its packet rate.
+    pub rate_limited_packets: usize,
+    /// Connection attempts the flood protection dropped because their address went over its connection rate.
+    pub rate_limited_connections: usize,
+    /// Connection attempts the flood protection dropped because the maximum number of connections was reached.
+    pub rejected_connection_limit: usize,
+    /// Packets the other side never acknowledged, these are the ones to resend.
+    pub lost_packets: usize,
+    /// Datagrams that could not be decrypted, decompressed or deserialized.
+    pub malformed_packets: usize,
+    /// Times `flush` held a packet back because it did not fit in the bandwidth budget.
+    pub deferred_packets: usize,
+    /// Low priority packets `flush` dropped because they did not fit in the bandwidth budget of a `Bad` connection.
+    pub dropped_low_priority_packets: usize,
+    /// Connections that were removed because nothing was heard from them within the timeout.
+    pub timed_out_connections: usize,
+    /// Connections right now.
+    pub active_connections: usize,
+    /// Round trip times of acknowledged packets.
+    pub rtt: RttHistogram,
+}
 
 pub struct UdpSocket {
     socket: net::UdpSocket,
-    state: SocketState,
+    protocol: Protocol,
     recv_buffer: [u8; BUFFER_SIZE],
+    link_conditioner: Option<LinkConditioner>,
+    capture: Option<Capture>,
+    // Messages of a packet that `recv_message` did not return yet
+    received_messages: VecDeque<(SocketAddr, Channel, Vec<u8>)>,
+    // Codecs for the messages of a channel, on top of the compression of whole datagrams
+    channel_compression: HashMap<Channel, Compression>,
+    aggregation: bool,
+    // Payloads `send` queued for `flush`, per destination
+    queued_payloads: HashMap<SocketAddr, Vec<Queued>>,
+    bandwidth_budget: Option<usize>,
+    budgets: HashMap<SocketAddr, TokenBucket>,
+    deferred_packets: usize,
+    dropped_low_priority_packets: usize,
+    // Packets of an aggregated datagram that `recv` did not return yet
+    received_packets: VecDeque<Packet>,
+    nonblocking: bool,
 }
 
 impl UdpSocket {
@@ -21,40 +96,565 @@ impl UdpSocket {
 
         Ok(UdpSocket {
             socket,
-            state,
+            protocol: Protocol::new(state),
             recv_buffer: [0; BUFFER_SIZE],
+            link_conditioner: None,
+            capture: None,
+            received_messages: VecDeque::new(),
+            channel_compression: HashMap::new(),
+            aggregation: false,
+            queued_payloads: HashMap::new(),
+            bandwidth_budget: None,
+            budgets: HashMap::new(),
+            deferred_packets: 0,
+            dropped_low_priority_packets: 0,
+            received_packets: VecDeque::new(),
+            nonblocking: false,
         })
     }
 
+    /// Encrypts everything this socket sends and only accepts encrypted datagrams, see `Encryption` for how this works.
+    ///
+    /// The other side has to enable encryption as well. Packets sent before the handshake with an address is done are queued until it is.
+    pub fn with_encryption(mut self) -> Self {
+        self.protocol.encryption = Some(Encryption::new());
+        self
+    }
+
+    /// Connects to the servers in `token` with it, so they know the backend authorized us. This enables encryption.
+    pub fn with_connect_token(mut self, token: ConnectToken) -> Self {
+        self.protocol.encryption = Some(self.protocol.encryption.take().unwrap_or_default().with_connect_token(token));
+        self
+    }
+
+    /// Only accepts clients with a connect token signed with `key` that lists `public_addr`, the address clients reach this server at. This enables encryption.
+    ///
+    /// Datagrams from other addresses are ignored, so they never get a `Connection`.
+    pub fn with_token_key(mut self, key: [u8; 32], public_addr: SocketAddr) -> Self {
+        self.protocol.encryption = Some(self.protocol.encryption.take().unwrap_or_default().with_token_key(key, public_addr));
+        self
+    }
+
+    /// Returns the client that connected from `addr` with a connect token, if any.
+    pub fn connected_client(&self, addr: SocketAddr) -> Option<&ConnectedClient> {
+        self.protocol.connected_client(addr)
+    }
+
+    /// Compresses the datagrams this socket sends, see `Compression`. The other side has to enable it with the same settings.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.protocol.compression = Some(compression);
+        self
+    }
+
+    /// Compresses every message sent on `channel` on its own with `compression`, see `send_message`. The other side has to set the same codec for the channel.
+    ///
+    /// This way each channel can have a dictionary made from the messages it carries. It works on top of `with_compression`, which compresses whole datagrams, so a channel with a codec of its own is best sent on a socket without it.
+    pub fn with_channel_compression(mut self, channel: Channel, compression: Compression) -> Self {
+        self.channel_compression.insert(channel, compression);
+        self
+    }
+
+    /// Pings the other side of every connection now and then to estimate its clock, see `ClockSync`. The pings go out with the packets sent.
+    ///
+    /// The other side answers pings whether it has clock sync on or not.
+    pub fn with_clock_sync(mut self) -> Self {
+        self.protocol.clock_sync = true;
+        self
+    }
+
+    /// Queues the packets passed to `send` until `flush` is called, which packs the ones for the same address into as few datagrams as fit.
+    ///
+    /// Every packet still has its own place in the order and is still reported by `dropped_packets` when the datagram carrying it is lost, so it can be resent on its own.
+    /// The packets in a datagram share its sequence number though, so they are acked or lost together.
+    /// The other side has to enable aggregation as well.
+    pub fn with_aggregation(mut self) -> Self {
+        self.aggregation = true;
+        self
+    }
+
+    /// Limits what `flush` sends to each address to `bytes_per_second`, including headers, with bursts of up to a second's worth.
+    ///
+    /// Packets that do not fit wait for the next flush, high priority ones first. A packet moves up a priority every few flushes it waited, so none wait forever.
+    /// While the quality of a connection is `Bad`, its low priority packets that do not fit are dropped instead.
+    /// This turns on aggregation, so the other side has to enable it as well.
+    pub fn with_bandwidth_budget(mut self, bytes_per_second: usize) -> Self {
+        self.aggregation = true;
+        self.bandwidth_budget = Some(bytes_per_second);
+        self
+    }
+
+    /// Checks every received datagram against the given flood protection before anything else is done with it.
+    pub fn with_flood_protection(mut self, flood_protection: FloodProtection) -> Self {
+        self.protocol.flood_protection = Some(flood_protection);
+        self
+    }
+
+    /// Returns the IP addresses the flood protection banned.
+    pub fn banned_addresses(&self) -> Vec<IpAddr> {
+        self.protocol.banned_addresses()
+    }
+
     pub fn recv(&mut self) -> io::Result<Option<Packet>> {
-        let (len, _addr) = self.socket.recv_from(&mut self.recv_buffer)?;
-        if len > 0 {
-            // TODO: Remove unwrap and funnel result error types
-            let raw_packet: RawPacket = deserialize(&self.recv_buffer[..len]).unwrap();
-            let packet = self.state.process_received(_addr, &raw_packet).unwrap();
-            Ok(Some(packet))
-        } else {
-            Ok(None)
+        if !self.aggregation {
+            return self.recv_datagram();
+        }
+
+        if self.received_packets.is_empty() {
+            let packet = match self.recv_datagram()? {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+            let addr = packet.addr();
+            self.received_packets
+                .extend(message::unpack(packet.payload())?.into_iter().map(|(_, payload)| Packet::new(addr, payload)));
+        }
+        Ok(self.received_packets.pop_front())
+    }
+
+    // Receives the packet of the next datagram
+    fn recv_datagram(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            let (addr, data) = if self.link_conditioner.is_some() {
+                match self.recv_conditioned()? {
+                    Some(datagram) => datagram,
+                    None => return Ok(None),
+                }
+            } else {
+                let (len, addr) = self.socket.recv_from(&mut self.recv_buffer)?;
+                if len == 0 {
+                    return Ok(None);
+                }
+                (addr, self.recv_buffer[..len].to_vec())
+            };
+
+            if let Some(ref mut capture) = self.capture {
+                capture.record(Direction::Incoming, addr, &data);
+            }
+
+            // Handshake, rejected and duplicate datagrams are handled here, keep reading until there is a packet
+            let received = self.protocol.receive(addr, data)?;
+            for reply in received.replies {
+                self.transmit(addr, reply)?;
+            }
+            if let Some(packet) = received.packet {
+                return Ok(Some(packet));
+            }
         }
     }
 
+    /// Sends a packet, or queues it for `flush` with aggregation on, in which case nothing is sent yet and this returns 0.
     pub fn send(&mut self, packet: Packet) -> Result<io::Result<usize>> {
-        let (addr, payload) = self.state.pre_process_packet(packet)?;
-        Ok(self.socket.send_to(&payload, addr))
+        self.send_with_priority(packet, Priority::Normal)
+    }
+
+    /// Like `send`, the priority decides which packets `flush` sends first when the bandwidth budget does not allow sending all of them.
+    ///
+    /// Priorities only matter with aggregation on, without it packets are sent right away.
+    pub fn send_with_priority(&mut self, packet: Packet, priority: Priority) -> Result<io::Result<usize>> {
+        self.send_or_queue(packet, priority, None)
+    }
+
+    // Sends a packet in a datagram of its own, or queues it for `flush` with aggregation on
+    fn send_or_queue(&mut self, packet: Packet, priority: Priority, handle: Option<PacketHandle>) -> Result<io::Result<usize>> {
+        if !self.aggregation {
+            let (addr, datagrams) = self.protocol.send_tracked(packet, handle.into_iter().collect())?;
+            return self.transmit_packet(addr, datagrams);
+        }
+
+        if packet.payload().len() > usize::from(u16::MAX) {
+            return Ok(Err(io::Error::new(io::ErrorKind::InvalidInput, "the packet is too big to be aggregated")));
+        }
+        let addr = packet.addr();
+        let queued = Queued {
+            handle,
+            ..Queued::new(priority, packet.payload.into_vec())
+        };
+        self.queued_payloads.entry(addr).or_default().push(queued);
+        Ok(Ok(0))
+    }
+
+    /// Sends the packets queued by `send` with aggregation on, packed into as few datagrams per address as fit in `max_payload_size`. Returns the number of bytes sent.
+    ///
+    /// Call it once per tick, after everything for the tick was sent.
+    ///
+    /// With a bandwidth budget, the packets that do not fit in it wait for the next flush.
+    /// When sending to an address fails, its packets that were not sent wait for the next flush as well, the other addresses are still flushed and the first error is returned.
+    pub fn flush(&mut self) -> Result<io::Result<usize>> {
+        let now = Instant::now();
+        let mut sent = 0;
+        let mut error = None;
+        let addrs: Vec<_> = self.queued_payloads.keys().cloned().collect();
+        for addr in addrs {
+            let queue = self.queued_payloads.remove(&addr).unwrap_or_default();
+            let schedule = match self.bandwidth_budget {
+                Some(rate) => {
+                    let bucket = self.budgets.entry(addr).or_insert_with(|| TokenBucket::new(rate, now));
+                    bucket.refill(now);
+                    let budget = bucket.available();
+                    if self.protocol.state.quality(addr) == Quality::Bad {
+                        bandwidth::schedule_dropping_low(queue, budget)
+                    } else {
+                        bandwidth::schedule(queue, budget)
+                    }
+                }
+                None => bandwidth::schedule(queue, usize::MAX),
+            };
+            self.deferred_packets += schedule.deferred.len();
+            self.dropped_low_priority_packets += schedule.dropped;
+            if !schedule.deferred.is_empty() {
+                self.queued_payloads.insert(addr, schedule.deferred);
+            }
+
+            let max_size = self.max_payload_size(addr);
+            let mut unsent = schedule.send;
+            while !unsent.is_empty() {
+                let count = message::fit(unsent.iter().map(|queued| queued.payload.len()), max_size);
+                let packed: Vec<_> = unsent.drain(..count).collect();
+                let result = self.send_packed(addr, &packed);
+                match result {
+                    Ok(Ok(len)) => {
+                        sent += len;
+                        if let Some(bucket) = self.budgets.get_mut(&addr) {
+                            bucket.take(len);
+                        }
+                    }
+                    _ => {
+                        // The rest of the queue of this address waits for the next flush, the other addresses are still flushed
+                        self.requeue(addr, packed.into_iter().chain(unsent.drain(..)).collect());
+                        if let Err(e) = result? {
+                            error.get_or_insert(e);
+                        }
+                    }
+                }
+            }
+
+            // A full bucket is no different from a new one, so only the ones in use are kept
+            if self.budgets.get(&addr).is_some_and(TokenBucket::is_full) && !self.queued_payloads.contains_key(&addr) {
+                self.budgets.remove(&addr);
+            }
+        }
+
+        match error {
+            Some(e) => Ok(Err(e)),
+            None => Ok(Ok(sent)),
+        }
+    }
+
+    // Sends queued payloads packed into a datagram, the packets sent with `send_tracked` among them are tracked with it
+    fn send_packed(&mut self, addr: SocketAddr, packed: &[Queued]) -> Result<io::Result<usize>> {
+        // Packets are all on the same channel, channels are for messages
+        let payloads: Vec<_> = packed.iter().map(|queued| (0, &queued.payload[..])).collect();
+        let payload = match message::pack(&payloads, usize::MAX) {
+            Ok(mut payloads) => payloads.remove(0),
+            Err(e) => return Ok(Err(e)),
+        };
+        let handles = packed.iter().filter_map(|queued| queued.handle).collect();
+        let (addr, datagrams) = self.protocol.send_tracked(Packet::new(addr, payload), handles)?;
+        self.transmit_packet(addr, datagrams)
+    }
+
+    // Puts the payloads that were picked to be sent but were not back in the queue, ahead of the deferred ones
+    fn requeue(&mut self, addr: SocketAddr, unsent: Vec<Queued>) {
+        let queue = self.queued_payloads.entry(addr).or_default();
+        let deferred = mem::replace(queue, unsent);
+        queue.extend(deferred);
+    }
+
+    /// Like `send`, and returns the handle the `DeliveryEvent`s about the packet carry.
+    ///
+    /// With aggregation on, the events are about the datagram `flush` packs the packet into, so the packets in it are acked or lost together.
+    /// Take the events with `delivery_events`, or from the receiver of the worker. Higher layers can build their own reliability on this, like resending only the latest value of something when it was lost.
+    pub fn send_tracked(&mut self, packet: Packet) -> Result<io::Result<PacketHandle>> {
+        let handle = self.protocol.state.new_handle(packet.addr());
+        Ok(self.send_with_handle(packet, handle)?.map(|_| handle))
+    }
+
+    // Like `send_tracked`, with a handle the worker handed out already
+    pub(super) fn send_with_handle(&mut self, packet: Packet, handle: PacketHandle) -> Result<io::Result<usize>> {
+        self.send_or_queue(packet, Priority::Normal, Some(handle))
+    }
+
+    // The counter the handles of `send_tracked` come from
+    pub(super) fn handle_ids(&self) -> Arc<AtomicU64> {
+        self.protocol.state.handle_ids()
+    }
+
+    /// Returns the packets sent with `send_tracked` that were acked or lost since the last call.
+    pub fn delivery_events(&mut self) -> Vec<DeliveryEvent> {
+        self.protocol.state.take_delivery_events()
+    }
+
+    // Sends the datagrams of a packet, and a path MTU probe and clock sync ping after them if they are due
+    fn transmit_packet(&mut self, addr: SocketAddr, datagrams: Vec<Vec<u8>>) -> Result<io::Result<usize>> {
+        let mut sent = 0;
+        for datagram in datagrams {
+            match self.transmit(addr, datagram) {
+                Ok(len) => sent += len,
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+
+        self.send_probe(addr)?;
+        for datagram in self.protocol.time_ping(addr)? {
+            if let Err(e) = self.transmit(addr, datagram) {
+                return Ok(Err(e));
+            }
+        }
+        Ok(Ok(sent))
+    }
+
+    /// Moves the socket to a thread of its own, which sends the packets queued on the worker's sender and delivers received packets and `ConnectionEvent`s on its receiver.
+    ///
+    /// This way sending does not need a `&mut UdpSocket`, and receiving never blocks the game loop.
+    pub fn start_worker(mut self) -> io::Result<Worker> {
+        let (event_tx, event_rx) = unbounded();
+        self.protocol.state.set_event_sender(event_tx.clone());
+        Worker::start(self, (event_tx, event_rx))
+    }
+
+    /// Returns the biggest payload that fits in a single datagram to `addr`.
+    ///
+    /// This grows as path MTU discovery confirms bigger datagrams. Bigger payloads are still sent, but may be fragmented or dropped on the way.
+    pub fn max_payload_size(&self, addr: SocketAddr) -> usize {
+        self.protocol.max_payload_size(addr)
+    }
+
+    /// Serializes a message with bincode and sends it on `channel`, in a packet of its own. Returns the number of bytes sent.
+    ///
+    /// The other side receives it with `recv_message`. Packets sent with `send` can not be received as messages and the other way around, so stick to one of them per socket.
+    pub fn send_message<T: Serialize>(&mut self, addr: SocketAddr, channel: Channel, message: &T) -> Result<io::Result<usize>> {
+        self.send_messages(addr, &[(channel, message)])
+    }
+
+    /// Serializes messages with bincode and sends them packed into as few packets as fit in `max_payload_size`. Returns the number of bytes sent.
+    ///
+    /// The messages packed into a packet share its sequence number, so they are acked or lost as one.
+    pub fn send_messages<T: Serialize>(&mut self, addr: SocketAddr, messages: &[(Channel, T)]) -> Result<io::Result<usize>> {
+        let mut serialized = Vec::with_capacity(messages.len());
+        for (channel, message) in messages {
+            let mut data = serialize(message)?;
+            if let Some(compression) = self.channel_compression.get(channel) {
+                data = compression.compress(&data);
+            }
+            serialized.push((*channel, data));
+        }
+
+        // Aggregation packs each payload again, with a header of its own
+        let mut max_size = self.max_payload_size(addr);
+        if self.aggregation {
+            max_size -= message::packed_size(0);
+        }
+        let mut sent = 0;
+        for payload in message::pack(&serialized, max_size)? {
+            match self.send(Packet::new(addr, payload))? {
+                Ok(len) => sent += len,
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+        Ok(Ok(sent))
+    }
+
+    /// Receives the next message sent with `send_message` or `send_messages`, reading a packet when there is no message left from the last one.
+    ///
+    /// A message that does not deserialize to `T`, or a packet without messages, is an `InvalidData` error.
+    pub fn recv_message<T: DeserializeOwned>(&mut self) -> io::Result<Option<Message<T>>> {
+        if self.received_messages.is_empty() {
+            let packet = match self.recv()? {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+            let addr = packet.addr();
+            self.received_messages
+                .extend(message::unpack(packet.payload())?.into_iter().map(|(channel, data)| (addr, channel, data)));
+        }
+
+        match self.received_messages.pop_front() {
+            Some((addr, channel, mut data)) => {
+                if let Some(compression) = self.channel_compression.get(&channel) {
+                    data = compression.decompress(&data)?;
+                }
+                let message = deserialize(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(Some(Message { addr, channel, message }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the clock sync state of the connection with `addr`, with the estimated offset and drift of its clock.
+    pub fn clock(&self, addr: SocketAddr) -> Option<ClockSync> {
+        self.protocol.state.clock(addr)
+    }
+
+    /// Returns the time on our clock sync clock, in microseconds.
+    pub fn clock_time(&self) -> i64 {
+        self.protocol.state.clock_time()
+    }
+
+    /// Returns the time on the clock of `addr` right now, in microseconds, once clock sync has an estimate.
+    pub fn remote_time(&self, addr: SocketAddr) -> Option<i64> {
+        self.protocol.remote_time(addr)
+    }
+
+    /// Returns the packets sent to `addr` that the other side never acknowledged, so they can be resent.
+    ///
+    /// With aggregation on, these are the packets that were in the lost datagrams.
+    pub fn dropped_packets(&mut self, addr: SocketAddr) -> Result<Vec<Packet>> {
+        let dropped = self.protocol.state.dropped_packets(addr)?;
+        if !self.aggregation {
+            return Ok(dropped);
+        }
+
+        let mut packets = Vec::new();
+        for datagram in dropped {
+            packets.extend(message::unpack(datagram.payload())?.into_iter().map(|(_, payload)| Packet::new(addr, payload)));
+        }
+        Ok(packets)
+    }
+
+    /// Returns the sequence numbers of the packets sent to `addr` that the other side acknowledged since the last call, oldest first.
+    ///
+    /// Together with `next_seq` this tells which packets made it, for example to pick the baseline of a `SnapshotBuffer`.
+    pub fn acked_packets(&mut self, addr: SocketAddr) -> Result<Vec<u16>> {
+        self.protocol.state.acked_packets(addr)
+    }
+
+    /// Returns the sequence number the next packet sent to `addr` gets.
+    ///
+    /// With aggregation on, the packets sent in a datagram by `flush` share its sequence number.
+    pub fn next_seq(&self, addr: SocketAddr) -> u16 {
+        self.protocol.state.next_seq(addr)
+    }
+
+    /// Runs all the datagrams we send and receive through the given link conditioner, or stops doing so when `None` is passed.
+    ///
+    /// Delayed datagrams are only sent and delivered while `send` or `recv` is called, so keep calling `recv` while testing with latency.
+    pub fn set_link_conditioner(&mut self, link_conditioner: Option<LinkConditioner>) -> io::Result<()> {
+        if link_conditioner.is_none() {
+            // `recv_conditioned` uses the read timeout to wake up for delayed datagrams
+            self.socket.set_read_timeout(None)?;
+        }
+        self.link_conditioner = link_conditioner;
+        Ok(())
+    }
+
+    /// Writes every datagram this socket sends and receives to the given capture, or stops capturing when `None` is passed.
+    ///
+    /// Replaces the capture set before, which flushes it.
+    pub fn set_capture(&mut self, capture: Option<Capture>) -> io::Result<()> {
+        self.capture = match capture {
+            Some(mut capture) => {
+                capture.set_local_addr(self.socket.local_addr()?);
+                Some(capture)
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    pub fn stats(&self) -> UdpStats {
+        UdpStats {
+            deferred_packets: self.deferred_packets,
+            dropped_low_priority_packets: self.dropped_low_priority_packets,
+            ..self.protocol.stats()
+        }
+    }
+
+    /// Returns the address this socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
     }
 
     pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking = nonblocking;
         self.socket.set_nonblocking(nonblocking)
     }
+
+    // Sends a path MTU probe to `addr` if one is due
+    fn send_probe(&mut self, addr: SocketAddr) -> Result<()> {
+        for datagram in self.protocol.probe(addr)? {
+            if let Err(e) = self.transmit(addr, datagram) {
+                debug!("Could not send MTU probe to {}: {}", addr, e);
+                self.protocol.probe_failed(addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Sends a datagram through the link conditioner if there is one, or straight to the socket
+    fn transmit(&mut self, addr: SocketAddr, data: Vec<u8>) -> io::Result<usize> {
+        if let Some(ref mut capture) = self.capture {
+            capture.record(Direction::Outgoing, addr, &data);
+        }
+
+        match self.link_conditioner {
+            Some(ref mut conditioner) => {
+                let len = data.len();
+                conditioner.push(Direction::Outgoing, addr, data, Instant::now());
+                self.send_delayed().map(|_| len)
+            }
+            None => self.socket.send_to(&data, addr),
+        }
+    }
+
+    // Receives through the link conditioner: datagrams read from the socket are queued, and only handed out once they are due.
+    fn recv_conditioned(&mut self) -> io::Result<Option<(SocketAddr, Vec<u8>)>> {
+        loop {
+            self.send_delayed()?;
+
+            let (due, next_due) = match self.link_conditioner {
+                Some(ref mut conditioner) => (conditioner.pop(Direction::Incoming, Instant::now()), conditioner.next_due()),
+                None => return Ok(None),
+            };
+
+            if due.is_some() {
+                return Ok(due);
+            }
+
+            // When blocking, only wait until the next delayed datagram is due
+            if !self.nonblocking {
+                let timeout = next_due.map(|due| {
+                    let wait = due.saturating_duration_since(Instant::now());
+                    wait.max(Duration::from_millis(1))
+                });
+                self.socket.set_read_timeout(timeout)?;
+            }
+
+            match self.socket.recv_from(&mut self.recv_buffer) {
+                Ok((len, addr)) => {
+                    let data = self.recv_buffer[..len].to_vec();
+                    if let Some(ref mut conditioner) = self.link_conditioner {
+                        conditioner.push(Direction::Incoming, addr, data, Instant::now());
+                    }
+                }
+                Err(ref e) if !self.nonblocking && (e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Sends the outgoing datagrams the link conditioner has released
+    fn send_delayed(&mut self) -> io::Result<()> {
+        if let Some(ref mut conditioner) = self.link_conditioner {
+            while let Some((addr, data)) = conditioner.pop(Direction::Outgoing, Instant::now()) {
+                self.socket.send_to(&data, addr)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::UdpSocket;
+    use super::{UdpSocket, MAX_DATAGRAM_SIZE};
+    use net::{Diff, Priority, Quality, SnapshotBuffer};
+    use packet::RAW_PACKET_HEADER_SIZE;
     use bincode::{deserialize, serialize};
-    use packet::Packet;
+    use net::{Compression, ConnectToken, FloodProtection, LinkConditioner};
+    use events::DeliveryEvent;
+    use packet::{Packet, PacketHandle};
+    use std::collections::HashSet;
     use std::io;
-    use std::net::{IpAddr, SocketAddr};
+    use std::net::{self, IpAddr, SocketAddr};
     use std::str::FromStr;
     use std::{thread, time};
 
@@ -105,7 +705,6 @@ mod test {
                     b: 1,
                 };
                 let data = serialize(&stub).unwrap();
-                let len = data.len();
                 let dummy_packet = Packet::new(addr, data);
                 let send_result = send_socket.send(dummy_packet);
                 assert!(send_result.is_ok());
@@ -139,7 +738,572 @@ mod test {
         .unwrap();
     }
 
-    #[derive(Serialize, Deserialize, Clone, Copy)]
+    #[test]
+    fn link_conditioner_exercises_dropped_packets() {
+        const TOTAL_PACKAGES: u8 = 100;
+
+        let mut send_socket = UdpSocket::bind("127.0.0.1:12367").unwrap();
+        let mut recv_socket = UdpSocket::bind("127.0.0.1:12368").unwrap();
+        let send_addr = send_socket.local_addr().unwrap();
+        let recv_addr = recv_socket.local_addr().unwrap();
+
+        send_socket
+            .set_link_conditioner(Some(LinkConditioner::new(1).with_packet_loss(0.3)))
+            .unwrap();
+        send_socket.set_nonblocking(true).unwrap();
+        recv_socket.set_nonblocking(true).unwrap();
+
+        for i in 0..TOTAL_PACKAGES {
+            send_socket.send(Packet::new(recv_addr, vec![i])).unwrap().unwrap();
+        }
+        thread::sleep(time::Duration::from_millis(50));
+
+        // Answer every packet that made it, so the acks make their way back to the sender
+        let mut received = HashSet::new();
+        while let Ok(Some(packet)) = recv_socket.recv() {
+            received.insert(packet.payload()[0]);
+            recv_socket.send(Packet::new(send_addr, Vec::new())).unwrap().unwrap();
+        }
+        thread::sleep(time::Duration::from_millis(50));
+        while let Ok(Some(_)) = send_socket.recv() {}
+
+        assert!(received.len() > 50 && received.len() < TOTAL_PACKAGES as usize);
+
+        let dropped: HashSet<u8> = send_socket
+            .dropped_packets(recv_addr)
+            .unwrap()
+            .iter()
+            .map(|packet| packet.payload()[0])
+            .collect();
+        let last_received = *received.iter().max().unwrap();
+
+        assert!(!dropped.is_empty());
+        // Only packets that never arrived are reported, and every one of them that fell out of the ack window is
+        assert!(dropped.iter().all(|seq| !received.contains(seq)));
+        for seq in 0..last_received.saturating_sub(32) {
+            assert_eq!(dropped.contains(&seq), !received.contains(&seq), "packet {}", seq);
+        }
+    }
+
+    #[test]
+    fn encrypted_sockets_exchange_packets() {
+        let mut client = UdpSocket::bind("127.0.0.1:12377").unwrap().with_encryption();
+        let mut server = UdpSocket::bind("127.0.0.1:12378").unwrap().with_encryption();
+        let mut plain = UdpSocket::bind("127.0.0.1:12379").unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let plain_addr = plain.local_addr().unwrap();
+        client.set_nonblocking(true).unwrap();
+        server.set_nonblocking(true).unwrap();
+        plain.set_nonblocking(true).unwrap();
+
+        // These are queued until the handshake is done
+        for i in 0..3 {
+            client.send(Packet::new(server_addr, vec![i])).unwrap().unwrap();
+        }
+
+        let mut received = Vec::new();
+        let mut replies = Vec::new();
+        for _ in 0..100 {
+            while let Ok(Some(packet)) = server.recv() {
+                assert_eq!(packet.addr(), client_addr);
+                received.push(packet.payload()[0]);
+                server.send(Packet::new(client_addr, vec![packet.payload()[0] + 10])).unwrap().unwrap();
+            }
+            while let Ok(Some(packet)) = client.recv() {
+                replies.push(packet.payload()[0]);
+            }
+            if replies.len() == 3 {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(5));
+        }
+
+        assert_eq!(received, vec![0, 1, 2]);
+        assert_eq!(replies, vec![10, 11, 12]);
+
+        // A socket without encryption can not read what an encrypted one sends, and the other way around
+        client.send(Packet::new(plain_addr, vec![1])).unwrap().unwrap();
+        plain.send(Packet::new(server_addr, vec![1])).unwrap().unwrap();
+        thread::sleep(time::Duration::from_millis(20));
+        assert_eq!(plain.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(server.recv().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn server_only_accepts_clients_with_a_connect_token() {
+        let key = ConnectToken::generate_key();
+        let mut server = UdpSocket::bind("127.0.0.1:12387").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        server = server.with_token_key(key, server_addr);
+
+        let expires_at = time::SystemTime::now() + time::Duration::from_secs(30);
+        let token = ConnectToken::generate(&key, 5, vec![server_addr], expires_at, b"blue team".to_vec()).unwrap();
+        let mut client = UdpSocket::bind("127.0.0.1:12388")
+            .unwrap()
+            .with_connect_token(ConnectToken::from_bytes(&token.to_bytes().unwrap()).unwrap());
+        let mut intruder = UdpSocket::bind("127.0.0.1:12389").unwrap().with_encryption();
+        let client_addr = client.local_addr().unwrap();
+        let intruder_addr = intruder.local_addr().unwrap();
+
+        for socket in &mut [&mut server, &mut client, &mut intruder] {
+            socket.set_nonblocking(true).unwrap();
+        }
+
+        client.send(Packet::new(server_addr, vec![1])).unwrap().unwrap();
+        intruder.send(Packet::new(server_addr, vec![2])).unwrap().unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..20 {
+            while let Ok(Some(packet)) = server.recv() {
+                received.push((packet.addr(), packet.payload().to_vec()));
+            }
+            while let Ok(Some(_)) = client.recv() {}
+            while let Ok(Some(_)) = intruder.recv() {}
+            thread::sleep(time::Duration::from_millis(5));
+        }
+
+        assert_eq!(received, vec![(client_addr, vec![1])]);
+        assert_eq!(server.connected_client(client_addr).unwrap().client_id, 5);
+        assert!(server.connected_client(intruder_addr).is_none());
+
+        // Sending to an address no client connected from fails without leaving a connection behind
+        assert!(server.send(Packet::new(intruder_addr, vec![3])).is_err());
+        assert!(!server.protocol.state.has_connection(intruder_addr));
+        assert!(server.send(dummy_packet()).is_err());
+    }
+
+    #[test]
+    fn delivers_duplicated_datagrams_once() {
+        let mut send_socket = UdpSocket::bind("127.0.0.1:12397").unwrap();
+        let mut recv_socket = UdpSocket::bind("127.0.0.1:12398").unwrap();
+        let recv_addr = recv_socket.local_addr().unwrap();
+
+        send_socket
+            .set_link_conditioner(Some(LinkConditioner::new(3).with_duplication(1.0)))
+            .unwrap();
+        recv_socket.set_nonblocking(true).unwrap();
+
+        for i in 0..10 {
+            send_socket.send(Packet::new(recv_addr, vec![i])).unwrap().unwrap();
+        }
+        thread::sleep(time::Duration::from_millis(50));
+
+        let mut received = Vec::new();
+        while let Ok(Some(packet)) = recv_socket.recv() {
+            received.push(packet.payload()[0]);
+        }
+
+        assert_eq!(received, (0..10).collect::<Vec<u8>>());
+        assert_eq!(recv_socket.stats().received_packets, 10);
+        assert_eq!(recv_socket.stats().duplicate_packets, 10);
+    }
+
+    #[test]
+    fn flood_protection_drops_floods_and_caps_connections() {
+        let mut flooder = UdpSocket::bind("127.0.0.1:12407").unwrap();
+        let mut latecomer = UdpSocket::bind("127.0.0.1:12408").unwrap();
+        let mut server = UdpSocket::bind("127.0.0.1:12409")
+            .unwrap()
+            .with_flood_protection(FloodProtection::new().with_packet_rate(1, 10).with_max_connections(1));
+        let server_addr = server.local_addr().unwrap();
+        server.set_nonblocking(true).unwrap();
+
+        for i in 0..30 {
+            flooder.send(Packet::new(server_addr, vec![i])).unwrap().unwrap();
+        }
+        thread::sleep(time::Duration::from_millis(20));
+        let mut received = 0;
+        while let Ok(Some(_)) = server.recv() {
+            received += 1;
+        }
+
+        // The flooder and the latecomer share an IP, so give the bucket time to refill
+        thread::sleep(time::Duration::from_millis(1100));
+        latecomer.send(Packet::new(server_addr, vec![1])).unwrap().unwrap();
+        thread::sleep(time::Duration::from_millis(20));
+        assert!(server.recv().is_err());
+
+        // The MTU probe the latecomer sends after its packet finds the bucket empty, so it is never known to be a probe
+        let stats = server.stats();
+        assert_eq!(received, 10);
+        assert_eq!(stats.received_packets, 10);
+        assert_eq!(stats.rate_limited_packets, 20 + 1);
+        assert_eq!(stats.rejected_connection_limit, 1);
+    }
+
+    #[test]
+    fn counts_traffic_in_the_stats() {
+        let mut client = UdpSocket::bind("127.0.0.1:12477").unwrap();
+        let mut server = UdpSocket::bind("127.0.0.1:12478").unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let mut sent = 0;
+        for i in 0..3 {
+            sent += client.send(Packet::new(server_addr, vec![i; 10])).unwrap().unwrap();
+        }
+        for _ in 0..3 {
+            server.recv().unwrap().unwrap();
+        }
+        server.send(Packet::new(client_addr, vec![1])).unwrap().unwrap();
+        client.recv().unwrap().unwrap();
+
+        // Garbage from an unrelated socket
+        let garbage = net::UdpSocket::bind("127.0.0.1:12479").unwrap();
+        garbage.send_to(&[0xff; 3], server_addr).unwrap();
+        assert_eq!(server.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let client_stats = client.stats();
+        assert_eq!(client_stats.sent_packets, 3);
+        // The MTU probe counts as well
+        assert!(client_stats.bytes_sent > sent);
+        assert_eq!(client_stats.active_connections, 1);
+        assert_eq!(client_stats.rtt.count, 1);
+        assert_eq!(client_stats.lost_packets, 0);
+
+        let server_stats = server.stats();
+        assert_eq!(server_stats.bytes_received, client_stats.bytes_sent + 3);
+        assert_eq!(server_stats.malformed_packets, 1);
+        // Garbage does not get a connection
+        assert_eq!(server_stats.active_connections, 1);
+    }
+
+    #[test]
+    fn clock_sync_is_stable_under_jitter() {
+        let mut server = UdpSocket::bind("127.0.0.1:12497").unwrap();
+        let mut client = UdpSocket::bind("127.0.0.1:12498").unwrap().with_clock_sync();
+        let server_addr = server.local_addr().unwrap();
+        server.set_nonblocking(true).unwrap();
+        client.set_nonblocking(true).unwrap();
+        client
+            .set_link_conditioner(Some(
+                LinkConditioner::new(11)
+                    .with_latency(time::Duration::from_millis(20))
+                    .with_jitter(time::Duration::from_millis(10)),
+            )).unwrap();
+
+        // Once the filter is full, the estimate should barely move
+        let mut estimates = Vec::new();
+        let started = time::Instant::now();
+        while started.elapsed() < time::Duration::from_millis(3000) {
+            client.send(Packet::new(server_addr, vec![1])).unwrap().unwrap();
+            for _ in 0..10 {
+                while let Ok(Some(_)) = server.recv() {}
+                while let Ok(Some(_)) = client.recv() {}
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            if started.elapsed() > time::Duration::from_millis(1000) {
+                estimates.push(client.clock(server_addr).unwrap().offset().unwrap());
+            }
+        }
+        let spread = estimates.iter().max().unwrap() - estimates.iter().min().unwrap();
+        assert!(spread < 5_000, "the estimate moved by {}us", spread);
+
+        // Both clocks run in this process, so the real offset is known
+        let offset = server.clock_time() - client.clock_time();
+        let clock = client.clock(server_addr).unwrap();
+        let error = (clock.offset().unwrap() - offset).abs();
+        // The ways differ by at most what the round trip took over the shortest possible one of 20ms, plus polling
+        let rtt = clock.rtt().unwrap().as_micros() as i64;
+        assert!(rtt >= 20_000);
+        assert!(error <= (rtt - 20_000) / 2 + 2_000, "offset is off by {}us with a round trip of {}us", error, rtt);
+        let remote_time = client.remote_time(server_addr).unwrap();
+        assert!((remote_time - server.clock_time()).abs() < 10_000);
+        // The server only answered
+        assert!(server.clock(client.local_addr().unwrap()).unwrap().offset().is_none());
+    }
+
+    #[test]
+    fn sends_typed_messages_packed_into_packets() {
+        let mut client = UdpSocket::bind("127.0.0.1:12507").unwrap();
+        let mut server = UdpSocket::bind("127.0.0.1:12508").unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let messages: Vec<_> = (0..200).map(|id| (id as u8 % 4, StubData { id, b: 1 })).collect();
+        client.send_messages(server_addr, &messages).unwrap().unwrap();
+        client.send_message(server_addr, 9, &StubData { id: 1000, b: 2 }).unwrap().unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < 201 {
+            let message = server.recv_message::<StubData>().unwrap().unwrap();
+            assert_eq!(message.addr, client_addr);
+            received.push((message.channel, message.message));
+        }
+        assert_eq!(&received[..200], &messages[..]);
+        assert_eq!(received[200], (9, StubData { id: 1000, b: 2 }));
+        // Seven bytes a message, so the 200 of them fit in three packets before the path MTU is discovered
+        assert_eq!(server.stats().received_packets, 4);
+
+        // Packets sent without messages do not unpack
+        client.send(Packet::new(server_addr, vec![1, 2, 3])).unwrap().unwrap();
+        let error = server.recv_message::<StubData>().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // Neither do packets with just the tag
+        client.send(Packet::new(server_addr, vec![0xa7])).unwrap().unwrap();
+        let error = server.recv_message::<StubData>().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn compresses_messages_per_channel() {
+        let dictionary = serialize(&vec![7_u8; 100]).unwrap();
+        let mut client = UdpSocket::bind("127.0.0.1:12567")
+            .unwrap()
+            .with_channel_compression(1, Compression::new().with_dictionary(dictionary.clone(), 512));
+        let mut server = UdpSocket::bind("127.0.0.1:12568")
+            .unwrap()
+            .with_channel_compression(1, Compression::new().with_dictionary(dictionary, 512));
+        let server_addr = server.local_addr().unwrap();
+
+        let message = vec![7_u8; 100];
+        let raw = client.send_message(server_addr, 2, &message).unwrap().unwrap();
+        let compressed = client.send_message(server_addr, 1, &message).unwrap().unwrap();
+        assert!(compressed < raw - 50);
+
+        for &channel in &[2, 1] {
+            let received = server.recv_message::<Vec<u8>>().unwrap().unwrap();
+            assert_eq!((received.channel, received.message), (channel, message.clone()));
+        }
+    }
+
+    #[test]
+    fn aggregates_packets_until_flushed() {
+        let mut client = UdpSocket::bind("127.0.0.1:12517").unwrap().with_aggregation();
+        let mut server = UdpSocket::bind("127.0.0.1:12518").unwrap().with_aggregation();
+        let server_addr = server.local_addr().unwrap();
+
+        for i in 0..20_u8 {
+            assert_eq!(client.send(Packet::new(server_addr, vec![i; 10])).unwrap().unwrap(), 0);
+        }
+        assert_eq!(client.stats().sent_packets, 0);
+        client.flush().unwrap().unwrap();
+        assert_eq!(client.stats().sent_packets, 1);
+        // Nothing is left to send
+        assert_eq!(client.flush().unwrap().unwrap(), 0);
+
+        for i in 0..20_u8 {
+            let packet = server.recv().unwrap().unwrap();
+            assert_eq!(packet.payload(), &[i; 10][..]);
+        }
+        assert_eq!(server.stats().received_packets, 1);
+
+        // Messages are aggregated like any other packet
+        client.send_message(server_addr, 3, &StubData { id: 1, b: 2 }).unwrap().unwrap();
+        client.send(Packet::new(server_addr, vec![0; 1000])).unwrap().unwrap();
+        client.flush().unwrap().unwrap();
+        assert_eq!(client.stats().sent_packets, 3);
+        let message = server.recv_message::<StubData>().unwrap().unwrap();
+        assert_eq!((message.channel, message.message), (3, StubData { id: 1, b: 2 }));
+        assert_eq!(server.recv().unwrap().unwrap().payload().len(), 1000);
+    }
+
+    #[test]
+    fn keeps_the_packets_a_flush_could_not_send() {
+        let mut client = UdpSocket::bind("127.0.0.1:12577").unwrap().with_aggregation();
+        let mut server = UdpSocket::bind("127.0.0.1:12578").unwrap().with_aggregation();
+        let server_addr = server.local_addr().unwrap();
+        // An IPv4 socket can not send to an IPv6 address
+        let unreachable_addr = "[::1]:12579".parse().unwrap();
+
+        client.send(Packet::new(unreachable_addr, vec![1])).unwrap().unwrap();
+        client.send(Packet::new(server_addr, vec![2])).unwrap().unwrap();
+        assert!(client.flush().unwrap().is_err());
+
+        // The other address is still flushed
+        assert_eq!(server.recv().unwrap().unwrap().payload(), &[2]);
+        assert_eq!(client.queued_payloads.len(), 1);
+        assert_eq!(client.queued_payloads[&unreachable_addr][0].payload, vec![1]);
+
+        client.send(Packet::new(unreachable_addr, vec![3])).unwrap().unwrap();
+        assert!(client.flush().unwrap().is_err());
+        let queued: Vec<_> = client.queued_payloads[&unreachable_addr].iter().map(|queued| queued.payload.clone()).collect();
+        assert_eq!(queued, vec![vec![1], vec![3]]);
+    }
+
+    #[test]
+    fn sends_by_priority_within_the_bandwidth_budget() {
+        let mut client = UdpSocket::bind("127.0.0.1:12527").unwrap().with_bandwidth_budget(1000);
+        let mut server = UdpSocket::bind("127.0.0.1:12528").unwrap().with_aggregation();
+        let server_addr = server.local_addr().unwrap();
+
+        for i in 0..5 {
+            client.send_with_priority(Packet::new(server_addr, vec![i; 10]), Priority::Low).unwrap().unwrap();
+        }
+        for i in 0..9 {
+            client.send_with_priority(Packet::new(server_addr, vec![100 + i; 97]), Priority::High).unwrap().unwrap();
+        }
+        // Goes over the budget, so nothing fits for about a second after it
+        client.send_with_priority(Packet::new(server_addr, vec![200; 1000]), Priority::High).unwrap().unwrap();
+        client.flush().unwrap().unwrap();
+        assert_eq!(client.stats().deferred_packets, 5);
+
+        for i in 0..9 {
+            assert_eq!(server.recv().unwrap().unwrap().payload(), &[100 + i; 97][..]);
+        }
+        assert_eq!(server.recv().unwrap().unwrap().payload().len(), 1000);
+
+        client.protocol.state.connection(server_addr).unwrap().write().unwrap().quality = Quality::Bad;
+        client.send(Packet::new(server_addr, vec![50; 10])).unwrap().unwrap();
+        assert_eq!(client.flush().unwrap().unwrap(), 0);
+        let stats = client.stats();
+        assert_eq!(stats.dropped_low_priority_packets, 5);
+        assert_eq!(stats.deferred_packets, 6);
+
+        server.set_nonblocking(true).unwrap();
+        assert_eq!(server.recv().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn sends_snapshot_deltas_against_acked_baselines() {
+        let mut server = UdpSocket::bind("127.0.0.1:12537").unwrap();
+        let mut client = UdpSocket::bind("127.0.0.1:12538").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let mut snapshots = SnapshotBuffer::new();
+
+        let first: Vec<u8> = (0..100).collect();
+        let seq = server.next_seq(client_addr);
+        snapshots.insert(client_addr, seq, first.clone());
+        server.send(Packet::new(client_addr, first.clone())).unwrap().unwrap();
+        assert_eq!(client.recv().unwrap().unwrap().payload(), &first[..]);
+
+        // Anything the client sends back acks the snapshot
+        client.send(Packet::new(server_addr, vec![1])).unwrap().unwrap();
+        server.recv().unwrap().unwrap();
+        let acked = server.acked_packets(client_addr).unwrap();
+        assert_eq!(acked, vec![seq]);
+        assert!(server.acked_packets(client_addr).unwrap().is_empty());
+        for seq in acked {
+            snapshots.ack(client_addr, seq);
+        }
+
+        let mut second = first.clone();
+        second[42] = 0;
+        let (baseline, delta) = snapshots.delta(client_addr, &second).unwrap();
+        assert_eq!(baseline, seq);
+        server.send(Packet::new(client_addr, delta)).unwrap().unwrap();
+        let delta = client.recv().unwrap().unwrap().payload().to_vec();
+        assert!(delta.len() < 10);
+        assert_eq!(first.apply(&delta).unwrap(), second);
+    }
+
+    #[test]
+    fn tells_when_tracked_packets_are_acked_or_lost() {
+        let mut client = UdpSocket::bind("127.0.0.1:12547").unwrap();
+        let mut server = UdpSocket::bind("127.0.0.1:12548").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        client.set_link_conditioner(Some(LinkConditioner::new(1).with_packet_loss(1.0))).unwrap();
+        let lost = client.send_tracked(Packet::new(server_addr, vec![0])).unwrap().unwrap();
+        assert_eq!(lost, PacketHandle { addr: server_addr, id: 0 });
+        client.set_link_conditioner(None).unwrap();
+
+        for i in 1..34 {
+            client.send(Packet::new(server_addr, vec![i])).unwrap().unwrap();
+        }
+        let acked = client.send_tracked(Packet::new(server_addr, vec![34])).unwrap().unwrap();
+        for _ in 1..35 {
+            server.recv().unwrap().unwrap();
+        }
+        assert!(client.delivery_events().is_empty());
+
+        server.send(Packet::new(client_addr, vec![1])).unwrap().unwrap();
+        client.recv().unwrap().unwrap();
+        assert_eq!(client.delivery_events(), vec![DeliveryEvent::Lost(lost), DeliveryEvent::Acked(acked)]);
+        assert!(client.delivery_events().is_empty());
+    }
+
+    #[test]
+    fn tracks_aggregated_packets_with_their_datagram() {
+        let mut client = UdpSocket::bind("127.0.0.1:12587").unwrap().with_aggregation();
+        let mut server = UdpSocket::bind("127.0.0.1:12588").unwrap().with_aggregation();
+        let server_addr = server.local_addr().unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        let first = client.send_tracked(Packet::new(server_addr, vec![1])).unwrap().unwrap();
+        client.send(Packet::new(server_addr, vec![2])).unwrap().unwrap();
+        let second = client.send_tracked(Packet::new(server_addr, vec![3])).unwrap().unwrap();
+        assert_ne!(first, second);
+        client.flush().unwrap().unwrap();
+        for i in 1..4 {
+            assert_eq!(server.recv().unwrap().unwrap().payload(), &[i]);
+        }
+
+        server.send(Packet::new(client_addr, vec![1])).unwrap().unwrap();
+        server.flush().unwrap().unwrap();
+        client.recv().unwrap().unwrap();
+        assert_eq!(client.delivery_events(), vec![DeliveryEvent::Acked(first), DeliveryEvent::Acked(second)]);
+    }
+
+    #[test]
+    fn compressed_sockets_exchange_packets() {
+        let mut send_socket = UdpSocket::bind("127.0.0.1:12417")
+            .unwrap()
+            .with_compression(Compression::new())
+            .with_encryption();
+        let mut recv_socket = UdpSocket::bind("127.0.0.1:12418")
+            .unwrap()
+            .with_compression(Compression::new())
+            .with_encryption();
+        let recv_addr = recv_socket.local_addr().unwrap();
+        send_socket.set_nonblocking(true).unwrap();
+        recv_socket.set_nonblocking(true).unwrap();
+
+        let payloads = vec![vec![1, 2, 3], vec![7; 600]];
+        for payload in &payloads {
+            send_socket.send(Packet::new(recv_addr, payload.clone())).unwrap().unwrap();
+        }
+
+        let mut received = Vec::new();
+        for _ in 0..20 {
+            while let Ok(Some(packet)) = recv_socket.recv() {
+                received.push(packet.payload().to_vec());
+            }
+            while let Ok(Some(_)) = send_socket.recv() {}
+            thread::sleep(time::Duration::from_millis(5));
+        }
+
+        assert_eq!(received, payloads);
+    }
+
+    #[test]
+    fn discovers_the_path_mtu() {
+        let mut client = UdpSocket::bind("127.0.0.1:12427").unwrap().with_encryption();
+        let mut server = UdpSocket::bind("127.0.0.1:12428").unwrap().with_encryption();
+        let client_addr = client.local_addr().unwrap();
+        let server_addr = server.local_addr().unwrap();
+        client.set_nonblocking(true).unwrap();
+        server.set_nonblocking(true).unwrap();
+
+        let before = client.max_payload_size(server_addr);
+        client.send(Packet::new(server_addr, vec![1])).unwrap().unwrap();
+
+        // The server only answers the probe, it never sends anything itself
+        let mut received = Vec::new();
+        for _ in 0..20 {
+            while let Ok(Some(packet)) = server.recv() {
+                received.push(packet.payload().to_vec());
+            }
+            while let Ok(Some(_)) = client.recv() {}
+            thread::sleep(time::Duration::from_millis(5));
+        }
+
+        // Loopback carries the biggest size we probe for
+        assert_eq!(received, vec![vec![1]]);
+        assert!(client.max_payload_size(server_addr) > before);
+        assert_eq!(client.max_payload_size(server_addr), MAX_DATAGRAM_SIZE - 25 - RAW_PACKET_HEADER_SIZE);
+        assert_eq!(server.max_payload_size(client_addr), before);
+
+        let payload = vec![7; client.max_payload_size(server_addr)];
+        client.send(Packet::new(server_addr, payload.clone())).unwrap().unwrap();
+        thread::sleep(time::Duration::from_millis(20));
+        assert_eq!(server.recv().unwrap().unwrap().payload(), &payload[..]);
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
     struct StubData {
         pub id: u16,
         pub b: u16,
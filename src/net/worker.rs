@@ -0,0 +1,236 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam_channel::{unbounded, Receiver, Select, Sender};
+
+use super::{Packet, PacketHandle, UdpSocket};
+use events::SocketEvent;
+
+// How long the worker waits for packets to send before it reads from the socket again
+const POLL_INTERVAL_MS: u64 = 1;
+
+/// A `UdpSocket` running on a thread of its own, see `UdpSocket::start_worker`.
+///
+/// Packets sent on `sender` go out right away, received packets and connection events arrive on `receiver` within a millisecond.
+/// Dropping the worker stops the thread and closes the socket.
+pub struct Worker {
+    packets: Sender<Packet>,
+    tracked_packets: Sender<(Packet, PacketHandle)>,
+    handle_ids: Arc<AtomicU64>,
+    events: Receiver<SocketEvent>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    // The socket state has to send its connection events to `events` already
+    pub(super) fn start(mut socket: UdpSocket, events: (Sender<SocketEvent>, Receiver<SocketEvent>)) -> io::Result<Worker> {
+        socket.set_nonblocking(true)?;
+
+        let (packet_tx, packet_rx) = unbounded();
+        let (tracked_tx, tracked_rx) = unbounded();
+        let handle_ids = socket.handle_ids();
+        let (event_tx, event_rx) = events;
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+
+        let thread = thread::Builder::new()
+            .name("udp_worker".into())
+            .spawn(move || run(socket, packet_rx, tracked_rx, event_tx, thread_running))?;
+
+        Ok(Worker {
+            packets: packet_tx,
+            tracked_packets: tracked_tx,
+            handle_ids,
+            events: event_rx,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns a sender for packets to send, it can be cloned and moved to other threads.
+    pub fn sender(&self) -> Sender<Packet> {
+        self.packets.clone()
+    }
+
+    /// Sends a packet like `UdpSocket::send_tracked` and returns its handle, the `DeliveryEvent`s about it arrive on `receiver`.
+    ///
+    /// The packet goes out with the next packets the worker sends, but not necessarily in order with the ones on `sender`.
+    pub fn send_tracked(&self, packet: Packet) -> PacketHandle {
+        let handle = PacketHandle {
+            addr: packet.addr(),
+            id: self.handle_ids.fetch_add(1, Ordering::Relaxed),
+        };
+        // The thread only stops when the worker is dropped
+        let _ = self.tracked_packets.send((packet, handle));
+        handle
+    }
+
+    /// Returns the receiver for received packets and connection events.
+    pub fn receiver(&self) -> Receiver<SocketEvent> {
+        self.events.clone()
+    }
+
+    /// Stops the worker thread and waits for it to finish.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Reads everything the socket has, then sends the queued packets or waits a moment for some
+fn run(
+    mut socket: UdpSocket,
+    packets: Receiver<Packet>,
+    tracked_packets: Receiver<(Packet, PacketHandle)>,
+    events: Sender<SocketEvent>,
+    running: Arc<AtomicBool>,
+) {
+    while running.load(Ordering::Relaxed) {
+        let mut received = false;
+        loop {
+            match socket.recv() {
+                Ok(Some(packet)) => {
+                    received = true;
+                    let _ = events.send(SocketEvent::Packet(packet));
+                }
+                Ok(None) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("Error receiving on the worker: {}", e);
+                    break;
+                }
+            }
+        }
+
+        // Only wait when there was nothing to read, so a busy socket is drained first
+        if !received {
+            let mut select = Select::new();
+            select.recv(&packets);
+            select.recv(&tracked_packets);
+            // The packets are taken below, whichever channel woke us up
+            let _ = select.ready_timeout(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+        for packet in packets.try_iter() {
+            send(&mut socket, packet, None);
+        }
+        for (packet, handle) in tracked_packets.try_iter() {
+            send(&mut socket, packet, Some(handle));
+        }
+        // Sends what aggregation queued, every pass is a tick
+        match socket.flush() {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!("Error flushing: {}", e),
+            Err(e) => error!("Error flushing: {}", e),
+        }
+    }
+}
+
+fn send(socket: &mut UdpSocket, packet: Packet, handle: Option<PacketHandle>) {
+    let addr = packet.addr();
+    let result = match handle {
+        Some(handle) => socket.send_with_handle(packet, handle),
+        None => socket.send(packet),
+    };
+    match result {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => error!("Error sending to {}: {}", addr, e),
+        Err(e) => error!("Error sending to {}: {}", addr, e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use events::{ConnectionEvent, DeliveryEvent, SocketEvent};
+    use net::UdpSocket;
+    use packet::Packet;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn sends_and_receives_from_other_threads() {
+        let server_addr: SocketAddr = "127.0.0.1:12467".parse().unwrap();
+        let client_addr: SocketAddr = "127.0.0.1:12468".parse().unwrap();
+        let server = UdpSocket::bind(server_addr).unwrap().start_worker().unwrap();
+        let client = UdpSocket::bind(client_addr).unwrap().start_worker().unwrap();
+
+        // Gameplay systems send from wherever they run
+        let senders: Vec<_> = (0..4_u8)
+            .map(|i| {
+                let sender = client.sender();
+                thread::spawn(move || sender.send(Packet::new(server_addr, vec![i])).unwrap())
+            })
+            .collect();
+        for sender in senders {
+            sender.join().unwrap();
+        }
+
+        let events = server.receiver();
+        let mut payloads = Vec::new();
+        let mut connected = None;
+        while payloads.len() < 4 {
+            match events.recv_timeout(Duration::from_secs(5)).expect("no event from the worker") {
+                SocketEvent::Packet(packet) => {
+                    assert_eq!(packet.addr(), client_addr);
+                    payloads.push(packet.payload()[0]);
+                }
+                SocketEvent::Connection(ConnectionEvent::Connected { conn }) => connected = Some(conn.read().unwrap().remote_address),
+                e => panic!("unexpected event: {:?}", e),
+            }
+        }
+        payloads.sort();
+        assert_eq!(payloads, vec![0, 1, 2, 3]);
+        assert_eq!(connected, Some(client_addr));
+
+        // The client sees the connection once the server answers its path MTU probe
+        match client.receiver().recv_timeout(Duration::from_secs(5)) {
+            Ok(SocketEvent::Connection(ConnectionEvent::Connected { conn })) => assert_eq!(conn.read().unwrap().remote_address, server_addr),
+            e => panic!("unexpected event: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn reports_tracked_packets_on_the_receiver() {
+        let server_addr: SocketAddr = "127.0.0.1:12597".parse().unwrap();
+        let client_addr: SocketAddr = "127.0.0.1:12598".parse().unwrap();
+        let server = UdpSocket::bind(server_addr).unwrap().with_aggregation().start_worker().unwrap();
+        let client = UdpSocket::bind(client_addr).unwrap().with_aggregation().start_worker().unwrap();
+
+        let handle = client.send_tracked(Packet::new(server_addr, vec![1]));
+        assert_eq!(handle.addr, server_addr);
+
+        // Anything the server sends back acks the packet
+        loop {
+            match server.receiver().recv_timeout(Duration::from_secs(5)).expect("no event from the worker") {
+                SocketEvent::Packet(packet) => {
+                    assert_eq!(packet.payload(), &[1]);
+                    break;
+                }
+                SocketEvent::Connection(_) => {}
+                e => panic!("unexpected event: {:?}", e),
+            }
+        }
+        server.sender().send(Packet::new(client_addr, vec![2])).unwrap();
+        loop {
+            match client.receiver().recv_timeout(Duration::from_secs(5)).expect("no event from the worker") {
+                SocketEvent::Delivery(event) => {
+                    assert_eq!(event, DeliveryEvent::Acked(handle));
+                    break;
+                }
+                SocketEvent::Packet(_) | SocketEvent::Connection(_) => {}
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::net::SocketAddr;
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -17,7 +18,7 @@ impl Packet {
     }
 
     pub fn payload(&self) -> &[u8] {
-        return &self.payload;
+        &self.payload
     }
 
     pub fn addr(&self) -> SocketAddr {
@@ -25,11 +26,68 @@ impl Packet {
     }
 }
 
+/// Identifies a packet sent with `UdpSocket::send_tracked` in the `DeliveryEvent`s about it.
+///
+/// Handles are numbered per socket in the order they were handed out, the sequence number of a packet is not known yet when aggregation queues it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct PacketHandle {
+    pub addr: SocketAddr,
+    pub id: u64,
+}
+
+/// What a `RawPacket` carries. Only `Data` packets are handed to the application.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum PacketType {
+    /// A packet sent by the application.
+    Data,
+    /// A padded packet that tests if the path carries datagrams of its size.
+    MtuProbe,
+    /// The answer to an `MtuProbe`, so its ack makes it back even if the application sends nothing.
+    MtuProbeAck,
+    /// Carries the time it was sent at, for clock sync.
+    TimePing,
+    /// The answer to a `TimePing`, with the time of the ping and the time it was answered at.
+    TimePong,
+}
+
+// Sent as a single byte instead of the four bytes bincode uses for enums
+impl From<PacketType> for u8 {
+    fn from(packet_type: PacketType) -> u8 {
+        match packet_type {
+            PacketType::Data => 0,
+            PacketType::MtuProbe => 1,
+            PacketType::MtuProbeAck => 2,
+            PacketType::TimePing => 3,
+            PacketType::TimePong => 4,
+        }
+    }
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<PacketType, String> {
+        match value {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::MtuProbe),
+            2 => Ok(PacketType::MtuProbeAck),
+            3 => Ok(PacketType::TimePing),
+            4 => Ok(PacketType::TimePong),
+            _ => Err(format!("invalid packet type {}", value)),
+        }
+    }
+}
+
+/// Size of a serialized `RawPacket` without its payload: seq, ack seq, ack field, packet type and the payload length.
+pub const RAW_PACKET_HEADER_SIZE: usize = 2 + 2 + 4 + 1 + 8;
+
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 /// packet that will be send over the network witch contains:
 /// 1. the sequence number
 /// 2. the last acknowledged sequence number
 /// 3. last 32 acknowledged packages.
+/// 4. what kind of packet it is.
 pub struct RawPacket {
     // this is the sequence number so that we can know where in the sequence of packages this packet belongs.
     pub seq: u16,
@@ -37,6 +95,8 @@ pub struct RawPacket {
     pub ack_seq: u16,
     // this is an bitfield of all last 32 acknowledged packages
     pub ack_field: u32,
+    // this tells if the packet is application data or used by the protocol itself.
+    pub packet_type: PacketType,
     // this is the payload in witch the packet data is stored.
     pub payload: Box<[u8]>,
 }
@@ -47,7 +107,29 @@ impl RawPacket {
             seq: seq_num,
             ack_seq: last_seq,
             ack_field: field,
+            packet_type: PacketType::Data,
             payload: p.payload.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Packet, PacketType, RawPacket, RAW_PACKET_HEADER_SIZE};
+    use bincode::{deserialize, serialize};
+
+    #[test]
+    fn raw_packet_header_size_matches_serialization() {
+        let packet = Packet::new("127.0.0.1:12345".parse().unwrap(), vec![1, 2, 3]);
+        let mut raw_packet = RawPacket::new(1, &packet, 2, 3);
+        raw_packet.packet_type = PacketType::MtuProbeAck;
+
+        let bytes = serialize(&raw_packet).unwrap();
+        assert_eq!(bytes.len(), RAW_PACKET_HEADER_SIZE + 3);
+        assert_eq!(deserialize::<RawPacket>(&bytes).unwrap(), raw_packet);
+
+        let mut invalid = bytes.clone();
+        invalid[8] = 9;
+        assert!(deserialize::<RawPacket>(&invalid).is_err());
+    }
+}